@@ -1,11 +1,19 @@
 use std::collections::HashMap; use std::collections::HashSet;
 use std::collections::hash_map::Keys;
 use std::iter::FromIterator;
+use std::time::{Duration, Instant};
 use std::vec::Vec;
 use copernica_common::{LinkId, BFI};
+use borsh::{BorshDeserialize, BorshSerialize};
+
+// Once the weight of a new observation has grown this large every count is
+// rescaled, lest old and new counts drift too far apart for an f64.
+const RESCALE_AT: f64 = 1e12;
+// Frequencies that decayed below this are forgotten when rescaling.
+const FORGET_BELOW: f64 = 1e-6;
 
 struct BFIs {
-    bfis: HashMap<BFI, HashMap<LinkId, i64>>,
+    bfis: HashMap<BFI, HashMap<LinkId, f64>>,
 }
 
 impl BFIs {
@@ -15,37 +23,54 @@ impl BFIs {
         }
     }
 
-    fn train(&mut self, bfi: &BFI, link: &LinkId) {
+    fn train(&mut self, bfi: &BFI, link: &LinkId, weight: f64) {
         let linkids = self.bfis
             .entry(*bfi)
-            .or_insert(HashMap::new());
-        let value = linkids.entry(link.clone()).or_insert(0);
-        *value += 1;
+            .or_default();
+        let value = linkids.entry(link.clone()).or_insert(0.0);
+        *value += weight;
     }
 
 
-    fn super_train(&mut self, bfi: &BFI, link: &LinkId) {
+    fn super_train(&mut self, bfi: &BFI, link: &LinkId, weight: f64) {
         let linkids = self.bfis
             .entry(*bfi)
-            .or_insert(HashMap::new());
-        let value = linkids.entry(link.clone()).or_insert(0);
-        *value += 4;
+            .or_default();
+        let value = linkids.entry(link.clone()).or_insert(0.0);
+        *value += 4.0 * weight;
     }
 
-    fn get_frequency(&mut self, bfi: &BFI, linkid: &LinkId) -> (Option<&i64>, bool) {
+    fn get_frequency(&mut self, bfi: &BFI, linkid: &LinkId) -> (Option<&f64>, bool) {
         match self.bfis.get(bfi) {
             Some(linkids) => match linkids.get(linkid) {
-                Some(value) => return (Some(value), true),
-                None => return (None, true),
+                Some(value) => (Some(value), true),
+                None => (None, true),
             },
-            None => return (None, false),
+            None => (None, false),
+        }
+    }
+
+    fn remove_link(&mut self, link: &LinkId) {
+        for linkids in self.bfis.values_mut() {
+            linkids.remove(link);
+        }
+        self.bfis.retain(|_, linkids| !linkids.is_empty());
+    }
+
+    fn rescale(&mut self, factor: f64) {
+        for linkids in self.bfis.values_mut() {
+            for value in linkids.values_mut() {
+                *value *= factor;
+            }
+            linkids.retain(|_, value| *value >= FORGET_BELOW);
         }
+        self.bfis.retain(|_, linkids| !linkids.is_empty());
     }
 }
 
 
 struct Links {
-    count: HashMap<LinkId, i64>,
+    count: HashMap<LinkId, f64>,
 }
 
 impl Links {
@@ -55,33 +80,68 @@ impl Links {
         }
     }
 
-    fn train(&mut self, link: &LinkId) {
-        let value = self.count.entry(link.clone()).or_insert(0);
-        *value += 1;
+    fn train(&mut self, link: &LinkId, weight: f64) {
+        let value = self.count.entry(link.clone()).or_insert(0.0);
+        *value += weight;
+    }
+
+    fn super_train(&mut self, link: &LinkId, weight: f64) {
+        let value = self.count.entry(link.clone()).or_insert(0.0);
+        *value += 4.0 * weight;
     }
 
-    fn super_train(&mut self, link: &LinkId) {
-        let value = self.count.entry(link.clone()).or_insert(0);
-        *value += 4;
+    fn get_count(&mut self, link: &LinkId) -> Option<&f64> {
+        self.count.get(link)
     }
 
-    fn get_count(&mut self, link: &LinkId) -> Option<&i64> {
-        return self.count.get(link);
+    fn get_linkids(&mut self) -> Keys<'_, LinkId, f64> {
+        self.count.keys()
     }
 
-    fn get_linkids(&mut self) -> Keys<LinkId, i64> {
-        return self.count.keys();
+    fn get_total(&mut self) -> f64 {
+        self.count.values().fold(0.0, |acc, x| acc + x)
     }
 
-    fn get_total(&mut self) -> i64 {
-        return self.count.values().fold(0, |acc, x| acc + x);
+    fn remove_link(&mut self, link: &LinkId) {
+        self.count.remove(link);
     }
+
+    // Links are peered, so they stay however small their count gets.
+    fn rescale(&mut self, factor: f64) {
+        for value in self.count.values_mut() {
+            *value *= factor;
+        }
+    }
+}
+
+
+// Rather than decaying every count as time passes, each new observation
+// weighs 2^(t / half_life) more than one made at `epoch`. The ratios the
+// classifier works from are the same either way.
+struct Decay {
+    half_life: Option<Duration>,
+    epoch: Instant,
 }
 
+impl Decay {
+    fn new() -> Decay {
+        Decay { half_life: None, epoch: Instant::now() }
+    }
+
+    fn weight(&self) -> f64 {
+        match self.half_life {
+            Some(half_life) if half_life.as_secs_f64() > 0.0 => {
+                (self.epoch.elapsed().as_secs_f64() / half_life.as_secs_f64()).exp2()
+            },
+            _ => 1.0,
+        }
+    }
+}
 
 struct Model {
     links: Links,
     bfis: BFIs,
+    decay: Decay,
 }
 
 impl Model {
@@ -89,26 +149,58 @@ impl Model {
         Model {
             links: Links::new(),
             bfis: BFIs::new(),
+            decay: Decay::new(),
         }
     }
+    fn weight(&mut self) -> f64 {
+        let weight = self.decay.weight();
+        if weight < RESCALE_AT {
+            return weight;
+        }
+        self.links.rescale(1.0 / weight);
+        self.bfis.rescale(1.0 / weight);
+        self.decay.epoch = Instant::now();
+        1.0
+    }
     fn add_link(&mut self, linkid: &LinkId) {
-        self.links.train(linkid);
+        let weight = self.weight();
+        self.links.train(linkid, weight);
+    }
+    fn remove_link(&mut self, linkid: &LinkId) {
+        self.links.remove_link(linkid);
+        self.bfis.remove_link(linkid);
     }
     fn train(&mut self, data: &Vec<BFI>, linkid: &LinkId) {
-        self.links.train(linkid);
+        let weight = self.weight();
+        self.links.train(linkid, weight);
         for bfi in data {
-            self.bfis.train(bfi, linkid);
+            self.bfis.train(bfi, linkid, weight);
         }
     }
 
     fn super_train(&mut self, data: &Vec<BFI>, linkid: &LinkId) {
-        self.links.super_train(linkid);
+        let weight = self.weight();
+        self.links.super_train(linkid, weight);
         for bfi in data {
-            self.bfis.super_train(bfi, linkid);
+            self.bfis.super_train(bfi, linkid, weight);
         }
     }
 }
 
+/// What the model learnt about one link, under a name that survives
+/// restarts, unlike the link's `Nonce`.
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, PartialEq)]
+pub struct LinkSnapshot {
+    pub name: String,
+    pub count: f64,
+    pub bfis: Vec<(BFI, f64)>,
+}
+
+#[derive(BorshSerialize, BorshDeserialize, Clone, Debug, Default, PartialEq)]
+pub struct BayesSnapshot {
+    pub links: Vec<LinkSnapshot>,
+}
+
 #[derive(Debug)]
 pub struct LinkWeight{
     pub linkid: LinkId,
@@ -118,7 +210,9 @@ pub struct LinkWeight{
 pub struct Bayes {
     model: Model,
     min_prob: f64,
-    min_log_prob: f64
+    min_log_prob: f64,
+    // restored links that aren't peered, kept so the next snapshot has them
+    dormant: Vec<LinkSnapshot>,
 }
 
 impl Bayes {
@@ -127,53 +221,104 @@ impl Bayes {
             model: Model::new(),
             min_prob: 1e-9,
             min_log_prob: -100.0,
+            dormant: vec![],
         }
     }
 
+    /// The counts of every link with a name in `names`. Links without one
+    /// can't be recognised after a restart and are left out.
+    pub fn snapshot(&self, names: &HashMap<LinkId, String>) -> BayesSnapshot {
+        let weight = self.model.decay.weight();
+        let mut links: Vec<LinkSnapshot> = vec![];
+        for (linkid, count) in &self.model.links.count {
+            if let Some(name) = names.get(linkid) {
+                let bfis = self.model.bfis.bfis.iter()
+                    .filter_map(|(bfi, linkids)| linkids.get(linkid).map(|frequency| (*bfi, *frequency / weight)))
+                    .collect();
+                links.push(LinkSnapshot { name: name.clone(), count: *count / weight, bfis });
+            }
+        }
+        for dormant in &self.dormant {
+            if !links.iter().any(|link| link.name == dormant.name) {
+                links.push(dormant.clone());
+            }
+        }
+        BayesSnapshot { links }
+    }
+
+    /// Replaces what is known about each link in `names` with its counts in
+    /// `snapshot`. Counts for names not peered are held until the next
+    /// snapshot.
+    pub fn restore(&mut self, snapshot: BayesSnapshot, names: &HashMap<LinkId, String>) {
+        let linkids: HashMap<&String, &LinkId> = names.iter().map(|(linkid, name)| (name, linkid)).collect();
+        let weight = self.model.weight();
+        self.dormant.clear();
+        for link in snapshot.links {
+            match linkids.get(&link.name) {
+                Some(linkid) => {
+                    self.model.links.count.insert((*linkid).clone(), link.count * weight);
+                    for (bfi, frequency) in link.bfis {
+                        self.model.bfis.bfis
+                            .entry(bfi)
+                            .or_default()
+                            .insert((*linkid).clone(), frequency * weight);
+                    }
+                },
+                None => self.dormant.push(link),
+            }
+        }
+    }
+
+    /// Halves the weight of what was learnt every `half_life`, so that
+    /// routes follow a changing topology. Nothing decays by default.
+    pub fn half_life(mut self, half_life: Duration) -> Bayes {
+        self.model.decay.half_life = Some(half_life);
+        self
+    }
+
     pub fn add_link(&mut self, linkid: &LinkId) {
         self.model.add_link(&linkid);
     }
 
+    /// Forgets a link that went away, along with everything learnt about it.
+    pub fn remove_link(&mut self, linkid: &LinkId) {
+        self.model.remove_link(linkid);
+    }
+
     fn prior(&mut self, linkid: &LinkId) -> Option<f64> {
-        let total = *(&self.model.links.get_total()) as f64;
+        let total = self.model.links.get_total();
         let linkid = &self.model.links.get_count(linkid);
         if linkid.is_some() && total > 0.0 {
-            return Some(*linkid.unwrap() as f64 / total);
+            Some(*linkid.unwrap() / total)
         } else {
-            return None;
+            None
         }
     }
 
     fn log_prior(&mut self, linkid: &LinkId) -> Option<f64> {
-        let total = *(&self.model.links.get_total()) as f64;
+        let total = self.model.links.get_total();
         let linkid = &self.model.links.get_count(linkid);
         if linkid.is_some() && total > 0.0 {
-            return Some((*linkid.unwrap() as f64).ln() - total.ln());
+            Some(linkid.unwrap().ln() - total.ln())
         } else {
-            return None;
+            None
         }
     }
 
     fn calculate_attr_prob(&mut self, bfi: &BFI, linkid: &LinkId) -> Option<f64> {
         match self.model.bfis.get_frequency(bfi, linkid) {
-            (Some(frequency), true) => match self.model.links.get_count(linkid) {
-                Some(count) => return Some((*frequency as f64) / (*count as f64)),
-                None => return None,
-            },
-            (None, true) => return Some(self.min_prob),
-            (None, false) => return None,
+            (Some(frequency), true) => self.model.links.get_count(linkid).map(|count| *frequency / *count),
+            (None, true) => Some(self.min_prob),
+            (None, false) => None,
             (Some(_), false) => None,
         }
     }
 
     fn calculate_attr_log_prob(&mut self, bfi: &BFI, linkid: &LinkId) -> Option<f64> {
         match self.model.bfis.get_frequency(bfi, linkid) {
-            (Some(frequency), true) => match self.model.links.get_count(linkid) {
-                Some(count) => return Some((*frequency as f64).ln() - (*count as f64).ln()),
-                None => return None,
-            },
-            (None, true) => return Some(self.min_log_prob),
-            (None, false) => return None,
+            (Some(frequency), true) => self.model.links.get_count(linkid).map(|count| frequency.ln() - count.ln()),
+            (None, true) => Some(self.min_log_prob),
+            (None, false) => None,
             (Some(_), false) => None,
         }
     }
@@ -188,7 +333,7 @@ impl Bayes {
                 None => {}
             }
         }
-        return probs;
+        probs
     }
 
     fn link_log_prob(&mut self, linkid: &LinkId, bfis: &HashSet<BFI>) -> Vec<f64> {
@@ -201,7 +346,7 @@ impl Bayes {
                 None => {}
             }
         }
-        return probs;
+        probs
     }
 
     /// trains the model with a `Vec<BFI>`, associating it with a `LinkId` link.
@@ -256,19 +401,19 @@ impl Bayes {
 #[cfg(test)]
 mod test_bfis {
     use super::*;
-    use crate::{BFI, LinkId, ReplyTo, copernica_constants};
+    use copernica_common::{BFI, LinkId, ReplyTo, constants as copernica_constants};
     #[test]
     fn bfi_add() {
         let mut model = BFIs::new();
         let h1: BFI = [u16::MAX; copernica_constants::BLOOM_FILTER_INDEX_ELEMENT_LENGTH as usize];
         let li = LinkId::listen(ReplyTo::Rf(0));
-        model.add(&h1, &li);
+        model.train(&h1, &li, 1.0);
         assert_eq!(
             *model
                 .get_frequency(&h1, &li)
                 .0
                 .unwrap(),
-            1
+            1.0
         );
     }
 
@@ -290,14 +435,14 @@ mod test_bfis {
 #[cfg(test)]
 mod test_linkids {
     use super::*;
-    use crate::{LinkId, ReplyTo};
+    use copernica_common::{LinkId, ReplyTo};
 
     #[test]
     fn linkid_add() {
         let mut linkids = Links::new();
         let h1 = LinkId::listen(ReplyTo::Rf(0));
-        linkids.add(&h1);
-        assert_eq!(*linkids.get_count(&h1).unwrap(), 1);
+        linkids.train(&h1, 1.0);
+        assert_eq!(*linkids.get_count(&h1).unwrap(), 1.0);
     }
 
     #[test]
@@ -311,7 +456,7 @@ mod test_linkids {
     fn get_linkids() {
         let mut linkids = Links::new();
         let h1 = LinkId::listen(ReplyTo::Rf(0));
-        linkids.add(&h1);
+        linkids.train(&h1, 1.0);
         assert_eq!(linkids.get_linkids().len(), 1);
         assert_eq!(linkids.get_linkids().last().unwrap(), &h1);
     }
@@ -320,10 +465,10 @@ mod test_linkids {
     fn get_counts() {
         let mut linkids = Links::new();
         let h1 = LinkId::listen(ReplyTo::Rf(0));
-        linkids.add(&h1);
-        linkids.add(&h1);
+        linkids.train(&h1, 1.0);
+        linkids.train(&h1, 1.0);
         assert_eq!(linkids.get_linkids().len(), 1);
-        assert_eq!(*linkids.get_count(&h1).unwrap(), 2);
+        assert_eq!(*linkids.get_count(&h1).unwrap(), 2.0);
     }
 
     #[test]
@@ -337,7 +482,7 @@ mod test_linkids {
     #[test]
     fn get_nonexistent_total() {
         let mut linkids = Links::new();
-        assert_eq!(linkids.get_total(), 0);
+        assert_eq!(linkids.get_total(), 0.0);
     }
 
     #[test]
@@ -346,11 +491,11 @@ mod test_linkids {
         let h1 = LinkId::listen(ReplyTo::Rf(0));
         let h2 = LinkId::listen(ReplyTo::Rf(1));
         let h3 = LinkId::listen(ReplyTo::Rf(2));
-        linkids.add(&h1);
-        linkids.add(&h1);
-        linkids.add(&h2);
-        linkids.add(&h3);
-        assert_eq!(linkids.get_total(), 4);
+        linkids.train(&h1, 1.0);
+        linkids.train(&h1, 1.0);
+        linkids.train(&h2, 1.0);
+        linkids.train(&h3, 1.0);
+        assert_eq!(linkids.get_total(), 4.0);
     }
 
 }
@@ -359,7 +504,7 @@ mod test_linkids {
 mod test_bayes {
     use super::*;
     use std::f64::consts::LN_2;
-    use crate::{BFI, LinkId, ReplyTo, copernica_constants};
+    use copernica_common::{BFI, LinkId, ReplyTo, constants as copernica_constants};
 
     #[test]
     fn test_prior() {
@@ -485,3 +630,97 @@ mod test_bayes {
     }
 
 }
+
+#[cfg(test)]
+mod test_snapshot {
+    use super::*;
+    use copernica_common::{BFI, LinkId, ReplyTo, constants as copernica_constants};
+
+    #[test]
+    fn restores_by_name() {
+        let h1: BFI = [7; copernica_constants::BLOOM_FILTER_INDEX_ELEMENT_LENGTH as usize];
+        let l1 = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:8090".parse().unwrap()));
+        let l2 = LinkId::listen(ReplyTo::Rf(0));
+        let mut names = HashMap::new();
+        names.insert(l1.clone(), "udp_ip:127.0.0.1:8090".to_string());
+        names.insert(l2.clone(), "rf:0".to_string());
+        let mut nb = Bayes::new();
+        nb.add_link(&l1);
+        nb.add_link(&l2);
+        nb.super_train(&vec![h1], &l1);
+        let snapshot = BayesSnapshot::try_from_slice(&nb.snapshot(&names).try_to_vec().unwrap()).unwrap();
+
+        // after a restart the links come back with new nonces
+        let r1 = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:8090".parse().unwrap()));
+        let mut renamed = HashMap::new();
+        renamed.insert(r1.clone(), "udp_ip:127.0.0.1:8090".to_string());
+        let mut restored = Bayes::new();
+        restored.add_link(&r1);
+        restored.restore(snapshot, &renamed);
+        assert_eq!(restored.model.links.count.get(&r1), Some(&5.0));
+        assert_eq!(restored.model.bfis.get_frequency(&h1, &r1).0, Some(&4.0));
+
+        // rf:0 isn't peered yet, but isn't forgotten either
+        let again = restored.snapshot(&renamed);
+        assert_eq!(again.links.len(), 2);
+        assert!(again.links.iter().any(|link| link.name == "rf:0" && link.count == 1.0));
+    }
+}
+
+#[cfg(test)]
+mod test_decay {
+    use super::*;
+    use copernica_common::{BFI, LinkId, ReplyTo, constants as copernica_constants};
+
+    fn bfi(n: u16) -> BFI {
+        [n; copernica_constants::BLOOM_FILTER_INDEX_ELEMENT_LENGTH as usize]
+    }
+
+    // as if `by` had passed since the model started
+    fn age(nb: &mut Bayes, by: Duration) {
+        nb.model.decay.epoch = nb.model.decay.epoch.checked_sub(by).unwrap();
+    }
+
+    #[test]
+    fn halves_after_a_half_life() {
+        let mut nb = Bayes::new().half_life(Duration::from_secs(1));
+        let l1 = LinkId::listen(ReplyTo::Rf(0));
+        let l2 = LinkId::listen(ReplyTo::Rf(1));
+        nb.model.train(&vec![bfi(1)], &l1);
+        age(&mut nb, Duration::from_secs(1));
+        nb.model.train(&vec![bfi(1)], &l2);
+        assert!((nb.prior(&l2).unwrap() - 2.0 / 3.0).abs() < 1e-3);
+        let classes = nb.classify(&vec![bfi(1)]);
+        assert_eq!(classes[0].linkid, l2);
+        let snapshot = nb.snapshot(&vec![(l1.clone(), "rf:0".to_string())].into_iter().collect());
+        assert!((snapshot.links[0].count - 0.5).abs() < 1e-3);
+    }
+
+    #[test]
+    fn rescales_before_counts_overflow() {
+        let mut nb = Bayes::new().half_life(Duration::from_millis(10));
+        let l1 = LinkId::listen(ReplyTo::Rf(0));
+        let l2 = LinkId::listen(ReplyTo::Rf(1));
+        nb.model.train(&vec![bfi(1)], &l1);
+        age(&mut nb, Duration::from_millis(10 * 41));
+        nb.model.train(&vec![bfi(2)], &l2);
+        assert!(nb.model.decay.weight() < 2.0);
+        assert!(*nb.model.links.get_count(&l2).unwrap() <= 2.0);
+        assert!(nb.model.links.get_count(&l1).is_some());
+        assert_eq!(nb.model.bfis.get_frequency(&bfi(1), &l1).0, None);
+    }
+
+    #[test]
+    fn removed_links_are_forgotten() {
+        let mut nb = Bayes::new();
+        let l1 = LinkId::listen(ReplyTo::Rf(0));
+        let l2 = LinkId::listen(ReplyTo::Rf(1));
+        nb.model.train(&vec![bfi(1)], &l1);
+        nb.model.train(&vec![bfi(1)], &l2);
+        nb.remove_link(&l1);
+        let classes = nb.classify(&vec![bfi(1)]);
+        assert_eq!(classes.len(), 1);
+        assert_eq!(classes[0].linkid, l2);
+        assert_eq!(nb.model.bfis.get_frequency(&bfi(1), &l1).0, None);
+    }
+}
@@ -1,20 +1,247 @@
 use {
-    copernica_common::{HBFI, BFI},
-    std::{ collections::HashMap, },
+    copernica_common::{HBFI},
+    std::{
+        collections::{VecDeque, hash_map::DefaultHasher},
+        hash::{Hash, Hasher},
+        time::{Duration, Instant},
+    },
 };
 
+/// How long a pending or forwarded request is remembered without a
+/// matching Response before it may be forwarded again.
+pub const REQUEST_LIFETIME: Duration = Duration::from_secs(1);
+
+/// How long after a forwarded request is forgotten its Response still
+/// counts as late rather than unsolicited. It is as long as a fetch
+/// waits at most before retransmitting.
+pub const RESPONSE_GRACE: Duration = Duration::from_secs(8);
+
+/// Sizes the counting Bloom filters of every link. Each of the pending,
+/// forwarded and expired filters is split into `generations` filters of
+/// `counters()` one byte counters, so a link costs
+/// `3 * generations * counters()` bytes whatever the traffic.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomConfig {
+    capacity: usize,
+    false_positive_rate: f64,
+    lifetime: Duration,
+    grace: Duration,
+    generations: usize,
+}
+
+impl Default for BloomConfig {
+    fn default() -> Self {
+        Self {
+            capacity: 1024,
+            false_positive_rate: 0.01,
+            lifetime: REQUEST_LIFETIME,
+            grace: RESPONSE_GRACE,
+            generations: 4,
+        }
+    }
+}
+
+impl BloomConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Requests in flight on a link, per generation, at which the false
+    /// positive rate is reached.
+    pub fn capacity(mut self, capacity: usize) -> Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    pub fn false_positive_rate(mut self, false_positive_rate: f64) -> Self {
+        self.false_positive_rate = false_positive_rate.clamp(1e-9, 0.5);
+        self
+    }
+
+    /// How long a request is remembered. It is forgotten between
+    /// `lifetime * (generations - 1) / generations` and `lifetime` after
+    /// it was last seen.
+    pub fn lifetime(mut self, lifetime: Duration) -> Self {
+        self.lifetime = lifetime;
+        self
+    }
+
+    /// How long a forwarded request is remembered once its lifetime is
+    /// over, so that its Response isn't taken for unsolicited.
+    pub fn grace(mut self, grace: Duration) -> Self {
+        self.grace = grace;
+        self
+    }
+
+    pub fn generations(mut self, generations: usize) -> Self {
+        self.generations = generations.max(2);
+        self
+    }
+
+    pub fn get_lifetime(&self) -> Duration {
+        self.lifetime
+    }
+
+    pub fn get_grace(&self) -> Duration {
+        self.grace
+    }
+
+    /// Time between generations; the broker sweeps this often.
+    pub fn rotation(&self) -> Duration {
+        self.lifetime / self.generations as u32
+    }
+
+    /// m = -n ln(p) / ln(2)^2
+    pub fn counters(&self) -> usize {
+        let ln2 = std::f64::consts::LN_2;
+        let m = -(self.capacity as f64) * self.false_positive_rate.ln() / (ln2 * ln2);
+        (m.ceil() as usize).max(1)
+    }
+
+    /// k = m / n ln(2)
+    pub fn hashes(&self) -> usize {
+        let k = self.counters() as f64 / self.capacity as f64 * std::f64::consts::LN_2;
+        (k.round() as usize).max(1)
+    }
+}
+
+/// Occupancy of one counting Bloom filter, across all its generations.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FilterStats {
+    pub counters: usize,
+    pub hashes: usize,
+    /// Fraction of counters in use.
+    pub fill: f64,
+    /// Distinct requests the filter appears to hold.
+    pub estimated_items: f64,
+    /// Chance that `contains_*` is true for a request never created.
+    pub false_positive_rate: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BloomStats {
+    pub pending: FilterStats,
+    pub forwarded: FilterStats,
+    pub expired: FilterStats,
+}
+
+#[derive(Clone)]
+struct CountingBloom {
+    hashes: usize,
+    generations: VecDeque<Vec<u8>>,
+}
+
+impl CountingBloom {
+    fn new(config: &BloomConfig) -> Self {
+        let generations = (0..config.generations).map(|_| vec![0u8; config.counters()]).collect();
+        Self { hashes: config.hashes(), generations }
+    }
+
+    // double hashing, g_i(x) = h1(x) + i * h2(x)
+    fn indexes(&self, hbfi: &HBFI) -> Vec<usize> {
+        let mut hasher = DefaultHasher::new();
+        hbfi.hash(&mut hasher);
+        let h1 = hasher.finish();
+        0xc0be_u16.hash(&mut hasher);
+        let h2 = hasher.finish() | 1;
+        let m = self.generations[0].len() as u64;
+        (0..self.hashes as u64)
+            .map(|i| (h1.wrapping_add(i.wrapping_mul(h2)) % m) as usize)
+            .collect()
+    }
+
+    fn insert(&mut self, hbfi: &HBFI) {
+        let indexes = self.indexes(hbfi);
+        if let Some(newest) = self.generations.back_mut() {
+            for i in indexes {
+                newest[i] = newest[i].saturating_add(1);
+            }
+        }
+    }
+
+    fn contains(&self, hbfi: &HBFI) -> bool {
+        let indexes = self.indexes(hbfi);
+        self.generations.iter().any(|generation| indexes.iter().all(|i| generation[*i] > 0))
+    }
+
+    // Counters that saturated are never decremented, they may be shared
+    // with more requests than can be counted.
+    fn remove(&mut self, hbfi: &HBFI) {
+        let indexes = self.indexes(hbfi);
+        for generation in self.generations.iter_mut().rev() {
+            if indexes.iter().all(|i| generation[*i] > 0) {
+                for i in indexes {
+                    if generation[i] < u8::MAX {
+                        generation[i] -= 1;
+                    }
+                }
+                return;
+            }
+        }
+    }
+
+    // Adds the oldest generation, about to be retired, to `into`'s newest.
+    fn retire_into(&self, into: &mut CountingBloom) {
+        if let (Some(oldest), Some(newest)) = (self.generations.front(), into.generations.back_mut()) {
+            for (counter, retired) in newest.iter_mut().zip(oldest) {
+                *counter = counter.saturating_add(*retired);
+            }
+        }
+    }
+
+    fn rotate(&mut self) {
+        if let Some(mut oldest) = self.generations.pop_front() {
+            for counter in oldest.iter_mut() {
+                *counter = 0;
+            }
+            self.generations.push_back(oldest);
+        }
+    }
+
+    fn stats(&self) -> FilterStats {
+        let m = self.generations[0].len();
+        let k = self.hashes as f64;
+        let used = (0..m).filter(|i| self.generations.iter().any(|g| g[*i] > 0)).count();
+        let fill = used as f64 / m as f64;
+        let estimated_items = if used == m {
+            f64::INFINITY
+        } else {
+            -(m as f64) / k * (1.0 - fill).ln()
+        };
+        FilterStats {
+            counters: m,
+            hashes: self.hashes,
+            fill,
+            estimated_items,
+            false_positive_rate: fill.powf(k),
+        }
+    }
+}
+
 #[derive(Clone)]
 pub struct Blooms {
-    pending_request: HashMap<Vec<BFI>, u64>,
-    forwarded_request: HashMap<Vec<BFI>, u64>,
+    pending_request: CountingBloom,
+    forwarded_request: CountingBloom,
+    expired_request: CountingBloom,
+    config: BloomConfig,
+    rotated: Instant,
+    expired_rotated: Instant,
 }
 
 impl Blooms {
     #[allow(dead_code)]
     pub fn new() -> Self {
+        Self::with_config(BloomConfig::default())
+    }
+
+    pub fn with_config(config: BloomConfig) -> Self {
         Self {
-            pending_request: HashMap::new(),
-            forwarded_request: HashMap::new(),
+            pending_request: CountingBloom::new(&config),
+            forwarded_request: CountingBloom::new(&config),
+            expired_request: CountingBloom::new(&config),
+            config,
+            rotated: Instant::now(),
+            expired_rotated: Instant::now(),
         }
     }
 
@@ -24,22 +251,14 @@ impl Blooms {
     // specifically which nodes to not forward to again.
 
     pub fn create_pending_request(&mut self, hbfi: &HBFI) {
-        *self.pending_request.entry(hbfi.to_vec()).or_insert(0) += 1;
+        self.pending_request.insert(hbfi);
     }
     pub fn contains_pending_request(&self, hbfi: &HBFI) -> bool {
-        if let Some(contains) = self.pending_request.get(&hbfi.to_vec()) {
-            if contains > &0 {
-                return true
-            } else {
-                return false
-            }
-        } else {
-            return false
-        }
+        self.pending_request.contains(hbfi)
     }
     #[allow(dead_code)]
     pub fn delete_pending_request(&mut self, hbfi: &HBFI) {
-        *self.pending_request.entry(hbfi.to_vec()).or_insert(0) -= 1;
+        self.pending_request.remove(hbfi);
     }
 
     // Forwarded Request Sparse Distributed Representation
@@ -48,21 +267,139 @@ impl Blooms {
     // this mixed up with Pending Requests, which has the specific purpose
     // of determining which faces are upstream nodes
     pub fn create_forwarded_request(&mut self, hbfi: &HBFI) {
-        *self.forwarded_request.entry(hbfi.to_vec()).or_insert(0) += 1;
+        self.forwarded_request.insert(hbfi);
     }
     pub fn contains_forwarded_request(&self, hbfi: &HBFI) -> bool {
-        if let Some(contains) = self.forwarded_request.get(&hbfi.to_vec()) {
-            if contains > &0 {
-                return true
-            } else {
-                return false
-            }
-        } else {
-            return false
-        }
+        self.forwarded_request.contains(hbfi)
     }
     #[allow(dead_code)]
     pub fn delete_forwarded_request(&mut self, hbfi: &HBFI) {
-        *self.forwarded_request.entry(hbfi.to_vec()).or_insert(0) -= 1;
+        self.forwarded_request.remove(hbfi);
+    }
+
+    // Expired Request Sparse Distributed Representation
+    // Forwarded requests that outlived their lifetime, kept for the grace
+    // period so that a Response arriving late over a slow link isn't
+    // taken for unsolicited.
+    pub fn contains_expired_request(&self, hbfi: &HBFI) -> bool {
+        self.expired_request.contains(hbfi)
+    }
+    pub fn delete_expired_request(&mut self, hbfi: &HBFI) {
+        self.expired_request.remove(hbfi);
+    }
+
+    /// Forgets requests that outlived their lifetime without a Response
+    /// by retiring the generations that are due. Retired forwarded
+    /// requests are kept as expired for the grace period.
+    pub fn sweep(&mut self) {
+        let generations = self.config.generations;
+        let expired_rotation = self.config.grace / generations as u32;
+        for _ in 0..due(&mut self.expired_rotated, expired_rotation, generations) {
+            self.expired_request.rotate();
+        }
+        for _ in 0..due(&mut self.rotated, self.config.rotation(), generations) {
+            self.forwarded_request.retire_into(&mut self.expired_request);
+            self.pending_request.rotate();
+            self.forwarded_request.rotate();
+        }
+    }
+
+    pub fn stats(&self) -> BloomStats {
+        BloomStats {
+            pending: self.pending_request.stats(),
+            forwarded: self.forwarded_request.stats(),
+            expired: self.expired_request.stats(),
+        }
+    }
+}
+
+// Generations due to retire since `rotated`, every one of them once a
+// whole lifetime has passed.
+fn due(rotated: &mut Instant, rotation: Duration, generations: usize) -> usize {
+    let mut due = 0;
+    while rotated.elapsed() >= rotation && due < generations {
+        *rotated += rotation;
+        due += 1;
+    }
+    if due == generations {
+        *rotated = Instant::now();
+    }
+    due
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn requests_expire() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let config = BloomConfig::new().lifetime(Duration::from_millis(40)).generations(4);
+        let mut blooms = Blooms::with_config(config);
+        blooms.create_forwarded_request(&hbfi);
+        blooms.create_pending_request(&hbfi.clone().offset(1));
+        assert!(blooms.contains_forwarded_request(&hbfi));
+        assert!(!blooms.contains_forwarded_request(&hbfi.clone().offset(1)));
+        thread::sleep(Duration::from_millis(50));
+        blooms.sweep();
+        assert!(!blooms.contains_forwarded_request(&hbfi));
+        assert!(!blooms.contains_pending_request(&hbfi.clone().offset(1)));
+        assert_eq!(blooms.stats().forwarded.fill, 0.0);
+    }
+
+    #[test]
+    fn expired_requests_are_kept_for_the_grace_period() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let config = BloomConfig::new()
+            .lifetime(Duration::from_millis(40))
+            .grace(Duration::from_millis(200))
+            .generations(4);
+        let mut blooms = Blooms::with_config(config);
+        blooms.create_forwarded_request(&hbfi);
+        assert!(!blooms.contains_expired_request(&hbfi));
+        thread::sleep(Duration::from_millis(50));
+        blooms.sweep();
+        assert!(!blooms.contains_forwarded_request(&hbfi));
+        assert!(blooms.contains_expired_request(&hbfi));
+        thread::sleep(Duration::from_millis(250));
+        blooms.sweep();
+        assert!(!blooms.contains_expired_request(&hbfi));
+        assert_eq!(blooms.stats().expired.fill, 0.0);
+    }
+
+    #[test]
+    fn responses_clear_requests() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let mut blooms = Blooms::new();
+        blooms.create_pending_request(&hbfi);
+        blooms.create_pending_request(&hbfi);
+        blooms.delete_pending_request(&hbfi);
+        assert!(blooms.contains_pending_request(&hbfi));
+        blooms.delete_pending_request(&hbfi);
+        blooms.delete_pending_request(&hbfi);
+        assert!(!blooms.contains_pending_request(&hbfi));
+        assert_eq!(blooms.stats().pending.fill, 0.0);
+    }
+
+    #[test]
+    fn memory_is_bounded_and_false_positives_match_the_target() {
+        let config = BloomConfig::new().capacity(1000).false_positive_rate(0.01).generations(2);
+        let mut blooms = Blooms::with_config(config);
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        for os in 0..1000 {
+            blooms.create_forwarded_request(&hbfi.clone().offset(os));
+        }
+        let stats = blooms.stats().forwarded;
+        assert_eq!(stats.counters, config.counters());
+        assert!((stats.estimated_items - 1000.0).abs() < 100.0, "{:?}", stats);
+        assert!(stats.false_positive_rate < 0.02, "{:?}", stats);
+        let false_positives = (1000..11000)
+            .filter(|os| blooms.contains_forwarded_request(&hbfi.clone().offset(*os)))
+            .count();
+        assert!(false_positives < 200, "{} false positives", false_positives);
+        for os in 0..1000 {
+            assert!(blooms.contains_forwarded_request(&hbfi.clone().offset(os)));
+        }
     }
 }
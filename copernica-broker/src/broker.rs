@@ -1,19 +1,32 @@
 use {
     crate::{
-        bloom_filter::Blooms,
+        bloom_filter::{Blooms, BloomConfig, BloomStats},
+        content_store::{ContentStore, ContentStoreStats, Eviction},
+        defcon::{DefconConfig, DefconStats, Mitigations},
         router::Router,
-        Bayes,
+        Bayes, BayesSnapshot,
     },
-    copernica_common::{Nonce, LinkId, InterLinkPacket},
+    copernica_common::{Nonce, LinkId, InterLinkPacket, ReplyTo, ResponseStore, StopHandle, STOP_POLL},
+    borsh::{BorshDeserialize, BorshSerialize},
     anyhow::{anyhow, Result},
-    crossbeam_channel::{unbounded, Receiver, Sender},
-    std::collections::HashMap,
+    crossbeam_channel::{unbounded, Receiver, Sender, RecvTimeoutError},
+    std::{
+        collections::HashMap,
+        sync::{Arc, Mutex},
+        time::{Duration, Instant},
+    },
     log::{
-        error, trace,
+        error, trace, warn,
         //debug
     },
 };
 
+/// The name the Bayes model is saved under in the broker's store.
+pub const BAYES_STATE: &str = "bayes";
+pub const CHECKPOINT_INTERVAL: Duration = Duration::from_secs(30);
+/// How long before what the router learnt about a link counts half as much.
+pub const BAYES_HALF_LIFE: Duration = Duration::from_secs(60 * 60);
+
 /*
      s = Service, l = Link, b = Broker, r = Router, 2 = to: e.g. l2b = "link to copernica_broker"
      link::{udp, mpsc_channel, mpsc_corruptor, etc}
@@ -39,8 +52,8 @@ use {
 */
 
 #[derive(Clone)]
-pub struct Broker {
-    rs: sled::Db,
+pub struct Broker<S: ResponseStore = sled::Db> {
+    cs: ContentStore<S>,
     l2b_tx: Sender<InterLinkPacket>,   // give to link
     l2b_rx: Receiver<InterLinkPacket>, // keep in broker
     b2l: HashMap<
@@ -52,29 +65,169 @@ pub struct Broker {
     >,
     r2b_tx: Sender<InterLinkPacket>,   // give to router
     r2b_rx: Receiver<InterLinkPacket>, // keep in broker
+    unpeer_tx: Sender<LinkId>,
+    unpeer_rx: Receiver<LinkId>,
     blooms: HashMap<LinkId, Blooms>,
+    bloom_config: BloomConfig,
+    bloom_stats: Arc<Mutex<HashMap<LinkId, BloomStats>>>,
+    defcon_config: DefconConfig,
+    defcon_stats: Arc<Mutex<DefconStats>>,
+    names: HashMap<LinkId, String>,
+    checkpoint_interval: Duration,
+    half_life: Duration,
+    stop: StopHandle,
 }
 
-impl Broker {
-    pub fn new(rs: sled::Db) -> Self {
+impl<S: ResponseStore> Broker<S> {
+    pub fn new(rs: S) -> Self {
         let (l2b_tx, l2b_rx) = unbounded::<InterLinkPacket>();
         let (r2b_tx, r2b_rx) = unbounded::<InterLinkPacket>();
+        let (unpeer_tx, unpeer_rx) = unbounded::<LinkId>();
         let b2l = HashMap::new();
         let blooms = HashMap::new();
         Self {
-            rs,
+            cs: ContentStore::new(rs),
             l2b_tx,
             l2b_rx,
             r2b_tx,
             r2b_rx,
+            unpeer_tx,
+            unpeer_rx,
             b2l,
             blooms,
+            bloom_config: BloomConfig::default(),
+            bloom_stats: Arc::new(Mutex::new(HashMap::new())),
+            defcon_config: DefconConfig::default(),
+            defcon_stats: Arc::new(Mutex::new(DefconStats::default())),
+            names: HashMap::new(),
+            checkpoint_interval: CHECKPOINT_INTERVAL,
+            half_life: BAYES_HALF_LIFE,
+            stop: StopHandle::new(),
+        }
+    }
+
+    /// Stops the running broker when `stop` is, once it has saved what
+    /// its Bayes model learnt.
+    pub fn stop_handle(mut self, stop: StopHandle) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// How quickly the router stops preferring links that used to answer
+    /// Requests, `BAYES_HALF_LIFE` by default.
+    pub fn half_life(mut self, half_life: Duration) -> Self {
+        self.half_life = half_life;
+        self
+    }
+
+    /// How often the running broker saves what its Bayes model learnt
+    /// to its store, to be restored by the next `run`.
+    pub fn checkpoint_interval(mut self, interval: Duration) -> Self {
+        self.checkpoint_interval = interval;
+        self
+    }
+
+    /// How long a forwarded Request waits for its Response before the
+    /// Router forgets it and may forward it again.
+    pub fn request_lifetime(mut self, lifetime: Duration) -> Self {
+        self.bloom_config = self.bloom_config.lifetime(lifetime);
+        self
+    }
+
+    /// Bounds the Responses cached for other nodes to `max_bytes` on disk.
+    pub fn max_content_bytes(mut self, max_bytes: u64) -> Self {
+        self.cs = self.cs.max_bytes(max_bytes);
+        self
+    }
+
+    pub fn max_content_entries(mut self, max_entries: usize) -> Self {
+        self.cs = self.cs.max_entries(max_entries);
+        self
+    }
+
+    /// What makes room in a full content store, `Eviction::Lru` by default.
+    pub fn eviction(mut self, eviction: Eviction) -> Self {
+        self.cs = self.cs.eviction(eviction);
+        self
+    }
+
+    pub fn content_store_stats(&self) -> ContentStoreStats {
+        self.cs.stats()
+    }
+
+    /// Sizes the Bloom filters of links peered after this call.
+    pub fn bloom_config(mut self, config: BloomConfig) -> Self {
+        self.bloom_config = config;
+        self
+    }
+
+    /// Fill and false positive estimates of each link's Bloom filters,
+    /// refreshed every time the running broker sweeps them.
+    pub fn bloom_stats(&self) -> HashMap<LinkId, BloomStats> {
+        match self.bloom_stats.lock() {
+            Ok(stats) => stats.clone(),
+            Err(_) => HashMap::new(),
+        }
+    }
+
+    /// Thresholds and limits of the Router's defences against Request
+    /// floods.
+    pub fn defcon(mut self, config: DefconConfig) -> Self {
+        self.defcon_config = config;
+        self
+    }
+
+    /// What the Router's defences refused, refreshed every time the
+    /// running broker sweeps its Bloom filters.
+    pub fn defcon_stats(&self) -> DefconStats {
+        match self.defcon_stats.lock() {
+            Ok(stats) => *stats,
+            Err(_) => DefconStats::default(),
         }
     }
 
+    /// Peers a link. What the router learns about it outlives a restart
+    /// only if its `ReplyTo` names a remote, otherwise see `named_peer`.
     pub fn peer(
         &mut self,
         link_id: LinkId,
+    ) -> Result<(Sender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
+        match stable_name(&link_id) {
+            Some(name) => self.named_peer(link_id, &name),
+            None => self.add_peer(link_id),
+        }
+    }
+
+    /// Peers a link under a `name` that is the same every time this node
+    /// starts, so the routing model can be restored for it.
+    pub fn named_peer(
+        &mut self,
+        link_id: LinkId,
+        name: &str,
+    ) -> Result<(Sender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
+        let channels = self.add_peer(link_id.clone())?;
+        self.names.insert(link_id, name.to_string());
+        Ok(channels)
+    }
+
+    /// Forgets a link that went away, including what the router learnt
+    /// about it, so Requests stop being routed its way.
+    pub fn unpeer(&mut self, link_id: &LinkId) -> Result<()> {
+        match self.blooms.remove(link_id) {
+            Some(_) => {
+                trace!("REMOVING REMOTE: {:?}", link_id);
+                self.b2l.remove(&link_id.nonce());
+                self.names.remove(link_id);
+                self.unpeer_tx.send(link_id.clone())?;
+                Ok(())
+            },
+            None => Err(anyhow!("Channel not initialized")),
+        }
+    }
+
+    fn add_peer(
+        &mut self,
+        link_id: LinkId,
     ) -> Result<(Sender<InterLinkPacket>, Receiver<InterLinkPacket>)> {
         match self.blooms.get(&link_id) {
             Some(_) => Err(anyhow!("Channel already initialized")),
@@ -82,7 +235,7 @@ impl Broker {
                 let (b2l_tx, b2l_rx) = unbounded::<InterLinkPacket>();
                 self.b2l.insert(link_id.nonce(), (b2l_tx.clone(), b2l_rx.clone()));
                 trace!("ADDING REMOTE: {:?}", link_id);
-                self.blooms.insert(link_id, Blooms::new());
+                self.blooms.insert(link_id, Blooms::with_config(self.bloom_config));
                 Ok((self.l2b_tx.clone(), b2l_rx))
             }
         }
@@ -93,24 +246,75 @@ impl Broker {
         let l2b_rx = self.l2b_rx.clone();
         let mut blooms = self.blooms.clone();
         let deep_six = LinkId::deep_six();
-        let b2l = self.b2l.clone();
+        let mut b2l = self.b2l.clone();
         let r2b_tx = self.r2b_tx.clone();
         let r2b_rx = self.r2b_rx.clone();
-        let mut bayes = Bayes::new();
+        let unpeer_rx = self.unpeer_rx.clone();
+        let mut bayes = Bayes::new().half_life(self.half_life);
         for (link_id, _) in &blooms {
             bayes.add_link(&link_id);
         }
-        let rs = self.rs.clone();
-        std::thread::spawn(move || {
+        let mut names = self.names.clone();
+        names.insert(deep_six.clone(), "deep_six".into());
+        if let Some(snapshot) = self.cs.load_state(BAYES_STATE)? {
+            match BayesSnapshot::try_from_slice(&snapshot) {
+                Ok(snapshot) => bayes.restore(snapshot, &names),
+                Err(e) => warn!("discarding unreadable routing model: {}", e),
+            }
+        }
+        let checkpoint_interval = self.checkpoint_interval;
+        let cs = self.cs.clone();
+        let bloom_config = self.bloom_config;
+        let bloom_stats = self.bloom_stats.clone();
+        let mut mitigations = Mitigations::new(self.defcon_config);
+        let defcon_stats = self.defcon_stats.clone();
+        let stop = self.stop.clone();
+        self.stop.spawn(move || {
+            let rotation = bloom_config.rotation();
+            let mut last_sweep = Instant::now();
+            let mut last_checkpoint = Instant::now();
             loop {
-                match l2b_rx.recv() {
+                if stop.is_stopped() {
+                    if let Err(e) = checkpoint(&cs, &bayes, &names) {
+                        error!("failed to checkpoint routing model: {}", e);
+                    }
+                    break;
+                }
+                while let Ok(link_id) = unpeer_rx.try_recv() {
+                    blooms.remove(&link_id);
+                    b2l.remove(&link_id.nonce());
+                    bayes.remove_link(&link_id);
+                    mitigations.remove_link(&link_id);
+                    names.remove(&link_id);
+                }
+                if last_checkpoint.elapsed() >= checkpoint_interval {
+                    if let Err(e) = checkpoint(&cs, &bayes, &names) {
+                        error!("failed to checkpoint routing model: {}", e);
+                    }
+                    last_checkpoint = Instant::now();
+                }
+                if last_sweep.elapsed() >= rotation {
+                    let mut stats = HashMap::new();
+                    for (link_id, bloom) in blooms.iter_mut() {
+                        bloom.sweep();
+                        stats.insert(link_id.clone(), bloom.stats());
+                    }
+                    if let Ok(mut bloom_stats) = bloom_stats.lock() {
+                        *bloom_stats = stats;
+                    }
+                    if let Ok(mut defcon_stats) = defcon_stats.lock() {
+                        *defcon_stats = mitigations.stats();
+                    }
+                    last_sweep = Instant::now();
+                }
+                match l2b_rx.recv_timeout(rotation.min(STOP_POLL)) {
                     Ok(ilp) => {
                         if !blooms.contains_key(&ilp.link_id()) {
                             trace!("ADDING {:?} to BLOOMS", ilp);
-                            blooms.insert(ilp.link_id(), Blooms::new());
+                            blooms.insert(ilp.link_id(), Blooms::with_config(bloom_config));
                             bayes.add_link(&ilp.link_id());
                         }
-                        Router::handle_packet(&ilp, r2b_tx.clone(), rs.clone(), &mut blooms, &mut bayes, &deep_six)?;
+                        Router::handle_packet(&ilp, r2b_tx.clone(), &cs, &mut blooms, &mut bayes, &mut mitigations, &deep_six)?;
                         while !r2b_rx.is_empty() {
                             let ilp = r2b_rx.recv()?;
                             if let Some((b2l_tx, _)) = b2l.get(&ilp.link_id().nonce()) {
@@ -118,6 +322,7 @@ impl Broker {
                             }
                         }
                     }
+                    Err(RecvTimeoutError::Timeout) => {},
                     Err(error) => error!("{}", anyhow!("{}", error)),
                 }
             }
@@ -126,3 +331,188 @@ impl Broker {
         Ok(())
     }
 }
+
+fn checkpoint<S: ResponseStore>(cs: &ContentStore<S>, bayes: &Bayes, names: &HashMap<LinkId, String>) -> Result<()> {
+    cs.save_state(BAYES_STATE, &bayes.snapshot(names).try_to_vec()?)?;
+    cs.flush()
+}
+
+// Nonces are drawn afresh each start, the remote a link talks to isn't.
+fn stable_name(link_id: &LinkId) -> Option<String> {
+    match link_id.reply_to() {
+        ReplyTo::UdpIp(addr) => Some(format!("udp_ip:{}", addr)),
+        ReplyTo::Tcp(addr) => Some(format!("tcp:{}", addr)),
+        ReplyTo::Rf(hertz) => Some(format!("rf:{}", hertz)),
+        ReplyTo::DeepSix => Some("deep_six".into()),
+        ReplyTo::Mpsc => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{constants, Data, HBFI, LinkPacket, MemoryStore, NarrowWaistPacket, PrivateIdentity};
+
+    #[test]
+    fn checkpoints_the_routing_model() {
+        let rs = MemoryStore::new();
+        let mut broker = Broker::new(rs.clone()).checkpoint_interval(Duration::from_millis(10));
+        let link_id = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50120".parse().unwrap()));
+        let (l2b_tx, _b2l_rx) = broker.peer(link_id.clone()).unwrap();
+        broker.run().unwrap();
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let lp = LinkPacket::new(link_id.reply_to(), NarrowWaistPacket::request(hbfi));
+        l2b_tx.send(InterLinkPacket::new(link_id, lp)).unwrap();
+        std::thread::sleep(Duration::from_millis(300));
+        let snapshot = rs.load_state(BAYES_STATE).unwrap().expect("a checkpoint");
+        let snapshot = BayesSnapshot::try_from_slice(&snapshot).unwrap();
+        let names: Vec<&str> = snapshot.links.iter().map(|link| link.name.as_str()).collect();
+        assert!(names.contains(&"udp_ip:127.0.0.1:50120"));
+        assert!(names.contains(&"deep_six"));
+    }
+
+    #[test]
+    fn unpeered_links_are_forgotten() {
+        let rs = MemoryStore::new();
+        let mut broker = Broker::new(rs.clone()).checkpoint_interval(Duration::from_millis(10));
+        let link_id = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50121".parse().unwrap()));
+        let (l2b_tx, _b2l_rx) = broker.peer(link_id.clone()).unwrap();
+        broker.run().unwrap();
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let lp = LinkPacket::new(link_id.reply_to(), NarrowWaistPacket::request(hbfi));
+        l2b_tx.send(InterLinkPacket::new(link_id.clone(), lp)).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        broker.unpeer(&link_id).unwrap();
+        assert!(broker.unpeer(&link_id).is_err());
+        std::thread::sleep(Duration::from_millis(300));
+        let snapshot = rs.load_state(BAYES_STATE).unwrap().expect("a checkpoint");
+        let snapshot = BayesSnapshot::try_from_slice(&snapshot).unwrap();
+        assert!(snapshot.links.iter().all(|link| link.name != "udp_ip:127.0.0.1:50121"));
+        assert!(broker.peer(link_id).is_ok());
+    }
+
+    #[test]
+    fn counts_refused_requests() {
+        let defcon = DefconConfig::new().thresholds(0.0, 0.0, 2.0).drop_probability(1.0).strikes(3);
+        let mut broker = Broker::new(MemoryStore::new()).defcon(defcon);
+        let link_id = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50122".parse().unwrap()));
+        let (l2b_tx, _b2l_rx) = broker.peer(link_id.clone()).unwrap();
+        broker.run().unwrap();
+        for n in 0..5 {
+            let hbfi = HBFI::new(&format!("app{}", n), "publisher").unwrap();
+            let lp = LinkPacket::new(link_id.reply_to(), NarrowWaistPacket::request(hbfi));
+            l2b_tx.send(InterLinkPacket::new(link_id.clone(), lp)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(600));
+        let stats = broker.defcon_stats();
+        assert_eq!(stats.requests, 3);
+        assert_eq!(stats.dropped, 3);
+        assert_eq!(stats.quarantines, 1);
+        assert_eq!(stats.quarantined, 2);
+        assert_eq!(stats.quarantined_links, 1);
+    }
+
+    #[test]
+    fn drops_replayed_requests() {
+        let mut broker = Broker::new(MemoryStore::new());
+        let link_id = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50123".parse().unwrap()));
+        let (l2b_tx, _b2l_rx) = broker.peer(link_id.clone()).unwrap();
+        broker.run().unwrap();
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let request = NarrowWaistPacket::request(hbfi.clone());
+        for nw in [request.clone(), request, NarrowWaistPacket::request(hbfi)] {
+            let lp = LinkPacket::new(link_id.reply_to(), nw);
+            l2b_tx.send(InterLinkPacket::new(link_id.clone(), lp)).unwrap();
+        }
+        std::thread::sleep(Duration::from_millis(600));
+        let stats = broker.defcon_stats();
+        assert_eq!(stats.replays, 1);
+        assert_eq!(stats.requests, 2);
+    }
+
+    #[test]
+    fn late_responses_are_not_unsolicited() {
+        let mut broker = Broker::new(MemoryStore::new()).request_lifetime(Duration::from_millis(40));
+        let downstream = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50126".parse().unwrap()));
+        let upstream = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50127".parse().unwrap()));
+        let (downstream_tx, _) = broker.peer(downstream.clone()).unwrap();
+        let (upstream_tx, upstream_rx) = broker.peer(upstream.clone()).unwrap();
+        broker.run().unwrap();
+        let identity = PrivateIdentity::generate();
+        let response = |name: &str| {
+            let hbfi = HBFI::new(name, &identity.public_id().to_string()).unwrap();
+            let data = Data { len: 0, data: [0; constants::FRAGMENT_SIZE as usize] };
+            let nw = NarrowWaistPacket::signed_response(&identity, hbfi.clone(), data, 0, 1, vec![]).unwrap();
+            InterLinkPacket::new(upstream.clone(), LinkPacket::new(upstream.reply_to(), nw))
+        };
+        let hbfi = HBFI::new("late", &identity.public_id().to_string()).unwrap();
+        let lp = LinkPacket::new(downstream.reply_to(), NarrowWaistPacket::request(hbfi));
+        downstream_tx.send(InterLinkPacket::new(downstream, lp)).unwrap();
+        assert!(upstream_rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        // answered well after the Request was forgotten
+        std::thread::sleep(Duration::from_millis(200));
+        upstream_tx.send(response("late")).unwrap();
+        upstream_tx.send(response("never requested")).unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        assert_eq!(broker.defcon_stats().unsolicited, 1);
+        assert_eq!(broker.content_store_stats().entries, 1);
+    }
+
+    #[test]
+    fn request_floods_do_not_quarantine_responses() {
+        let defcon = DefconConfig::new().thresholds(0.0, 2.0, 3.0).rate_limit(0.0, 1.0).strikes(2);
+        let mut broker = Broker::new(MemoryStore::new()).defcon(defcon);
+        let downstream = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50128".parse().unwrap()));
+        let flooder = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50129".parse().unwrap()));
+        let (downstream_tx, downstream_rx) = broker.peer(downstream.clone()).unwrap();
+        let (flooder_tx, flooder_rx) = broker.peer(flooder.clone()).unwrap();
+        broker.run().unwrap();
+        let identity = PrivateIdentity::generate();
+        let hbfi = HBFI::new("wanted", &identity.public_id().to_string()).unwrap();
+        let lp = LinkPacket::new(downstream.reply_to(), NarrowWaistPacket::request(hbfi.clone()));
+        downstream_tx.send(InterLinkPacket::new(downstream, lp)).unwrap();
+        assert!(flooder_rx.recv_timeout(Duration::from_secs(1)).is_ok());
+        for n in 0..3 {
+            let hbfi = HBFI::new(&format!("flood{}", n), "publisher").unwrap();
+            let lp = LinkPacket::new(flooder.reply_to(), NarrowWaistPacket::request(hbfi));
+            flooder_tx.send(InterLinkPacket::new(flooder.clone(), lp)).unwrap();
+        }
+        let data = Data { len: 0, data: [0; constants::FRAGMENT_SIZE as usize] };
+        let nw = NarrowWaistPacket::signed_response(&identity, hbfi.clone(), data, 0, 1, vec![]).unwrap();
+        flooder_tx.send(InterLinkPacket::new(flooder.clone(), LinkPacket::new(flooder.reply_to(), nw))).unwrap();
+        let response = loop {
+            match downstream_rx.recv_timeout(Duration::from_secs(1)).expect("the response").narrow_waist() {
+                NarrowWaistPacket::Response { hbfi, .. } => break hbfi,
+                NarrowWaistPacket::Request { .. } => continue,
+            }
+        };
+        assert_eq!(response, hbfi);
+        std::thread::sleep(Duration::from_millis(600));
+        let stats = broker.defcon_stats();
+        assert_eq!(stats.quarantines, 1);
+        assert_eq!(stats.quarantined_links, 1);
+    }
+
+    #[test]
+    fn requests_run_out_of_hops() {
+        let mut broker = Broker::new(MemoryStore::new());
+        let downstream = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50124".parse().unwrap()));
+        let upstream = LinkId::listen(ReplyTo::UdpIp("127.0.0.1:50125".parse().unwrap()));
+        let (l2b_tx, _) = broker.peer(downstream.clone()).unwrap();
+        let (_, b2l_rx) = broker.peer(upstream).unwrap();
+        broker.run().unwrap();
+        for (name, hops) in [("last", 1), ("next", 2)] {
+            let hbfi = HBFI::new(name, "publisher").unwrap();
+            let lp = LinkPacket::new(downstream.reply_to(), NarrowWaistPacket::request(hbfi).hop_limit(hops));
+            l2b_tx.send(InterLinkPacket::new(downstream.clone(), lp)).unwrap();
+        }
+        match b2l_rx.recv_timeout(Duration::from_secs(1)).unwrap().narrow_waist() {
+            NarrowWaistPacket::Request { hbfi, hops, .. } => {
+                assert_eq!(hbfi, HBFI::new("next", "publisher").unwrap());
+                assert_eq!(hops, 1);
+            },
+            _ => panic!("expected a request"),
+        }
+        assert!(b2l_rx.recv_timeout(Duration::from_millis(200)).is_err());
+    }
+}
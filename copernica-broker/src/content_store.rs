@@ -0,0 +1,414 @@
+use {
+    copernica_common::{HBFI, BFI, NarrowWaistPacket, ResponseStore},
+    borsh::{BorshSerialize},
+    anyhow::{anyhow, Result},
+    std::{
+        collections::{BTreeMap, HashMap},
+        sync::{Arc, Mutex, MutexGuard},
+    },
+    log::{trace, warn},
+};
+
+/// Which cached Response makes room for a new one once the store is full.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Eviction {
+    /// The least recently used.
+    Lru,
+    /// The least frequently used, the least recently used among equals.
+    Lfu,
+    /// No publisher may hold more than this many bytes; its least recently
+    /// used Responses go first. Beyond that behaves like `Lru`.
+    PublisherQuota(u64),
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ContentStoreStats {
+    pub entries: usize,
+    pub bytes: u64,
+    pub hits: u64,
+    pub misses: u64,
+    pub evictions: u64,
+}
+
+struct Meta {
+    size: u64,
+    hits: u64,
+    tick: u64,
+    publisher: BFI,
+}
+
+#[derive(Default)]
+struct Publisher {
+    bytes: u64,
+    recency: BTreeMap<u64, HBFI>,
+}
+
+struct Inner<S: ResponseStore> {
+    rs: S,
+    max_bytes: Option<u64>,
+    max_entries: Option<usize>,
+    eviction: Eviction,
+    entries: HashMap<HBFI, Meta>,
+    order: BTreeMap<(u64, u64), HBFI>,
+    publishers: HashMap<BFI, Publisher>,
+    tick: u64,
+    stats: ContentStoreStats,
+}
+
+/// The Responses a broker caches on behalf of its links. Without limits
+/// it grows forever; `max_bytes` and `max_entries` bound it, evicting by
+/// policy, at once if it already holds more.
+pub struct ContentStore<S: ResponseStore = sled::Db> {
+    inner: Arc<Mutex<Inner<S>>>,
+}
+
+impl<S: ResponseStore> Clone for ContentStore<S> {
+    fn clone(&self) -> Self {
+        Self { inner: self.inner.clone() }
+    }
+}
+
+impl<S: ResponseStore> ContentStore<S> {
+    /// Indexes the Responses already in `rs`, treating them as used in
+    /// the order they are listed.
+    pub fn new(rs: S) -> Self {
+        let mut inner = Inner {
+            rs: rs.clone(),
+            max_bytes: None,
+            max_entries: None,
+            eviction: Eviction::Lru,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            publishers: HashMap::new(),
+            tick: 0,
+            stats: ContentStoreStats::default(),
+        };
+        match rs.index() {
+            Ok(index) => for (hbfi, size) in index {
+                inner.track(hbfi, size);
+            },
+            Err(e) => warn!("failed to index content store: {}", e),
+        }
+        Self { inner: Arc::new(Mutex::new(inner)) }
+    }
+
+    pub fn max_bytes(self, max_bytes: u64) -> Self {
+        if let Ok(mut inner) = self.lock() {
+            inner.max_bytes = Some(max_bytes);
+            inner.shrink();
+        }
+        self
+    }
+
+    pub fn max_entries(self, max_entries: usize) -> Self {
+        if let Ok(mut inner) = self.lock() {
+            inner.max_entries = Some(max_entries);
+            inner.shrink();
+        }
+        self
+    }
+
+    pub fn eviction(self, eviction: Eviction) -> Self {
+        if let Ok(mut inner) = self.lock() {
+            inner.eviction = eviction;
+            let entries: Vec<(HBFI, (u64, u64))> = inner.entries.iter()
+                .map(|(key, meta)| (key.clone(), rank(eviction, meta)))
+                .collect();
+            inner.order = entries.into_iter().map(|(key, rank)| (rank, key)).collect();
+            inner.shrink();
+        }
+        self
+    }
+
+    pub fn get(&self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>> {
+        let mut inner = self.lock()?;
+        match inner.rs.get(hbfi)? {
+            Some(nw) => {
+                inner.stats.hits += 1;
+                inner.touch(hbfi);
+                Ok(Some(nw))
+            },
+            None => {
+                inner.stats.misses += 1;
+                Ok(None)
+            },
+        }
+    }
+
+    /// Stores the Response, then evicts until the store is back within its
+    /// limits. A Response larger than `max_bytes` is not kept at all.
+    pub fn insert(&self, hbfi: &HBFI, nw: &NarrowWaistPacket) -> Result<()> {
+        let mut inner = self.lock()?;
+        let size = (hbfi.try_to_vec()?.len() + nw.try_to_vec()?.len()) as u64;
+        inner.rs.insert(hbfi, nw)?;
+        inner.untrack(hbfi);
+        inner.track(hbfi.clone(), size);
+        inner.evict(Some(hbfi))
+    }
+
+    pub fn remove(&self, hbfi: &HBFI) -> Result<()> {
+        let mut inner = self.lock()?;
+        inner.rs.remove(hbfi)?;
+        inner.untrack(hbfi);
+        Ok(())
+    }
+
+    pub fn len(&self) -> usize {
+        self.stats().entries
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn stats(&self) -> ContentStoreStats {
+        match self.lock() {
+            Ok(inner) => inner.stats,
+            Err(_) => ContentStoreStats::default(),
+        }
+    }
+
+    pub fn load_state(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        self.lock()?.rs.load_state(name)
+    }
+
+    pub fn save_state(&self, name: &str, state: &[u8]) -> Result<()> {
+        self.lock()?.rs.save_state(name, state)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.lock()?.rs.flush()
+    }
+
+    fn lock(&self) -> Result<MutexGuard<'_, Inner<S>>> {
+        self.inner.lock().map_err(|_| anyhow!("content store lock poisoned"))
+    }
+}
+
+fn rank(eviction: Eviction, meta: &Meta) -> (u64, u64) {
+    match eviction {
+        Eviction::Lfu => (meta.hits, meta.tick),
+        Eviction::Lru | Eviction::PublisherQuota(_) => (0, meta.tick),
+    }
+}
+
+impl<S: ResponseStore> Inner<S> {
+    fn next_tick(&mut self) -> u64 {
+        self.tick += 1;
+        self.tick
+    }
+
+    fn track(&mut self, hbfi: HBFI, size: u64) {
+        let meta = Meta { size, hits: 0, tick: self.next_tick(), publisher: hbfi.id };
+        self.order.insert(rank(self.eviction, &meta), hbfi.clone());
+        let p = self.publishers.entry(hbfi.id).or_default();
+        p.bytes += size;
+        p.recency.insert(meta.tick, hbfi.clone());
+        self.stats.entries += 1;
+        self.stats.bytes += size;
+        self.entries.insert(hbfi, meta);
+    }
+
+    fn untrack(&mut self, hbfi: &HBFI) -> Option<Meta> {
+        let meta = self.entries.remove(hbfi)?;
+        self.order.remove(&rank(self.eviction, &meta));
+        if let Some(p) = self.publishers.get_mut(&meta.publisher) {
+            p.bytes -= meta.size;
+            p.recency.remove(&meta.tick);
+            if p.recency.is_empty() {
+                self.publishers.remove(&meta.publisher);
+            }
+        }
+        self.stats.entries -= 1;
+        self.stats.bytes -= meta.size;
+        Some(meta)
+    }
+
+    fn touch(&mut self, hbfi: &HBFI) {
+        let eviction = self.eviction;
+        let tick = self.next_tick();
+        if let Some(meta) = self.entries.get_mut(hbfi) {
+            let old_rank = rank(eviction, meta);
+            let old_tick = meta.tick;
+            meta.hits += 1;
+            meta.tick = tick;
+            self.order.remove(&old_rank);
+            self.order.insert(rank(eviction, meta), hbfi.clone());
+            if let Some(p) = self.publishers.get_mut(&meta.publisher) {
+                p.recency.remove(&old_tick);
+                p.recency.insert(tick, hbfi.clone());
+            }
+        }
+    }
+
+    fn over_limit(&self) -> bool {
+        self.max_bytes.is_some_and(|max| self.stats.bytes > max)
+            || self.max_entries.is_some_and(|max| self.stats.entries > max)
+    }
+
+    // Brings a store whose limits or policy just changed within them.
+    fn shrink(&mut self) {
+        if let Err(e) = self.evict(None) {
+            warn!("failed to evict from content store: {}", e);
+        }
+    }
+
+    // The newcomer goes last, else Lfu would always evict it first. Only
+    // its publisher can have gone over quota; without one, any may have.
+    fn evict(&mut self, newcomer: Option<&HBFI>) -> Result<()> {
+        if let Eviction::PublisherQuota(quota) = self.eviction {
+            let publishers: Vec<BFI> = match newcomer {
+                Some(newcomer) => vec![newcomer.id],
+                None => self.publishers.keys().cloned().collect(),
+            };
+            for publisher in publishers {
+                loop {
+                    let victim = match self.publishers.get(&publisher) {
+                        Some(p) if p.bytes > quota => p.recency.values()
+                            .find(|hbfi| Some(*hbfi) != newcomer)
+                            .or_else(|| p.recency.values().next())
+                            .cloned(),
+                        _ => None,
+                    };
+                    match victim {
+                        Some(key) => self.remove_victim(&key)?,
+                        None => break,
+                    }
+                }
+            }
+        }
+        while self.over_limit() {
+            let victim = self.order.values()
+                .find(|hbfi| Some(*hbfi) != newcomer)
+                .or_else(|| self.order.values().next())
+                .cloned();
+            match victim {
+                Some(key) => self.remove_victim(&key)?,
+                None => break,
+            }
+        }
+        Ok(())
+    }
+
+    fn remove_victim(&mut self, hbfi: &HBFI) -> Result<()> {
+        trace!("evicting {:?}", hbfi);
+        self.rs.remove(hbfi)?;
+        self.untrack(hbfi);
+        self.stats.evictions += 1;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::{PrivateIdentity, Data, MemoryStore, constants};
+
+    fn response(identity: &PrivateIdentity, name: &str, offset: u64) -> (HBFI, NarrowWaistPacket) {
+        let hbfi = HBFI::new(name, &identity.public_id().to_string()).unwrap().offset(offset);
+        let data = Data { len: 0, data: [0; constants::FRAGMENT_SIZE as usize] };
+        let nw = NarrowWaistPacket::signed_response(identity, hbfi.clone(), data, offset, 10, vec![]).unwrap();
+        (hbfi, nw)
+    }
+
+    fn size(hbfi: &HBFI, nw: &NarrowWaistPacket) -> u64 {
+        (hbfi.try_to_vec().unwrap().len() + nw.try_to_vec().unwrap().len()) as u64
+    }
+
+    fn store() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    #[test]
+    fn lru_evicts_the_least_recently_used() {
+        let id = PrivateIdentity::generate();
+        let cs = ContentStore::new(MemoryStore::new()).max_entries(2);
+        let (a, na) = response(&id, "app", 1);
+        let (b, nb) = response(&id, "app", 2);
+        let (c, nc) = response(&id, "app", 3);
+        cs.insert(&a, &na).unwrap();
+        cs.insert(&b, &nb).unwrap();
+        assert!(cs.get(&a).unwrap().is_some());
+        cs.insert(&c, &nc).unwrap();
+        assert!(cs.get(&b).unwrap().is_none());
+        assert!(cs.get(&a).unwrap().is_some());
+        assert!(cs.get(&c).unwrap().is_some());
+        assert_eq!(cs.stats().evictions, 1);
+    }
+
+    #[test]
+    fn lfu_evicts_the_least_frequently_used() {
+        let id = PrivateIdentity::generate();
+        let cs = ContentStore::new(MemoryStore::new()).max_entries(2).eviction(Eviction::Lfu);
+        let (a, na) = response(&id, "app", 1);
+        let (b, nb) = response(&id, "app", 2);
+        let (c, nc) = response(&id, "app", 3);
+        cs.insert(&a, &na).unwrap();
+        cs.insert(&b, &nb).unwrap();
+        cs.get(&a).unwrap();
+        cs.get(&a).unwrap();
+        cs.get(&b).unwrap();
+        cs.insert(&c, &nc).unwrap();
+        assert!(cs.get(&b).unwrap().is_none());
+        assert!(cs.get(&a).unwrap().is_some());
+    }
+
+    #[test]
+    fn publisher_quota_spares_other_publishers() {
+        let greedy = PrivateIdentity::generate();
+        let modest = PrivateIdentity::generate();
+        let (m, nm) = response(&modest, "app", 1);
+        let size = size(&m, &nm);
+        let cs = ContentStore::new(MemoryStore::new()).eviction(Eviction::PublisherQuota(size * 3));
+        cs.insert(&m, &nm).unwrap();
+        for offset in 1..10 {
+            let (g, ng) = response(&greedy, "app", offset);
+            cs.insert(&g, &ng).unwrap();
+        }
+        assert!(cs.get(&m).unwrap().is_some());
+        assert_eq!(cs.len(), 4);
+        assert!(cs.get(&response(&greedy, "app", 9).0).unwrap().is_some());
+        assert!(cs.get(&response(&greedy, "app", 6).0).unwrap().is_none());
+    }
+
+    #[test]
+    fn byte_limit_holds_across_reopen() {
+        let id = PrivateIdentity::generate();
+        let db = store();
+        let (h, n) = response(&id, "app", 1);
+        let cs = ContentStore::new(db.clone()).max_bytes(size(&h, &n) * 3);
+        for offset in 1..10 {
+            let (h, n) = response(&id, "app", offset);
+            cs.insert(&h, &n).unwrap();
+        }
+        let stats = cs.stats();
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.evictions, 6);
+        let reopened = ContentStore::new(db);
+        assert_eq!(reopened.stats().bytes, stats.bytes);
+        assert_eq!(reopened.len(), stats.entries);
+    }
+
+    #[test]
+    fn limits_apply_to_what_is_already_stored() {
+        let greedy = PrivateIdentity::generate();
+        let modest = PrivateIdentity::generate();
+        let db = store();
+        let (m, nm) = response(&modest, "app", 1);
+        let size = size(&m, &nm);
+        db.insert(&m, &nm).unwrap();
+        for offset in 1..10 {
+            let (g, ng) = response(&greedy, "app", offset);
+            db.insert(&g, &ng).unwrap();
+        }
+        let cs = ContentStore::new(db).eviction(Eviction::PublisherQuota(size * 5));
+        assert_eq!(cs.len(), 6);
+        assert!(cs.get(&m).unwrap().is_some());
+        let cs = cs.max_entries(4);
+        assert_eq!(cs.len(), 4);
+        let cs = cs.max_bytes(size * 2);
+        assert_eq!(cs.len(), 2);
+        assert_eq!(cs.stats().evictions, 8);
+        assert!(cs.get(&m).unwrap().is_some());
+    }
+}
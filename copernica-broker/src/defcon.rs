@@ -0,0 +1,497 @@
+use {
+    crate::replay_cache::{ReplayCache, REPLAY_CAPACITY, REPLAY_WINDOW},
+    copernica_common::{HBFI, LinkId},
+    rand::Rng,
+    std::{
+        collections::HashMap,
+        time::{Duration, Instant},
+    },
+    log::{debug, warn},
+};
+
+/// How alarmed the Router is, going by how likely the deep six link is to
+/// be the best route for a Request. A Request nobody answers trains deep
+/// six, so a flood of made up Requests drives the level down to `One`.
+/// Replayed Requests are dropped before they train deep six, so they
+/// don't pass for demand.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Defcon {
+    #[default]
+    Four,
+    Three,
+    Two,
+    One,
+}
+
+/// When each Defcon level starts, and what is done at it:
+///
+/// * Four: Requests are routed as usual.
+/// * Three: Requests beyond `rate` a second, with bursts of `burst`, are
+///   dropped per link.
+/// * Two: as Three, and the Requests let through are dropped with
+///   `drop_probability`.
+/// * One: Requests are deep sixed.
+///
+/// A signed Request can't be replayed under a new nonce, so it is handled
+/// as if the level were one better. Requests whose nonce was seen in the
+/// last `replay_window` are dropped at every level.
+///
+/// A link whose Requests are dropped `strikes` times in a row at Three or
+/// worse has its Requests quarantined, none are handled for `quarantine`.
+/// A link that sends `unsolicited` Responses in a row that match no
+/// Request forwarded to it, nor one forgotten within the Bloom filters'
+/// grace period, has its Responses quarantined likewise. Only the traffic
+/// that tripped a quarantine is refused, a link flooding Requests still
+/// delivers the Responses it was asked for.
+///
+/// Whatever the level, a link's Responses train the Router at most
+/// `training_limit` times each `training_window`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DefconConfig {
+    three: f64,
+    two: f64,
+    one: f64,
+    rate: f64,
+    burst: f64,
+    drop_probability: f64,
+    strikes: u32,
+    quarantine: Duration,
+    unsolicited: u32,
+    training_limit: u32,
+    training_window: Duration,
+    replay_window: Duration,
+    replay_capacity: usize,
+}
+
+impl Default for DefconConfig {
+    fn default() -> Self {
+        Self {
+            three: 0.36,
+            two: 0.60,
+            one: 0.90,
+            rate: 100.0,
+            burst: 200.0,
+            drop_probability: 0.5,
+            strikes: 100,
+            quarantine: Duration::from_secs(10),
+            unsolicited: 1000,
+            training_limit: 1000,
+            training_window: Duration::from_secs(1),
+            replay_window: REPLAY_WINDOW,
+            replay_capacity: REPLAY_CAPACITY,
+        }
+    }
+}
+
+impl DefconConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// The deep six weights at which Defcon Three, Two and One start.
+    pub fn thresholds(mut self, three: f64, two: f64, one: f64) -> Self {
+        self.three = three;
+        self.two = two.max(three);
+        self.one = one.max(self.two);
+        self
+    }
+
+    /// Requests a second, and in a burst, each link may send at Defcon
+    /// Three or worse.
+    pub fn rate_limit(mut self, rate: f64, burst: f64) -> Self {
+        self.rate = rate.max(0.0);
+        self.burst = burst.max(1.0);
+        self
+    }
+
+    pub fn drop_probability(mut self, drop_probability: f64) -> Self {
+        self.drop_probability = drop_probability.clamp(0.0, 1.0);
+        self
+    }
+
+    pub fn strikes(mut self, strikes: u32) -> Self {
+        self.strikes = strikes.max(1);
+        self
+    }
+
+    pub fn quarantine(mut self, quarantine: Duration) -> Self {
+        self.quarantine = quarantine;
+        self
+    }
+
+    pub fn unsolicited(mut self, unsolicited: u32) -> Self {
+        self.unsolicited = unsolicited.max(1);
+        self
+    }
+
+    pub fn training_limit(mut self, limit: u32, window: Duration) -> Self {
+        self.training_limit = limit;
+        self.training_window = window;
+        self
+    }
+
+    /// How long, and for how many Requests at most, nonces are remembered.
+    pub fn replay_window(mut self, window: Duration, capacity: usize) -> Self {
+        self.replay_window = window;
+        self.replay_capacity = capacity;
+        self
+    }
+
+    pub fn level(&self, deep_six_weight: f64) -> Defcon {
+        if deep_six_weight >= self.one {
+            Defcon::One
+        } else if deep_six_weight >= self.two {
+            Defcon::Two
+        } else if deep_six_weight >= self.three {
+            Defcon::Three
+        } else {
+            Defcon::Four
+        }
+    }
+}
+
+/// The traffic a quarantine refuses.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Traffic {
+    Requests,
+    Responses,
+}
+
+/// Counts of the Requests the Router refused, since the broker started.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct DefconStats {
+    /// The level the last Request was handled at.
+    pub level: Defcon,
+    /// Requests not found in the content store, which the Router had to
+    /// decide whether to forward.
+    pub requests: u64,
+    pub rate_limited: u64,
+    pub dropped: u64,
+    pub deep_sixed: u64,
+    /// Requests whose nonce had been seen already.
+    pub replays: u64,
+    /// Responses that matched no Request forwarded to their link.
+    pub unsolicited: u64,
+    /// Responses that didn't train the Router, their link had trained
+    /// it `training_limit` times this window.
+    pub training_limited: u64,
+    /// Requests and Responses refused because their link was quarantined.
+    pub quarantined: u64,
+    /// Times a link was put in quarantine.
+    pub quarantines: u64,
+    /// Links with Requests or Responses in quarantine now.
+    pub quarantined_links: usize,
+}
+
+struct Throttle {
+    tokens: f64,
+    refilled: Instant,
+    strikes: u32,
+    requests_quarantined_until: Option<Instant>,
+    unsolicited: u32,
+    responses_quarantined_until: Option<Instant>,
+    trained: u32,
+    window: Instant,
+}
+
+impl Throttle {
+    fn new(config: &DefconConfig) -> Self {
+        let now = Instant::now();
+        Self {
+            tokens: config.burst,
+            refilled: now,
+            strikes: 0,
+            requests_quarantined_until: None,
+            unsolicited: 0,
+            responses_quarantined_until: None,
+            trained: 0,
+            window: now,
+        }
+    }
+
+    fn quarantined_until(&mut self, traffic: Traffic) -> &mut Option<Instant> {
+        match traffic {
+            Traffic::Requests => &mut self.requests_quarantined_until,
+            Traffic::Responses => &mut self.responses_quarantined_until,
+        }
+    }
+
+    fn quarantine(&mut self, config: &DefconConfig, traffic: Traffic) {
+        match traffic {
+            Traffic::Requests => self.strikes = 0,
+            Traffic::Responses => self.unsolicited = 0,
+        }
+        *self.quarantined_until(traffic) = Some(Instant::now() + config.quarantine);
+    }
+
+    fn in_quarantine(&self, now: Instant) -> bool {
+        [self.requests_quarantined_until, self.responses_quarantined_until]
+            .iter()
+            .any(|until| until.is_some_and(|until| now < until))
+    }
+
+    fn take(&mut self, config: &DefconConfig) -> bool {
+        let now = Instant::now();
+        let refill = now.duration_since(self.refilled).as_secs_f64() * config.rate;
+        self.tokens = (self.tokens + refill).min(config.burst);
+        self.refilled = now;
+        if self.tokens < 1.0 {
+            return false;
+        }
+        self.tokens -= 1.0;
+        true
+    }
+}
+
+/// The per link state behind the Defcon levels.
+pub struct Mitigations {
+    config: DefconConfig,
+    links: HashMap<LinkId, Throttle>,
+    replays: ReplayCache,
+    stats: DefconStats,
+}
+
+impl Mitigations {
+    pub fn new(config: DefconConfig) -> Self {
+        let replays = ReplayCache::new(config.replay_window, config.replay_capacity);
+        Self { config, links: HashMap::new(), replays, stats: DefconStats::default() }
+    }
+
+    /// Whether `traffic` from `link` is refused outright. A quarantine
+    /// that ran out is lifted.
+    pub fn quarantined(&mut self, link: &LinkId, traffic: Traffic) -> bool {
+        let throttle = match self.links.get_mut(link) {
+            Some(throttle) => throttle,
+            None => return false,
+        };
+        let quarantined_until = throttle.quarantined_until(traffic);
+        match *quarantined_until {
+            Some(until) if Instant::now() < until => {
+                self.stats.quarantined += 1;
+                true
+            },
+            Some(_) => {
+                debug!("lifting quarantine of {:?} from {:?}", traffic, link);
+                *quarantined_until = None;
+                false
+            },
+            None => false,
+        }
+    }
+
+    /// Whether the Request for `hbfi` with `nonce` was seen already.
+    pub fn replayed(&mut self, hbfi: &HBFI, nonce: u64) -> bool {
+        if self.replays.seen(hbfi, nonce) {
+            self.stats.replays += 1;
+            return true;
+        }
+        false
+    }
+
+    /// Whether a Request from `link` may be forwarded, given the weight the
+    /// Router gave the deep six link for it and whether it was signed.
+    pub fn admit(&mut self, link: &LinkId, deep_six_weight: f64, signed: bool) -> bool {
+        let config = self.config;
+        let level = config.level(deep_six_weight);
+        self.stats.level = level;
+        self.stats.requests += 1;
+        let throttle = self.links.entry(link.clone()).or_insert_with(|| Throttle::new(&config));
+        let handled_at = match (level, signed) {
+            (Defcon::One, true) => Defcon::Two,
+            (Defcon::Two, true) => Defcon::Three,
+            _ => level,
+        };
+        let admitted = match handled_at {
+            Defcon::Four => true,
+            Defcon::Three | Defcon::Two if !throttle.take(&config) => {
+                self.stats.rate_limited += 1;
+                false
+            },
+            Defcon::Three => true,
+            Defcon::Two if rand::thread_rng().gen_bool(config.drop_probability) => {
+                self.stats.dropped += 1;
+                false
+            },
+            Defcon::Two => true,
+            Defcon::One => {
+                self.stats.deep_sixed += 1;
+                false
+            },
+        };
+        if admitted {
+            throttle.strikes = 0;
+            return true;
+        }
+        debug!("Defcon {:?}: refused a request from {:?}", level, link);
+        throttle.strikes += 1;
+        if throttle.strikes >= config.strikes {
+            warn!("Defcon {:?}: quarantining requests from {:?} for {:?}", level, link, config.quarantine);
+            throttle.quarantine(&config, Traffic::Requests);
+            self.stats.quarantines += 1;
+        }
+        false
+    }
+
+    /// Notes a Response from `link` that answered a Request forwarded to it.
+    pub fn solicited(&mut self, link: &LinkId) {
+        if let Some(throttle) = self.links.get_mut(link) {
+            throttle.unsolicited = 0;
+        }
+    }
+
+    /// Notes a Response from `link` that nobody asked it for.
+    pub fn unsolicited(&mut self, link: &LinkId) {
+        let config = self.config;
+        self.stats.unsolicited += 1;
+        let throttle = self.links.entry(link.clone()).or_insert_with(|| Throttle::new(&config));
+        throttle.unsolicited += 1;
+        if throttle.unsolicited >= config.unsolicited {
+            warn!("quarantining responses from {:?} for {:?}, they match no request", link, config.quarantine);
+            throttle.quarantine(&config, Traffic::Responses);
+            self.stats.quarantines += 1;
+        }
+    }
+
+    /// Whether a Response from `link` may train the Router, counting it
+    /// against the link's budget if so.
+    pub fn trainable(&mut self, link: &LinkId) -> bool {
+        let config = self.config;
+        let throttle = self.links.entry(link.clone()).or_insert_with(|| Throttle::new(&config));
+        if throttle.window.elapsed() >= config.training_window {
+            throttle.window = Instant::now();
+            throttle.trained = 0;
+        }
+        if throttle.trained >= config.training_limit {
+            self.stats.training_limited += 1;
+            return false;
+        }
+        throttle.trained += 1;
+        true
+    }
+
+    pub fn remove_link(&mut self, link: &LinkId) {
+        self.links.remove(link);
+    }
+
+    pub fn stats(&self) -> DefconStats {
+        let now = Instant::now();
+        let quarantined_links = self.links.values()
+            .filter(|throttle| throttle.in_quarantine(now))
+            .count();
+        DefconStats { quarantined_links, ..self.stats }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use copernica_common::ReplyTo;
+
+    #[test]
+    fn levels_follow_the_thresholds() {
+        let config = DefconConfig::new();
+        assert_eq!(config.level(0.0), Defcon::Four);
+        assert_eq!(config.level(0.36), Defcon::Three);
+        assert_eq!(config.level(0.6), Defcon::Two);
+        assert_eq!(config.level(0.95), Defcon::One);
+        let config = config.thresholds(0.1, 0.2, 0.3);
+        assert_eq!(config.level(0.25), Defcon::Two);
+    }
+
+    #[test]
+    fn rate_limits_then_quarantines() {
+        let config = DefconConfig::new()
+            .rate_limit(0.0, 3.0)
+            .strikes(2)
+            .quarantine(Duration::from_millis(50));
+        let mut mitigations = Mitigations::new(config);
+        let flood = LinkId::listen(ReplyTo::Rf(0));
+        let other = LinkId::listen(ReplyTo::Rf(1));
+        // nothing is limited while the network is calm
+        for _ in 0..10 {
+            assert!(mitigations.admit(&flood, 0.0, false));
+        }
+        let admitted = (0..5).filter(|_| mitigations.admit(&flood, 0.4, false)).count();
+        assert_eq!(admitted, 3);
+        assert!(mitigations.quarantined(&flood, Traffic::Requests));
+        assert!(!mitigations.quarantined(&flood, Traffic::Responses));
+        assert!(!mitigations.quarantined(&other, Traffic::Requests));
+        assert!(mitigations.admit(&other, 0.4, false));
+        let stats = mitigations.stats();
+        assert_eq!(stats.level, Defcon::Three);
+        assert_eq!(stats.requests, 16);
+        assert_eq!(stats.rate_limited, 2);
+        assert_eq!(stats.quarantines, 1);
+        assert_eq!(stats.quarantined, 1);
+        assert_eq!(stats.quarantined_links, 1);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!mitigations.quarantined(&flood, Traffic::Requests));
+        assert_eq!(mitigations.stats().quarantined_links, 0);
+    }
+
+    #[test]
+    fn drops_and_deep_sixes() {
+        let link = LinkId::listen(ReplyTo::Rf(0));
+        let mut mitigations = Mitigations::new(DefconConfig::new().drop_probability(1.0));
+        assert!(!mitigations.admit(&link, 0.7, false));
+        assert!(!mitigations.admit(&link, 0.9, false));
+        let stats = mitigations.stats();
+        assert_eq!((stats.dropped, stats.deep_sixed), (1, 1));
+        let mut mitigations = Mitigations::new(DefconConfig::new().drop_probability(0.0));
+        assert!(mitigations.admit(&link, 0.7, false));
+        let stats = mitigations.stats();
+        assert_eq!((stats.dropped, stats.deep_sixed), (0, 0));
+    }
+
+    #[test]
+    fn caps_training() {
+        let config = DefconConfig::new().training_limit(2, Duration::from_millis(50));
+        let mut mitigations = Mitigations::new(config);
+        let link = LinkId::listen(ReplyTo::Rf(0));
+        let trained = (0..5).filter(|_| mitigations.trainable(&link)).count();
+        assert_eq!(trained, 2);
+        assert_eq!(mitigations.stats().training_limited, 3);
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(mitigations.trainable(&link));
+    }
+
+    #[test]
+    fn quarantines_unsolicited_responses() {
+        let mut mitigations = Mitigations::new(DefconConfig::new().unsolicited(3));
+        let link = LinkId::listen(ReplyTo::Rf(0));
+        mitigations.unsolicited(&link);
+        mitigations.unsolicited(&link);
+        mitigations.solicited(&link);
+        mitigations.unsolicited(&link);
+        mitigations.unsolicited(&link);
+        assert!(!mitigations.quarantined(&link, Traffic::Responses));
+        mitigations.unsolicited(&link);
+        assert!(mitigations.quarantined(&link, Traffic::Responses));
+        // its Requests are still handled
+        assert!(!mitigations.quarantined(&link, Traffic::Requests));
+        assert!(mitigations.admit(&link, 0.0, false));
+        let stats = mitigations.stats();
+        assert_eq!((stats.unsolicited, stats.quarantines, stats.quarantined), (5, 1, 1));
+    }
+
+    #[test]
+    fn signed_requests_fare_one_level_better() {
+        let link = LinkId::listen(ReplyTo::Rf(0));
+        let mut mitigations = Mitigations::new(DefconConfig::new().drop_probability(1.0));
+        assert!(!mitigations.admit(&link, 0.95, false));
+        assert!(!mitigations.admit(&link, 0.95, true));
+        assert!(mitigations.admit(&link, 0.7, true));
+        let stats = mitigations.stats();
+        assert_eq!((stats.deep_sixed, stats.dropped), (1, 1));
+        assert_eq!(stats.level, Defcon::Two);
+    }
+
+    #[test]
+    fn counts_replays() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let mut mitigations = Mitigations::new(DefconConfig::new());
+        assert!(!mitigations.replayed(&hbfi, 7));
+        assert!(mitigations.replayed(&hbfi, 7));
+        assert_eq!(mitigations.stats().replays, 1);
+    }
+}
@@ -3,11 +3,17 @@
 extern crate borsh;
 
 mod bloom_filter;
+mod content_store;
+mod defcon;
+mod replay_cache;
 mod broker;
 pub mod bayes;
 mod router;
 pub use crate::{
-    broker::Broker,
+    broker::{Broker, BAYES_STATE},
+    bloom_filter::{BloomConfig, BloomStats, FilterStats},
+    content_store::{ContentStore, ContentStoreStats, Eviction},
+    defcon::{Defcon, DefconConfig, DefconStats, Mitigations, Traffic},
     router::Router,
-    bayes::{Bayes, LinkWeight},
+    bayes::{Bayes, BayesSnapshot, LinkSnapshot, LinkWeight},
 };
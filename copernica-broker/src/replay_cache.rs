@@ -0,0 +1,79 @@
+use {
+    copernica_common::HBFI,
+    std::{
+        collections::{HashSet, VecDeque},
+        time::{Duration, Instant},
+    },
+};
+
+/// How long a Request's nonce is remembered to spot replays of it.
+pub const REPLAY_WINDOW: Duration = Duration::from_secs(10);
+/// Nonces remembered at most, beyond which the oldest are forgotten early.
+pub const REPLAY_CAPACITY: usize = 1 << 16;
+
+/// The nonces of recent Requests, across every link, so that a Request
+/// seen again, whether replayed or looped back through the mesh, is
+/// handled once. A nonce is remembered for between `window` and twice
+/// `window`, less if more than `capacity` arrive meanwhile.
+pub struct ReplayCache {
+    window: Duration,
+    capacity: usize,
+    generations: VecDeque<HashSet<(HBFI, u64)>>,
+    rotated: Instant,
+}
+
+impl ReplayCache {
+    pub fn new(window: Duration, capacity: usize) -> Self {
+        let generations = (0..2).map(|_| HashSet::new()).collect();
+        Self { window, capacity: capacity.max(2), generations, rotated: Instant::now() }
+    }
+
+    /// Remembers the Request and says whether it had been seen already.
+    pub fn seen(&mut self, hbfi: &HBFI, nonce: u64) -> bool {
+        let key = (hbfi.clone(), nonce);
+        if self.generations.iter().any(|generation| generation.contains(&key)) {
+            return true;
+        }
+        let full = self.generations.back().is_some_and(|newest| newest.len() >= self.capacity / 2);
+        if full || self.rotated.elapsed() >= self.window {
+            self.generations.pop_front();
+            self.generations.push_back(HashSet::new());
+            self.rotated = Instant::now();
+        }
+        if let Some(newest) = self.generations.back_mut() {
+            newest.insert(key);
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replays_are_seen_within_the_window() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let mut cache = ReplayCache::new(Duration::from_millis(50), 100);
+        assert!(!cache.seen(&hbfi, 1));
+        assert!(cache.seen(&hbfi, 1));
+        assert!(!cache.seen(&hbfi, 2));
+        assert!(!cache.seen(&hbfi.clone().offset(1), 1));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!cache.seen(&hbfi, 3));
+        assert!(cache.seen(&hbfi, 1));
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(!cache.seen(&hbfi, 4));
+        assert!(!cache.seen(&hbfi, 1));
+    }
+
+    #[test]
+    fn memory_is_bounded() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let mut cache = ReplayCache::new(Duration::from_secs(60), 100);
+        for nonce in 0..1000 {
+            cache.seen(&hbfi, nonce);
+        }
+        assert!(cache.generations.iter().map(HashSet::len).sum::<usize>() <= 100);
+    }
+}
@@ -1,10 +1,11 @@
 use {
     crate::{
-        borsh::{BorshDeserialize, BorshSerialize},
         bloom_filter::{Blooms},
+        content_store::{ContentStore},
+        defcon::{Mitigations, Traffic},
         Bayes, LinkWeight
     },
-    copernica_common::{Nonce, LinkId, InterLinkPacket, LinkPacket, NarrowWaistPacket},
+    copernica_common::{Nonce, LinkId, InterLinkPacket, LinkPacket, NarrowWaistPacket, ResponseStore},
     anyhow::Result,
     //log::{trace},
     crossbeam_channel::Sender,
@@ -16,12 +17,13 @@ use {
 pub struct Router {}
 
 impl Router {
-    pub fn handle_packet(
+    pub fn handle_packet<S: ResponseStore>(
         ilp: &InterLinkPacket,
         r2c_tx: Sender<InterLinkPacket>,
-        response_store: sled::Db,
+        content_store: &ContentStore<S>,
         blooms: &mut HashMap<LinkId, Blooms>,
         bayes: &mut Bayes,
+        mitigations: &mut Mitigations,
         deep_six: &LinkId,
     ) -> Result<()> {
         let this_link: LinkId = ilp.link_id();
@@ -29,10 +31,21 @@ impl Router {
         let nw: NarrowWaistPacket = ilp.narrow_waist();
         if let Some(this_bloom) = blooms.get_mut(&this_link) {
             match nw.clone() {
-                NarrowWaistPacket::Request { hbfi } => {
-                    match response_store.get(&hbfi.try_to_vec()?)? {
-                        Some(response) => {
-                            let nw = NarrowWaistPacket::try_from_slice(&response)?;
+                NarrowWaistPacket::Request { hbfi, nonce, requester, .. } => {
+                    if !nw.verify() {
+                        warn!("dropping unverified request {:?}", nw);
+                        return Ok(());
+                    }
+                    if mitigations.quarantined(&this_link, Traffic::Requests) {
+                        debug!("dropping request from quarantined link {:?}", this_link);
+                        return Ok(());
+                    }
+                    if mitigations.replayed(&hbfi, nonce) {
+                        debug!("dropping replayed request {:?}", nw);
+                        return Ok(());
+                    }
+                    match content_store.get(&hbfi)? {
+                        Some(nw) => {
                             debug!("********* RESPONSE PACKET FOUND *********");
                             let wp = LinkPacket::new(this_link.reply_to(), nw);
                             let ilp = InterLinkPacket::new(this_link.clone(), wp);
@@ -41,32 +54,23 @@ impl Router {
                         }
                         None => {
                             debug!("********* NO   RESPONSE   FOUND *********");
-                            this_bloom.create_pending_request(&hbfi);
+                            let forward = match nw.next_hop() {
+                                Some(nw) => ilp.change_narrow_waist(nw),
+                                None => {
+                                    debug!("dropping request out of hops {:?}", nw);
+                                    return Ok(());
+                                },
+                            };
                             let link_weights = bayes.classify(&hbfi.to_vec());
-                            //std::thread::sleep_ms(500);
                             bayes.train(&hbfi.to_vec(), deep_six);
-                            if link_weights[0].linkid == *deep_six {
-                                warn!("{}, {:?}", link_weights[0].weight, link_weights[0].linkid);
-                                let litmus_weight = (link_weights[0].weight * 100.00) as u64;
-                                match litmus_weight {
-                                    0..=35 => {
-                                        warn!("Defcon 4: Do something")
-                                    },
-                                    36..=59 => {
-                                        warn!("Defcon 3: Do something")
-                                        // packets need a nonce and it needs to be signed. So as to avert the
-                                        // scenario whereby an attacker replays requests thus shutting down
-                                        // the flow of legitimate information.
-                                    },
-                                    60..=89 => {
-                                        warn!("Defcon 2: Do something")
-                                    },
-                                    90..=u64::MAX => {
-                                        warn!("Defcon 1: Deep Sixed packet: {:?}", hbfi);
-                                        return Ok(())
-                                    },
-                                }
+                            let deep_six_weight = match link_weights.first() {
+                                Some(LinkWeight { linkid, weight }) if linkid == deep_six => *weight,
+                                _ => 0.0,
+                            };
+                            if !mitigations.admit(&this_link, deep_six_weight, requester.is_some()) {
+                                return Ok(());
                             }
+                            this_bloom.create_pending_request(&hbfi);
                             let mut forwarded = false;
                             for LinkWeight { linkid: that_link, weight} in link_weights {
                                 warn!("{}, {:?}", weight, that_link);
@@ -85,11 +89,11 @@ impl Router {
                                     }
                                     if (weight < 0.00) && (forwarded == false) {
                                         that_bloom.create_forwarded_request(&hbfi);
-                                        r2c_tx.send(ilp.change_destination(that_link))?;
+                                        r2c_tx.send(forward.change_destination(that_link))?;
                                         continue;
                                     }
                                     that_bloom.create_forwarded_request(&hbfi);
-                                    r2c_tx.send(ilp.change_destination(that_link))?;
+                                    r2c_tx.send(forward.change_destination(that_link))?;
                                     forwarded = true;
                                 }
                             }
@@ -97,22 +101,46 @@ impl Router {
                     }
                 }
                 NarrowWaistPacket::Response { hbfi, .. } => {
-                    if this_bloom.contains_forwarded_request(&hbfi) {
-                        response_store.insert(hbfi.try_to_vec()?, nw.clone().try_to_vec()?)?;
-                        bayes.super_train(&hbfi.to_vec(), &this_link);
-                        // ^^^ think about an attack whereby a response is continually sent thus adjusting the weights
-                        this_bloom.delete_forwarded_request(&hbfi);
-                        for (that_link, that_bloom) in blooms.iter_mut() {
-                            if that_link.nonce() == this_link_id {
-                                continue;
-                            }
-                            if that_bloom.contains_pending_request(&hbfi) {
-                                that_bloom.delete_pending_request(&hbfi);
-                                debug!("********* RESPONSE DOWNSTREAM *********");
-                                r2c_tx.send(ilp.change_destination(that_link.clone()))?;
-                            }
+                    if !nw.verify() {
+                        warn!("dropping unverified response {:?}", nw);
+                        return Ok(());
+                    }
+                    if mitigations.quarantined(&this_link, Traffic::Responses) {
+                        debug!("dropping response from quarantined link {:?}", this_link);
+                        return Ok(());
+                    }
+                    if !this_bloom.contains_forwarded_request(&hbfi) {
+                        // a slow link may answer after the Request was
+                        // forgotten, the Response is kept for the retry
+                        if this_bloom.contains_expired_request(&hbfi) {
+                            debug!("caching late response {:?}", hbfi);
+                            this_bloom.delete_expired_request(&hbfi);
+                            content_store.insert(&hbfi, &nw)?;
+                            return Ok(());
+                        }
+                        mitigations.unsolicited(&this_link);
+                        return Ok(());
+                    }
+                    mitigations.solicited(&this_link);
+                    content_store.insert(&hbfi, &nw)?;
+                    this_bloom.delete_forwarded_request(&hbfi);
+                    let mut satisfied = false;
+                    for (that_link, that_bloom) in blooms.iter_mut() {
+                        if that_link.nonce() == this_link_id {
+                            continue;
+                        }
+                        if that_bloom.contains_pending_request(&hbfi) {
+                            that_bloom.delete_pending_request(&hbfi);
+                            debug!("********* RESPONSE DOWNSTREAM *********");
+                            r2c_tx.send(ilp.change_destination(that_link.clone()))?;
+                            satisfied = true;
                         }
                     }
+                    // a Response replayed after its Requests were satisfied
+                    // mustn't skew the model
+                    if satisfied && mitigations.trainable(&this_link) {
+                        bayes.super_train(&hbfi.to_vec(), &this_link);
+                    }
                 }
             }
         }
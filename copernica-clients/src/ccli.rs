@@ -1,8 +1,67 @@
+#[allow(dead_code)]
+#[path = "ccli/config.rs"]
+mod config;
+
 use {
-    anyhow::{Result},
+    config::Options,
+    copernica_common::{setup_logging, Keystore, PublicIdentity},
+    copernica_clients::{passphrase, new_passphrase},
+    structopt::StructOpt,
+    anyhow::{anyhow, Result},
+    std::path::PathBuf,
 };
 
 fn main() -> Result<()> {
+    let options = Options::from_args();
+    setup_logging(options.verbose as u64, None).expect("failed to initialize logging.");
+    let dir = match &options.keystore {
+        Some(dir) => PathBuf::from(dir),
+        None => Keystore::default_dir(),
+    };
+    let keystore = Keystore::open(&dir)?;
+
+    if options.generate_id {
+        let id = keystore.generate(&new_passphrase()?)?;
+        if keystore.default_id()?.is_none() {
+            keystore.set_default(&id)?;
+        }
+        println!("{}", id);
+    }
+
+    if let Some(id) = &options.decrypt_id {
+        let id: PublicIdentity = id.parse()?;
+        keystore.unlock(&id, &passphrase("Passphrase: ")?)?;
+        println!("{} unlocked", id);
+    }
+
+    if options.list_ids {
+        let default = keystore.default_id()?;
+        for id in keystore.list()? {
+            let marker = if Some(id) == default { "*" } else { " " };
+            println!("{} {}", marker, id);
+            for trusted in keystore.trusted(&id)? {
+                println!("    trusts {}", trusted);
+            }
+        }
+    }
+
+    if let Some(id) = &options.use_id {
+        let id: PublicIdentity = id.parse()?;
+        keystore.unlock(&id, &passphrase("Passphrase: ")?)?;
+        keystore.set_default(&id)?;
+    }
+
+    if let Some(ids) = &options.trust_id {
+        match ids.split_first() {
+            Some((ours, theirs)) if !theirs.is_empty() => {
+                let ours: PublicIdentity = ours.parse()?;
+                for theirs in theirs {
+                    keystore.trust(&ours, &theirs.parse()?)?;
+                }
+            },
+            _ => return Err(anyhow!("--trust-id expects <your-id> <their-id> [<another-id> ...]")),
+        }
+    }
+
     Ok(())
 }
-
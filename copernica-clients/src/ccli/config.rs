@@ -8,14 +8,14 @@ pub struct Options {
     #[structopt(short = "g", long = "generate-id", help = "Generate a new Ed25519 identity keypair")]
     pub generate_id: bool,
 
-    #[structopt(short = "d", long = "decrypt-id", help = "Generate a new Ed25519 identity keypair")]
+    #[structopt(short = "d", long = "decrypt-id", help = "Check the passphrase of this identity by decrypting its private key")]
     pub decrypt_id: Option<String>,
 
     #[structopt(short = "l", long = "list-ids", help = "List identities")]
     pub list_ids: bool,
 
-    #[structopt(short = "u", long = "use-id", help = "Load up the private key associated with this identity")]
-    pub use_id: bool,
+    #[structopt(short = "u", long = "use-id", help = "Load up the private key associated with this identity and make it the default")]
+    pub use_id: Option<String>,
 
     #[structopt(short = "t", long = "trust-id", help = "Trust someone else's identity -t <your-id> <their-id> <another-id> <etc>")]
     pub trust_id: Option<Vec<String>>,
@@ -23,6 +23,9 @@ pub struct Options {
     #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
     pub verbose: u8,
 
+    #[structopt(short = "k", long = "keystore", help = "Location of your keystore, defaults to ~/.copernica/keystore")]
+    pub keystore: Option<String>,
+
     #[structopt(short = "c", long = "config", help = "Location of your config file")]
     pub config: Option<String>,
 
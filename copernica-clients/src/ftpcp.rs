@@ -1,3 +1,147 @@
-fn main() {
-    println!("ftp");
+#[path = "ftpcp/config.rs"]
+mod config;
+
+use {
+    config::{Options, Command},
+    copernica_common::{setup_logging, Keystore, PrivateIdentity, PublicIdentity, HBFI},
+    copernica_clients::{passphrase, Config, Node, ServiceConfig},
+    copernica_services::{FilePacker, Progress, Service, FTP},
+    structopt::StructOpt,
+    anyhow::{anyhow, Result},
+    std::{env, fs, path::{Component, Path, PathBuf}, process},
+};
+
+fn publish(keystore: &Keystore, dir: &Path, name: String, id: Option<String>, store: &Path) -> Result<()> {
+    let id: PublicIdentity = match id {
+        Some(id) => id.parse()?,
+        None => keystore.default_id()?.ok_or_else(|| anyhow!("No --id given and the keystore has no default identity"))?,
+    };
+    let identity = keystore.unlock(&id, &passphrase(&format!("Passphrase for {}: ", id))?)?;
+    let packer = FilePacker::new(dir, store, name.clone(), identity)?
+        .progress(Box::new(|p: &Progress| eprintln!("{} {}/{} bytes ({}/{})", p.name, p.bytes, p.size, p.file, p.files)));
+    packer.publish()?;
+    println!("{} {}", name, id);
+    Ok(())
+}
+
+/// A node for one command, built from its own config rather than a running
+/// daemon's, whose store and listen addresses it would collide with.
+struct Client {
+    node: Node,
+    // the throwaway ftp store made when the config doesn't host one
+    temp_store: Option<PathBuf>,
+}
+
+impl Client {
+    /// Hosts an ftp service on a throwaway store if `config` doesn't
+    /// already host one.
+    fn new(config: &Path) -> Result<Self> {
+        let mut config = Config::from_file(config)
+            .map_err(|e| anyhow!("Cannot read the client config {:?}: {}", config, e))?;
+        let hosts_ftp = config.services.iter().any(|s| matches!(s, ServiceConfig::Ftp { .. }));
+        let mut temp_store = None;
+        if !hosts_ftp {
+            let data_dir = env::temp_dir().join("copernica").join(format!("ftpcp-{}", process::id()));
+            config.services.push(ServiceConfig::Ftp { data_dir: data_dir.clone() });
+            temp_store = Some(data_dir);
+        }
+        let node = Node::new(config).and_then(|mut node| {
+            node.run()?;
+            Ok(node)
+        });
+        match node {
+            Ok(node) => Ok(Self { node, temp_store }),
+            Err(e) => {
+                if let Some(data_dir) = temp_store {
+                    let _ = fs::remove_dir_all(data_dir);
+                }
+                Err(e)
+            },
+        }
+    }
+
+    fn ftp(&mut self) -> &mut FTP {
+        &mut self.node.ftps()[0]
+    }
+
+    /// Shuts the node down and deletes the throwaway store.
+    fn shutdown(self) -> Result<()> {
+        let result = self.node.shutdown();
+        if let Some(data_dir) = self.temp_store {
+            fs::remove_dir_all(&data_dir)
+                .map_err(|e| anyhow!("Cannot remove the temporary store {:?}: {}", data_dir, e))?;
+        }
+        result
+    }
+}
+
+fn get(ftp: &mut FTP, hbfi: HBFI, to: &Path, file: Option<String>, signer: Option<PrivateIdentity>) -> Result<()> {
+    if let Some(signer) = signer {
+        let config = ftp.get_fetch_config().identity(signer);
+        ftp.set_fetch_config(config);
+    }
+    match file {
+        Some(file) => {
+            if !Path::new(&file).components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(anyhow!("Refusing to write {:?} outside of {:?}", file, to));
+            }
+            let path = to.join(&file);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let bytes = ftp.download_to(hbfi, file.clone(), &path)?;
+            eprintln!("{} {} bytes", file, bytes);
+        },
+        None => {
+            fs::create_dir_all(to)?;
+            ftp.download_all(hbfi, to, Box::new(|p: &Progress| eprintln!("{} {}/{} bytes ({}/{})", p.name, p.bytes, p.size, p.file, p.files)))?;
+        },
+    }
+    Ok(())
+}
+
+fn ls(ftp: &mut FTP, hbfi: HBFI) -> Result<()> {
+    let mut names = ftp.file_names(hbfi)?;
+    names.sort();
+    for name in names {
+        println!("{}", name);
+    }
+    Ok(())
+}
+
+fn hbfi(name: &str, id: &str) -> Result<HBFI> {
+    let id: PublicIdentity = id.parse()?;
+    HBFI::new(name, &id.to_string())
+}
+
+fn main() -> Result<()> {
+    let options = Options::from_args();
+    setup_logging(options.verbose as u64, None).expect("failed to initialize logging.");
+    match options.command {
+        Command::Publish { dir, name, id, store } => {
+            let keystore = Keystore::open(&options.keystore.unwrap_or_else(Keystore::default_dir))?;
+            publish(&keystore, &dir, name, id, &store)?;
+        },
+        Command::Get { name, id, to, file, sign_as } => {
+            let hbfi = hbfi(&name, &id)?;
+            let signer = match sign_as {
+                Some(sign_as) => {
+                    let keystore = Keystore::open(&options.keystore.unwrap_or_else(Keystore::default_dir))?;
+                    let sign_as: PublicIdentity = sign_as.parse()?;
+                    Some(keystore.unlock(&sign_as, &passphrase(&format!("Passphrase for {}: ", sign_as))?)?)
+                },
+                None => None,
+            };
+            let mut client = Client::new(&options.config)?;
+            let result = get(client.ftp(), hbfi, &to, file, signer);
+            result.and(client.shutdown())?;
+        },
+        Command::Ls { name, id } => {
+            let hbfi = hbfi(&name, &id)?;
+            let mut client = Client::new(&options.config)?;
+            let result = ls(client.ftp(), hbfi);
+            result.and(client.shutdown())?;
+        },
+    }
+    Ok(())
 }
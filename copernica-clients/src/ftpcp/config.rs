@@ -0,0 +1,65 @@
+use {
+    structopt::StructOpt,
+    std::path::PathBuf,
+};
+
+#[derive(StructOpt, Debug)]
+#[structopt(name = "ftpcp", about = "Publish and fetch files over Copernica", author = "Stewart Mackenzie <sjm@fractalide.com>", version = "0.1.0")]
+pub struct Options {
+    #[structopt(short = "v", long = "verbose", parse(from_occurrences))]
+    pub verbose: u8,
+
+    #[structopt(short = "c", long = "config", default_value = "ftpcp.json", help = "Node config describing the links used to reach the network, its store and listen addresses must not be a running node's")]
+    pub config: PathBuf,
+
+    #[structopt(short = "k", long = "keystore", help = "Location of your keystore, defaults to ~/.copernica/keystore")]
+    pub keystore: Option<PathBuf>,
+
+    #[structopt(subcommand)]
+    pub command: Command,
+}
+
+#[derive(StructOpt, Debug)]
+pub enum Command {
+    #[structopt(about = "Pack a directory into a store, signed by one of your identities")]
+    Publish {
+        #[structopt(parse(from_os_str), help = "Directory to publish")]
+        dir: PathBuf,
+
+        #[structopt(short = "n", long = "name", help = "Name of the publication")]
+        name: String,
+
+        #[structopt(short = "i", long = "id", help = "Your identity to publish under, defaults to the keystore default")]
+        id: Option<String>,
+
+        #[structopt(short = "s", long = "store", parse(from_os_str), help = "Store to publish into, serve it with an ftp service")]
+        store: PathBuf,
+    },
+
+    #[structopt(about = "Fetch every file of a publication, or just one with --file")]
+    Get {
+        #[structopt(help = "Name of the publication")]
+        name: String,
+
+        #[structopt(help = "Identity of the publisher")]
+        id: String,
+
+        #[structopt(short = "t", long = "to", parse(from_os_str), help = "Directory to write the files to")]
+        to: PathBuf,
+
+        #[structopt(short = "f", long = "file", help = "Only fetch this file")]
+        file: Option<String>,
+
+        #[structopt(short = "s", long = "sign-as", help = "Sign your requests with one of your identities, brokers under load favour signed requests")]
+        sign_as: Option<String>,
+    },
+
+    #[structopt(about = "List the files of a publication")]
+    Ls {
+        #[structopt(help = "Name of the publication")]
+        name: String,
+
+        #[structopt(help = "Identity of the publisher")]
+        id: String,
+    },
+}
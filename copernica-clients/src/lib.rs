@@ -0,0 +1,7 @@
+mod node;
+mod passphrase;
+
+pub use crate::{
+    node::{Config, ContentStoreConfig, EvictionConfig, FecConfig, LinkConfig, ServiceConfig, Node},
+    passphrase::{passphrase, new_passphrase, PASSPHRASE_VAR},
+};
@@ -0,0 +1,323 @@
+use {
+    copernica_broker::{Broker, Eviction},
+    copernica_common::{LinkId, ReplyTo, StopHandle},
+    copernica_links::{Fec, Link, MpscChannel, Tcp, UdpIp, ECC_LEN},
+    copernica_services::{Service, FTP, RelayNode},
+    serde::{Deserialize, Serialize},
+    anyhow::{anyhow, Result},
+    std::{
+        fs, io,
+        net::{SocketAddr, TcpListener, UdpSocket},
+        path::{Path, PathBuf},
+    },
+    log::{debug, error},
+};
+
+/// The node described by a `copernica.json`:
+///
+/// {
+///     "data_dir": "/home/user/.copernica/rs",
+///     "content_store": { "max_bytes": 104857600, "eviction": { "type": "lru" } },
+///     "links": [
+///         { "type": "udp_ip", "listen": "127.0.0.1:8089", "remote": "127.0.0.1:8090",
+///           "fec": { "type": "adaptive", "min": 4, "max": 32 }, "interleave": true },
+///         { "type": "tcp", "listen": "127.0.0.1:8091", "remote": "127.0.0.1:8092" }
+///     ],
+///     "services": [
+///         { "type": "ftp", "data_dir": "/home/user/.copernica/ftp" }
+///     ]
+/// }
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Config {
+    /// The broker's response store.
+    pub data_dir: PathBuf,
+    #[serde(default)]
+    pub content_store: ContentStoreConfig,
+    #[serde(default)]
+    pub links: Vec<LinkConfig>,
+    #[serde(default)]
+    pub services: Vec<ServiceConfig>,
+}
+
+/// Limits on the Responses the broker caches for other nodes. Unbounded
+/// unless a limit is given.
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+pub struct ContentStoreConfig {
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+    #[serde(default)]
+    pub max_entries: Option<usize>,
+    #[serde(default)]
+    pub eviction: EvictionConfig,
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum EvictionConfig {
+    #[default]
+    Lru,
+    Lfu,
+    PublisherQuota { bytes: u64 },
+}
+
+impl From<EvictionConfig> for Eviction {
+    fn from(config: EvictionConfig) -> Self {
+        match config {
+            EvictionConfig::Lru => Eviction::Lru,
+            EvictionConfig::Lfu => Eviction::Lfu,
+            EvictionConfig::PublisherQuota { bytes } => Eviction::PublisherQuota(bytes),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum LinkConfig {
+    UdpIp {
+        listen: SocketAddr,
+        remote: SocketAddr,
+        #[serde(default)]
+        fec: FecConfig,
+        /// Spread each frame's blocks byte by byte, for links prone to
+        /// burst errors.
+        #[serde(default)]
+        interleave: bool,
+    },
+    Tcp {
+        listen: SocketAddr,
+        remote: SocketAddr,
+        #[serde(default)]
+        fec: FecConfig,
+        #[serde(default)]
+        interleave: bool,
+    },
+}
+
+/// The Reed-Solomon parity a link sends with, in bytes per block.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum FecConfig {
+    Fixed { ecc_len: u8 },
+    Adaptive { min: u8, max: u8 },
+}
+
+impl Default for FecConfig {
+    fn default() -> Self {
+        FecConfig::Fixed { ecc_len: ECC_LEN }
+    }
+}
+
+impl From<FecConfig> for Fec {
+    fn from(config: FecConfig) -> Self {
+        match config {
+            FecConfig::Fixed { ecc_len } => Fec::Fixed(ecc_len),
+            FecConfig::Adaptive { min, max } => Fec::Adaptive { min, max },
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum ServiceConfig {
+    Ftp { data_dir: PathBuf },
+    RelayNode { data_dir: PathBuf },
+}
+
+impl Config {
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let config = fs::read_to_string(path)?;
+        Ok(serde_json::from_str(&config)?)
+    }
+}
+
+/// A `Broker` together with the links and services a `Config` describes.
+/// Each hosted service reaches the broker over its own pair of
+/// `MpscChannel`s. The links' addresses are bound when the node is made,
+/// and their threads, the broker's and the services' all end on `shutdown`.
+pub struct Node {
+    broker: Broker,
+    stores: Vec<sled::Db>,
+    links: Vec<Box<dyn Link<'static>>>,
+    ftps: Vec<FTP>,
+    relay_nodes: Vec<RelayNode>,
+    stop: StopHandle,
+}
+
+impl Node {
+    pub fn new(config: Config) -> Result<Self> {
+        let stop = StopHandle::new();
+        let rs = open_store(&config.data_dir)?;
+        let mut broker = Broker::new(rs.clone())
+            .eviction(config.content_store.eviction.into())
+            .stop_handle(stop.clone());
+        if let Some(max_bytes) = config.content_store.max_bytes {
+            broker = broker.max_content_bytes(max_bytes);
+        }
+        if let Some(max_entries) = config.content_store.max_entries {
+            broker = broker.max_content_entries(max_entries);
+        }
+        let mut stores = vec![rs];
+        let mut links: Vec<Box<dyn Link<'static>>> = vec![];
+        for link in config.links {
+            // bound here, where a taken address can still be returned, and
+            // handed to the link, which would otherwise bind once it runs
+            match link {
+                LinkConfig::UdpIp { listen, remote, fec, interleave } => {
+                    let socket = UdpSocket::bind(listen).map_err(|e| listen_error(listen, e))?;
+                    let lid = LinkId::listen(ReplyTo::UdpIp(listen));
+                    let udpip: UdpIp = Link::new(lid.clone(), broker.peer(lid.remote(ReplyTo::UdpIp(remote)))?)?;
+                    links.push(Box::new(udpip.fec(fec.into()).interleave(interleave).socket(socket).stop_handle(stop.clone())));
+                },
+                LinkConfig::Tcp { listen, remote, fec, interleave } => {
+                    let listener = TcpListener::bind(listen).map_err(|e| listen_error(listen, e))?;
+                    let lid = LinkId::listen(ReplyTo::Tcp(listen));
+                    let tcp: Tcp = Link::new(lid.clone(), broker.peer(lid.remote(ReplyTo::Tcp(remote)))?)?;
+                    links.push(Box::new(tcp.fec(fec.into()).interleave(interleave).listener(listener).stop_handle(stop.clone())));
+                },
+            }
+        }
+        let mut ftps = vec![];
+        let mut relay_nodes = vec![];
+        for service in config.services {
+            let drop_hook = Box::new(move || {});
+            match service {
+                ServiceConfig::Ftp { data_dir } => {
+                    let rs = open_store(&data_dir)?;
+                    stores.push(rs.clone());
+                    let mut ftp: FTP = Service::new(rs, drop_hook);
+                    links.append(&mut attach(&mut broker, &mut ftp, &format!("ftp:{}", data_dir.display()), &stop)?);
+                    ftps.push(ftp);
+                },
+                ServiceConfig::RelayNode { data_dir } => {
+                    let rs = open_store(&data_dir)?;
+                    stores.push(rs.clone());
+                    let mut relay_node: RelayNode = Service::new(rs, drop_hook);
+                    links.append(&mut attach(&mut broker, &mut relay_node, &format!("relay_node:{}", data_dir.display()), &stop)?);
+                    relay_nodes.push(relay_node);
+                },
+            }
+        }
+        Ok(Self { broker, stores, links, ftps, relay_nodes, stop })
+    }
+
+    pub fn run(&mut self) -> Result<()> {
+        for link in &self.links {
+            link.run()?;
+        }
+        self.broker.run()?;
+        for ftp in &mut self.ftps {
+            ftp.run()?;
+        }
+        for relay_node in &mut self.relay_nodes {
+            relay_node.run()?;
+        }
+        debug!("node running with {} links", self.links.len());
+        Ok(())
+    }
+
+    /// The hosted `FTP` services, in the order they were configured.
+    pub fn ftps(&mut self) -> &mut Vec<FTP> {
+        &mut self.ftps
+    }
+
+    /// Cancels outstanding fetches, stops the broker, link and service
+    /// threads and waits for them, then flushes every store to disk,
+    /// returning the first flush that failed once all have been tried.
+    pub fn shutdown(mut self) -> Result<()> {
+        for ftp in &mut self.ftps {
+            ftp.get_cancel_handle().cancel();
+        }
+        for relay_node in &mut self.relay_nodes {
+            relay_node.get_cancel_handle().cancel();
+        }
+        self.stop.stop();
+        let mut result = Ok(());
+        for rs in &self.stores {
+            if let Err(e) = rs.flush() {
+                error!("failed to flush store: {}", e);
+                if result.is_ok() {
+                    result = Err(anyhow!("Failed to flush store: {}", e));
+                }
+            }
+        }
+        result
+    }
+}
+
+// sled locks a store while it is open, so a store that won't open is most
+// often one a running node holds.
+fn open_store(data_dir: &Path) -> Result<sled::Db> {
+    sled::open(data_dir).map_err(|e| anyhow!("Cannot open the store at {:?}, is another node using it? {}", data_dir, e))
+}
+
+fn listen_error(listen: SocketAddr, e: io::Error) -> anyhow::Error {
+    anyhow!("Cannot listen on {}, is another node using it? {}", listen, e)
+}
+
+// `name` identifies the service to the broker's routing model across restarts.
+fn attach<'a, S: Service<'a>>(broker: &mut Broker, service: &mut S, name: &str, stop: &StopHandle) -> Result<Vec<Box<dyn Link<'static>>>> {
+    let lid_b = LinkId::listen(ReplyTo::Mpsc);
+    let lid_s = LinkId::listen(ReplyTo::Mpsc);
+    service.set_stop_handle(stop.clone());
+    let b: MpscChannel = Link::new(lid_b.clone(), broker.named_peer(lid_b, name)?)?;
+    let s: MpscChannel = Link::new(lid_s.clone(), service.peer(lid_s)?)?;
+    let (mut b, mut s) = (b.stop_handle(stop.clone()), s.stop_handle(stop.clone()));
+    b.female(s.male());
+    s.female(b.male());
+    Ok(vec![Box::new(b), Box::new(s)])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_config() {
+        let config: Config = serde_json::from_str(r#"{
+            "data_dir": "/tmp/rs",
+            "content_store": { "max_entries": 1000, "eviction": { "type": "publisher_quota", "bytes": 4096 } },
+            "links": [
+                { "type": "udp_ip", "listen": "127.0.0.1:8089", "remote": "127.0.0.1:8090" },
+                { "type": "udp_ip", "listen": "127.0.0.1:8091", "remote": "127.0.0.1:8092",
+                  "fec": { "type": "adaptive", "min": 4, "max": 32 }, "interleave": true },
+                { "type": "tcp", "listen": "127.0.0.1:8093", "remote": "127.0.0.1:8094" }
+            ],
+            "services": [
+                { "type": "ftp", "data_dir": "/tmp/ftp" },
+                { "type": "relay_node", "data_dir": "/tmp/relay" }
+            ]
+        }"#).unwrap();
+        assert_eq!(config, Config {
+            data_dir: "/tmp/rs".into(),
+            content_store: ContentStoreConfig {
+                max_bytes: None,
+                max_entries: Some(1000),
+                eviction: EvictionConfig::PublisherQuota { bytes: 4096 },
+            },
+            links: vec![
+                LinkConfig::UdpIp {
+                    listen: "127.0.0.1:8089".parse().unwrap(),
+                    remote: "127.0.0.1:8090".parse().unwrap(),
+                    fec: FecConfig::Fixed { ecc_len: 12 },
+                    interleave: false,
+                },
+                LinkConfig::UdpIp {
+                    listen: "127.0.0.1:8091".parse().unwrap(),
+                    remote: "127.0.0.1:8092".parse().unwrap(),
+                    fec: FecConfig::Adaptive { min: 4, max: 32 },
+                    interleave: true,
+                },
+                LinkConfig::Tcp {
+                    listen: "127.0.0.1:8093".parse().unwrap(),
+                    remote: "127.0.0.1:8094".parse().unwrap(),
+                    fec: FecConfig::default(),
+                    interleave: false,
+                },
+            ],
+            services: vec![
+                ServiceConfig::Ftp { data_dir: "/tmp/ftp".into() },
+                ServiceConfig::RelayNode { data_dir: "/tmp/relay".into() },
+            ],
+        });
+    }
+}
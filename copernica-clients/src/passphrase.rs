@@ -0,0 +1,27 @@
+use {
+    anyhow::{anyhow, Result},
+    std::env,
+};
+
+/// Read from the `COPERNICA_PASSPHRASE` environment variable when it is
+/// set, so scripts can unlock identities, otherwise from the terminal.
+pub const PASSPHRASE_VAR: &str = "COPERNICA_PASSPHRASE";
+
+pub fn passphrase(prompt: &str) -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    Ok(rpassword::read_password_from_tty(Some(prompt))?)
+}
+
+pub fn new_passphrase() -> Result<String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_VAR) {
+        return Ok(passphrase);
+    }
+    let first = passphrase("New passphrase: ")?;
+    let second = passphrase("Repeat passphrase: ")?;
+    if first != second {
+        return Err(anyhow!("The passphrases do not match"));
+    }
+    Ok(first)
+}
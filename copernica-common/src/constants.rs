@@ -11,5 +11,22 @@
 /// Was set at 1428 but I'm setting it to 1024 so that things don't blow up
 /// using datatye u16 because this information is communicated in the Response Manifest.
 pub const FRAGMENT_SIZE: u16 = 1024;
+/// Largest UDP payload that crosses an ethernet link without fragmenting.
+///
+/// Derived from ethernet_mtu - ipv4_header_size - udp_header_size
+///       1472 = 1500         - 20               - 8
+pub const DATAGRAM_SIZE: usize = 1472;
+/// Hashes in the Merkle proof a Response carries, however large the
+/// publication. Proofs stop this many levels above the chunks, at a node
+/// listed in the publication's segment roots.
+///
+/// A Response of FRAGMENT_SIZE bytes with an IPv6 reply address takes 1187
+/// bytes and each proof hash 32 more. Framed with the default Reed-Solomon
+/// parity, 12 bytes per 243, 1397 bytes of packet fit in DATAGRAM_SIZE.
+/// 1379 = 1187 + 6 * 32
+pub const MAX_PROOF_DEPTH: u8 = 6;
 pub const BLOOM_FILTER_LENGTH: u64 = u16::MAX as u64;
 pub const BLOOM_FILTER_INDEX_ELEMENT_LENGTH: u16 = 4;
+/// Brokers a Request may pass through before it is dropped, unless the
+/// requesting service sets its own limit.
+pub const HOP_LIMIT: u8 = 16;
@@ -1,5 +1,5 @@
 use {
-    crate::{constants},
+    crate::{constants, identity::PublicIdentity},
     anyhow::Result,
     borsh::{BorshDeserialize, BorshSerialize},
     sha3::{Digest, Sha3_512},
@@ -42,6 +42,13 @@ impl HBFI {
         self.os = os;
         self
     }
+    /// True when `id` was derived from `publisher`, i.e. `HBFI::new(h1, &publisher.to_string())`.
+    pub fn is_published_by(&self, publisher: &PublicIdentity) -> bool {
+        match bloom_filter_index(&publisher.to_string()) {
+            Ok(id) => id == self.id,
+            Err(_) => false,
+        }
+    }
 }
 
 impl fmt::Debug for HBFI {
@@ -96,6 +103,7 @@ mod tests {
     use crate::{
         packets::{Data, NarrowWaistPacket, LinkPacket},
         link::{ReplyTo},
+        identity::PrivateIdentity,
     };
 
     #[test]
@@ -114,11 +122,36 @@ mod tests {
         let hbfi = HBFI::new_test(h1, id, u64::MAX);
         let data = [0; constants::FRAGMENT_SIZE as usize];
         let data: Data = Data { len: constants::FRAGMENT_SIZE, data};
-        let nw: NarrowWaistPacket = NarrowWaistPacket::Response { hbfi, data, offset: u64::MAX, total: u64::MAX };
-        let reply_to: ReplyTo = ReplyTo::UdpIp("127.0.0.1:50000".parse().unwrap());
+        let identity = PrivateIdentity::generate();
+        // proofs end at the segment roots, so none is longer than this
+        let proof = vec![[u8::MAX; 32]; constants::MAX_PROOF_DEPTH as usize];
+        let nw: NarrowWaistPacket = NarrowWaistPacket::signed_response(&identity, hbfi, data, u64::MAX, u64::MAX, proof).unwrap();
+        let reply_to: ReplyTo = ReplyTo::UdpIp("[::1]:50000".parse().unwrap());
         let wp: LinkPacket = LinkPacket { reply_to, nw };
         let wp_ser = wp.try_to_vec().unwrap();
-        let lt1472 = if wp_ser.len() <= 1472 { true } else { false };
+        let lt1472 = if wp_ser.len() <= constants::DATAGRAM_SIZE { true } else { false };
         assert_eq!(true, lt1472);
     }
+
+    #[test]
+    fn responses_verify_against_their_publisher() {
+        let identity = PrivateIdentity::generate();
+        let hbfi = HBFI::new("app", &identity.public_id().to_string()).unwrap();
+        let data: Data = Data { len: 1, data: [7; constants::FRAGMENT_SIZE as usize] };
+        let nw = NarrowWaistPacket::signed_response(&identity, hbfi.clone(), data.clone(), 1, 2, vec![]).unwrap();
+        assert!(nw.verify());
+
+        let tampered = match nw.clone() {
+            NarrowWaistPacket::Response { hbfi, mut data, offset, total, publisher, signature, proof } => {
+                data.data[0] = 8;
+                NarrowWaistPacket::Response { hbfi, data, offset, total, publisher, signature, proof }
+            },
+            _ => unreachable!(),
+        };
+        assert!(!tampered.verify());
+
+        let impostor = PrivateIdentity::generate();
+        let forged = NarrowWaistPacket::signed_response(&impostor, hbfi, data, 1, 2, vec![]).unwrap();
+        assert!(!forged.verify());
+    }
 }
@@ -0,0 +1,148 @@
+use {
+    anyhow::{anyhow, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    ed25519_dalek::{Keypair, PublicKey, Signer, Verifier},
+    rand::rngs::OsRng,
+    std::{convert::TryFrom, fmt, str::FromStr},
+};
+
+pub const PUBLIC_IDENTITY_LENGTH: usize = ed25519_dalek::PUBLIC_KEY_LENGTH;
+pub const SECRET_IDENTITY_LENGTH: usize = ed25519_dalek::SECRET_KEY_LENGTH;
+pub const SIGNATURE_LENGTH: usize = ed25519_dalek::SIGNATURE_LENGTH;
+
+/// A publisher's Ed25519 keypair. The public half, rendered as hex, is
+/// the `id` a publication's `HBFI` is derived from.
+pub struct PrivateIdentity {
+    keypair: Keypair,
+}
+
+impl PrivateIdentity {
+    pub fn generate() -> Self {
+        let mut csprng = OsRng {};
+        Self { keypair: Keypair::generate(&mut csprng) }
+    }
+    pub fn from_secret_bytes(secret: &[u8]) -> Result<Self> {
+        let secret = ed25519_dalek::SecretKey::from_bytes(secret).map_err(|e| anyhow!("{}", e))?;
+        let public: PublicKey = (&secret).into();
+        Ok(Self { keypair: Keypair { secret, public } })
+    }
+    pub fn secret_bytes(&self) -> [u8; SECRET_IDENTITY_LENGTH] {
+        self.keypair.secret.to_bytes()
+    }
+    pub fn public_id(&self) -> PublicIdentity {
+        PublicIdentity(self.keypair.public.to_bytes())
+    }
+    pub fn sign(&self, message: &[u8]) -> Signature {
+        Signature(self.keypair.sign(message).to_bytes())
+    }
+}
+
+impl Clone for PrivateIdentity {
+    fn clone(&self) -> Self {
+        Self { keypair: Keypair::from_bytes(&self.keypair.to_bytes()).expect("a valid keypair") }
+    }
+}
+
+impl fmt::Debug for PrivateIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrivateIdentity({})", self.public_id())
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, BorshSerialize, BorshDeserialize)]
+pub struct PublicIdentity(pub [u8; PUBLIC_IDENTITY_LENGTH]);
+
+impl PublicIdentity {
+    pub fn verify(&self, message: &[u8], signature: &Signature) -> bool {
+        let public = match PublicKey::from_bytes(&self.0) {
+            Ok(public) => public,
+            Err(_) => return false,
+        };
+        let signature = match ed25519_dalek::Signature::try_from(&signature.0[..]) {
+            Ok(signature) => signature,
+            Err(_) => return false,
+        };
+        public.verify(message, &signature).is_ok()
+    }
+}
+
+impl fmt::Display for PublicIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in self.0.iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for PublicIdentity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PublicIdentity({})", self)
+    }
+}
+
+impl FromStr for PublicIdentity {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != PUBLIC_IDENTITY_LENGTH * 2 || !s.is_ascii() {
+            return Err(anyhow!("A public identity is {} hex characters", PUBLIC_IDENTITY_LENGTH * 2));
+        }
+        let mut bytes = [0u8; PUBLIC_IDENTITY_LENGTH];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&s[i * 2..i * 2 + 2], 16)?;
+        }
+        PublicKey::from_bytes(&bytes).map_err(|e| anyhow!("{}", e))?;
+        Ok(PublicIdentity(bytes))
+    }
+}
+
+#[derive(Clone, Copy, BorshSerialize, BorshDeserialize)]
+pub struct Signature(pub [u8; SIGNATURE_LENGTH]);
+
+impl PartialEq for Signature {
+    fn eq(&self, other: &Self) -> bool {
+        self.0[..] == other.0[..]
+    }
+}
+
+impl Eq for Signature {}
+
+impl fmt::Debug for Signature {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Signature(")?;
+        for byte in self.0[..4].iter() {
+            write!(f, "{:02x}", byte)?;
+        }
+        write!(f, "..)")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_and_verify() {
+        let identity = PrivateIdentity::generate();
+        let signature = identity.sign(b"hello");
+        assert!(identity.public_id().verify(b"hello", &signature));
+        assert!(!identity.public_id().verify(b"hellO", &signature));
+        assert!(!PrivateIdentity::generate().public_id().verify(b"hello", &signature));
+    }
+
+    #[test]
+    fn public_identity_round_trips_through_hex() {
+        let identity = PrivateIdentity::generate();
+        let public = identity.public_id();
+        let parsed: PublicIdentity = public.to_string().parse().unwrap();
+        assert_eq!(parsed, public);
+        assert!("not hex".parse::<PublicIdentity>().is_err());
+    }
+
+    #[test]
+    fn secret_round_trips() {
+        let identity = PrivateIdentity::generate();
+        let restored = PrivateIdentity::from_secret_bytes(&identity.secret_bytes()).unwrap();
+        assert_eq!(restored.public_id(), identity.public_id());
+    }
+}
@@ -0,0 +1,281 @@
+use {
+    crate::identity::{PrivateIdentity, PublicIdentity, SECRET_IDENTITY_LENGTH},
+    anyhow::{anyhow, Result},
+    borsh::{BorshDeserialize, BorshSerialize},
+    chacha20poly1305::{
+        aead::{Aead, NewAead},
+        ChaCha20Poly1305, Key, Nonce,
+    },
+    hmac::Hmac,
+    rand::{rngs::OsRng, RngCore},
+    sha2::Sha256,
+    std::{
+        env, fs,
+        io::Write,
+        path::{Path, PathBuf},
+    },
+};
+
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt, PermissionsExt};
+
+const IDS: &str = "ids";
+const TRUSTED: &str = "trusted";
+const DEFAULT: &str = "default";
+const ROUNDS: u32 = 100_000;
+
+#[derive(BorshSerialize, BorshDeserialize)]
+struct EncryptedIdentity {
+    rounds: u32,
+    salt: [u8; 16],
+    nonce: [u8; 12],
+    ciphertext: Vec<u8>,
+}
+
+/// An on disk store of our own identities, each secret key encrypted
+/// under its own passphrase, and of the identities each of them trusts.
+///
+/// keystore/
+///   ids/<public id>        encrypted secret key
+///   trusted/<public id>    public ids trusted by this identity
+///   default                public id used when none is given
+pub struct Keystore {
+    dir: PathBuf,
+    rounds: u32,
+}
+
+impl Keystore {
+    /// `$HOME/.copernica/keystore`, or a relative `.copernica/keystore`.
+    pub fn default_dir() -> PathBuf {
+        let home = env::var_os("HOME").map(PathBuf::from).unwrap_or_default();
+        home.join(".copernica").join("keystore")
+    }
+
+    /// Creates the keystore if need be, readable only by its owner.
+    pub fn open(dir: &Path) -> Result<Self> {
+        create_private_dir(dir)?;
+        create_private_dir(&dir.join(IDS))?;
+        create_private_dir(&dir.join(TRUSTED))?;
+        Ok(Self { dir: dir.to_path_buf(), rounds: ROUNDS })
+    }
+
+    /// PBKDF2 rounds used to derive the key of newly stored identities.
+    pub fn rounds(mut self, rounds: u32) -> Self {
+        self.rounds = rounds.max(1);
+        self
+    }
+
+    pub fn generate(&self, passphrase: &str) -> Result<PublicIdentity> {
+        let identity = PrivateIdentity::generate();
+        self.insert(&identity, passphrase)?;
+        Ok(identity.public_id())
+    }
+
+    pub fn insert(&self, identity: &PrivateIdentity, passphrase: &str) -> Result<()> {
+        let mut salt = [0u8; 16];
+        let mut nonce = [0u8; 12];
+        OsRng.fill_bytes(&mut salt);
+        OsRng.fill_bytes(&mut nonce);
+        let cipher = cipher(passphrase, &salt, self.rounds);
+        let ciphertext = cipher
+            .encrypt(&Nonce::from(nonce), &identity.secret_bytes()[..])
+            .map_err(|_| anyhow!("Failed to encrypt {}", identity.public_id()))?;
+        let encrypted = EncryptedIdentity { rounds: self.rounds, salt, nonce, ciphertext };
+        write_private(&self.id_path(&identity.public_id()), &encrypted.try_to_vec()?)?;
+        Ok(())
+    }
+
+    /// Our own identities, in no particular order.
+    pub fn list(&self) -> Result<Vec<PublicIdentity>> {
+        let mut ids = vec![];
+        for entry in fs::read_dir(self.dir.join(IDS))? {
+            let name = entry?.file_name();
+            if let Some(Ok(id)) = name.to_str().map(str::parse::<PublicIdentity>) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+
+    pub fn contains(&self, id: &PublicIdentity) -> bool {
+        self.id_path(id).is_file()
+    }
+
+    pub fn unlock(&self, id: &PublicIdentity, passphrase: &str) -> Result<PrivateIdentity> {
+        let encrypted = fs::read(self.id_path(id)).map_err(|_| anyhow!("{} is not in the keystore", id))?;
+        let encrypted = EncryptedIdentity::try_from_slice(&encrypted)?;
+        let cipher = cipher(passphrase, &encrypted.salt, encrypted.rounds);
+        let secret = cipher
+            .decrypt(&Nonce::from(encrypted.nonce), &encrypted.ciphertext[..])
+            .map_err(|_| anyhow!("Wrong passphrase for {}", id))?;
+        if secret.len() != SECRET_IDENTITY_LENGTH {
+            return Err(anyhow!("The secret key of {} is corrupt", id));
+        }
+        let identity = PrivateIdentity::from_secret_bytes(&secret)?;
+        if identity.public_id() != *id {
+            return Err(anyhow!("The secret key of {} belongs to {}", id, identity.public_id()));
+        }
+        Ok(identity)
+    }
+
+    pub fn remove(&self, id: &PublicIdentity) -> Result<()> {
+        fs::remove_file(self.id_path(id))?;
+        let trusted = self.trusted_path(id);
+        if trusted.is_file() {
+            fs::remove_file(trusted)?;
+        }
+        if self.default_id()? == Some(*id) {
+            fs::remove_file(self.dir.join(DEFAULT))?;
+        }
+        Ok(())
+    }
+
+    /// Makes `id` the identity used when none is given explicitly.
+    pub fn set_default(&self, id: &PublicIdentity) -> Result<()> {
+        if !self.contains(id) {
+            return Err(anyhow!("{} is not in the keystore", id));
+        }
+        write_private(&self.dir.join(DEFAULT), id.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    pub fn default_id(&self) -> Result<Option<PublicIdentity>> {
+        match fs::read_to_string(self.dir.join(DEFAULT)) {
+            Ok(id) => Ok(Some(id.trim().parse()?)),
+            Err(_) => Ok(None),
+        }
+    }
+
+    /// Records that our identity `ours` trusts `theirs`.
+    pub fn trust(&self, ours: &PublicIdentity, theirs: &PublicIdentity) -> Result<()> {
+        if !self.contains(ours) {
+            return Err(anyhow!("{} is not in the keystore", ours));
+        }
+        let mut trusted = self.trusted(ours)?;
+        if !trusted.contains(theirs) {
+            trusted.push(*theirs);
+            write_private(&self.trusted_path(ours), &trusted.try_to_vec()?)?;
+        }
+        Ok(())
+    }
+
+    pub fn distrust(&self, ours: &PublicIdentity, theirs: &PublicIdentity) -> Result<()> {
+        let mut trusted = self.trusted(ours)?;
+        trusted.retain(|id| id != theirs);
+        write_private(&self.trusted_path(ours), &trusted.try_to_vec()?)?;
+        Ok(())
+    }
+
+    pub fn trusted(&self, ours: &PublicIdentity) -> Result<Vec<PublicIdentity>> {
+        match fs::read(self.trusted_path(ours)) {
+            Ok(trusted) => Ok(Vec::<PublicIdentity>::try_from_slice(&trusted)?),
+            Err(_) => Ok(vec![]),
+        }
+    }
+
+    pub fn is_trusted(&self, ours: &PublicIdentity, theirs: &PublicIdentity) -> Result<bool> {
+        Ok(self.trusted(ours)?.contains(theirs))
+    }
+
+    fn id_path(&self, id: &PublicIdentity) -> PathBuf {
+        self.dir.join(IDS).join(id.to_string())
+    }
+
+    fn trusted_path(&self, id: &PublicIdentity) -> PathBuf {
+        self.dir.join(TRUSTED).join(id.to_string())
+    }
+}
+
+// Directories and files are created with modes 0700 and 0600. Existing
+// ones are narrowed to the same modes, as a keystore may predate this.
+fn create_private_dir(dir: &Path) -> Result<()> {
+    let mut builder = fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(dir)?;
+    #[cfg(unix)]
+    fs::set_permissions(dir, fs::Permissions::from_mode(0o700))?;
+    Ok(())
+}
+
+fn write_private(path: &Path, bytes: &[u8]) -> Result<()> {
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(path)?;
+    #[cfg(unix)]
+    file.set_permissions(fs::Permissions::from_mode(0o600))?;
+    file.write_all(bytes)?;
+    Ok(())
+}
+
+fn cipher(passphrase: &str, salt: &[u8], rounds: u32) -> ChaCha20Poly1305 {
+    let mut key = [0u8; 32];
+    pbkdf2::pbkdf2::<Hmac<Sha256>>(passphrase.as_bytes(), salt, rounds, &mut key);
+    ChaCha20Poly1305::new(&Key::from(key))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keystore() -> Keystore {
+        let mut dir = env::temp_dir();
+        dir.push("copernica");
+        dir.push(format!("keystore-{}", OsRng.next_u64()));
+        Keystore::open(&dir).unwrap().rounds(10)
+    }
+
+    #[test]
+    fn unlock_needs_the_passphrase() {
+        let keystore = keystore();
+        let id = keystore.generate("hunter2").unwrap();
+        assert_eq!(keystore.list().unwrap(), vec![id]);
+        assert_eq!(keystore.unlock(&id, "hunter2").unwrap().public_id(), id);
+        assert!(keystore.unlock(&id, "hunter3").is_err());
+        let stranger = PrivateIdentity::generate().public_id();
+        assert!(keystore.unlock(&stranger, "hunter2").is_err());
+    }
+
+    #[test]
+    fn trust_is_per_identity() {
+        let keystore = keystore();
+        let ours = keystore.generate("a").unwrap();
+        let mine_too = keystore.generate("b").unwrap();
+        let theirs = PrivateIdentity::generate().public_id();
+        keystore.trust(&ours, &theirs).unwrap();
+        keystore.trust(&ours, &theirs).unwrap();
+        assert_eq!(keystore.trusted(&ours).unwrap(), vec![theirs]);
+        assert!(!keystore.is_trusted(&mine_too, &theirs).unwrap());
+        assert!(keystore.trust(&theirs, &ours).is_err());
+        keystore.distrust(&ours, &theirs).unwrap();
+        assert!(!keystore.is_trusted(&ours, &theirs).unwrap());
+    }
+
+    #[test]
+    fn default_identity() {
+        let keystore = keystore();
+        assert_eq!(keystore.default_id().unwrap(), None);
+        let id = keystore.generate("a").unwrap();
+        keystore.set_default(&id).unwrap();
+        assert_eq!(keystore.default_id().unwrap(), Some(id));
+        keystore.remove(&id).unwrap();
+        assert_eq!(keystore.default_id().unwrap(), None);
+        assert!(keystore.list().unwrap().is_empty());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn only_the_owner_can_read_keys() {
+        let keystore = keystore();
+        let ours = keystore.generate("a").unwrap();
+        keystore.trust(&ours, &PrivateIdentity::generate().public_id()).unwrap();
+        let mode = |path: PathBuf| fs::metadata(path).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode(keystore.dir.clone()), 0o700);
+        assert_eq!(mode(keystore.dir.join(IDS)), 0o700);
+        assert_eq!(mode(keystore.id_path(&ours)), 0o600);
+        assert_eq!(mode(keystore.trusted_path(&ours)), 0o600);
+    }
+}
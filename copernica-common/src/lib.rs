@@ -1,12 +1,22 @@
 mod link;
 mod packets;
 mod hbfi;
+mod identity;
+mod keystore;
+mod merkle;
+mod response_store;
+mod stop;
 pub mod constants;
 pub mod log;
 
 pub use crate::{
     hbfi::{HBFI, BFI},
+    identity::{PrivateIdentity, PublicIdentity, Signature},
+    keystore::{Keystore},
+    merkle::{Hash, MerkleTree, Segments, leaf_hash, chunk_hash},
     link::{LinkId, Nonce, ReplyTo},
     packets::{Data, InterLinkPacket, NarrowWaistPacket, LinkPacket},
+    response_store::{ResponseStore, Watch, MemoryStore, MemoryWatcher, SledWatcher},
+    stop::{StopHandle, STOP_POLL},
     log::setup_logging,
 };
@@ -15,6 +15,7 @@ pub enum ReplyTo {
     Rf(Hertz),
     Mpsc,
     DeepSix,
+    Tcp(SocketAddr),
     //Release, // think about how to release the constriction
 }
 
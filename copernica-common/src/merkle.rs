@@ -0,0 +1,164 @@
+use {
+    crate::{constants, packets::Data},
+    sha3::{Digest, Sha3_256},
+};
+
+pub type Hash = [u8; 32];
+
+const LEAF: u8 = 0;
+const NODE: u8 = 1;
+
+/// Hash of the chunk published at `offset`, covering only the `len`
+/// bytes of `data` in use.
+pub fn leaf_hash(offset: u64, data: &Data) -> Hash {
+    let (chunk, _) = data.data.split_at(data.len as usize);
+    chunk_hash(offset, chunk)
+}
+
+pub fn chunk_hash(offset: u64, chunk: &[u8]) -> Hash {
+    let mut hasher = Sha3_256::new();
+    hasher.input([LEAF]);
+    hasher.input(offset.to_le_bytes());
+    hasher.input(chunk);
+    to_hash(hasher)
+}
+
+fn node_hash(left: &Hash, right: &Hash) -> Hash {
+    let mut hasher = Sha3_256::new();
+    hasher.input([NODE]);
+    hasher.input(left);
+    hasher.input(right);
+    to_hash(hasher)
+}
+
+fn to_hash(hasher: Sha3_256) -> Hash {
+    let mut hash = [0u8; 32];
+    hash.copy_from_slice(&hasher.result());
+    hash
+}
+
+/// Binary Merkle tree over the chunks of a publication. A level with an
+/// odd number of nodes pairs its last node with itself, so a tree of `n`
+/// leaves is `ceil(log2(n))` levels deep. Proofs stop at the segment
+/// roots, `constants::MAX_PROOF_DEPTH` levels above the leaves, so that
+/// they stay short however large the publication; the segment roots are
+/// published alongside the chunks.
+pub struct MerkleTree {
+    levels: Vec<Vec<Hash>>,
+}
+
+impl MerkleTree {
+    pub fn new(leaves: Vec<Hash>) -> Self {
+        let mut levels = vec![leaves];
+        while levels[levels.len() - 1].len() > 1 {
+            let level = levels[levels.len() - 1]
+                .chunks(2)
+                .map(|pair| node_hash(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+                .collect();
+            levels.push(level);
+        }
+        Self { levels }
+    }
+
+    /// The root of an empty tree is all zeroes.
+    pub fn root(&self) -> Hash {
+        match self.levels[self.levels.len() - 1].first() {
+            Some(root) => *root,
+            None => [0u8; 32],
+        }
+    }
+
+    /// Sibling hashes from the leaf at `index` up to, but excluding, its
+    /// segment root.
+    pub fn proof(&self, index: usize) -> Vec<Hash> {
+        let mut proof = vec![];
+        let mut index = index;
+        for level in &self.levels[..self.segment_depth()] {
+            let sibling = if index.is_multiple_of(2) { index + 1 } else { index - 1 };
+            proof.push(*level.get(sibling).unwrap_or(&level[index]));
+            index /= 2;
+        }
+        proof
+    }
+
+    pub fn segments(&self) -> Segments {
+        let depth = self.segment_depth();
+        Segments { roots: self.levels[depth].clone(), depth }
+    }
+
+    fn segment_depth(&self) -> usize {
+        (self.levels.len() - 1).min(constants::MAX_PROOF_DEPTH as usize)
+    }
+}
+
+/// The nodes of a publication's Merkle tree that its chunks' proofs end
+/// at: those `constants::MAX_PROOF_DEPTH` levels above the leaves, or the
+/// root alone of a shallower tree. Each covers a segment of up to
+/// 2^MAX_PROOF_DEPTH consecutive chunks.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Segments {
+    roots: Vec<Hash>,
+    depth: usize,
+}
+
+impl Segments {
+    /// The segment `roots` of a tree over `leaves` chunks.
+    pub fn new(roots: Vec<Hash>, leaves: u64) -> Self {
+        let depth = (u64::BITS - (leaves.max(1) - 1).leading_zeros()) as usize;
+        Self { roots, depth: depth.min(constants::MAX_PROOF_DEPTH as usize) }
+    }
+
+    pub fn roots(&self) -> &[Hash] {
+        &self.roots
+    }
+
+    /// The root of the whole tree, for checking the segment roots against
+    /// the one a publisher signed.
+    pub fn root(&self) -> Hash {
+        MerkleTree::new(self.roots.clone()).root()
+    }
+
+    /// True when `proof` places `leaf` at `index` below its segment root.
+    pub fn verify(&self, leaf: Hash, index: u64, proof: &[Hash]) -> bool {
+        if proof.len() != self.depth {
+            return false;
+        }
+        let mut hash = leaf;
+        let mut index = index;
+        for sibling in proof {
+            hash = if index.is_multiple_of(2) { node_hash(&hash, sibling) } else { node_hash(sibling, &hash) };
+            index /= 2;
+        }
+        index < self.roots.len() as u64 && self.roots[index as usize] == hash
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_leaf_proves_against_its_segment() {
+        let depth = constants::MAX_PROOF_DEPTH as u32;
+        for n in (1..20u64).chain([(1 << depth) - 1, 1 << depth, (1 << depth) + 1, 5 << depth]) {
+            let leaves: Vec<Hash> = (0..n).map(|i| chunk_hash(i, &[i as u8])).collect();
+            let tree = MerkleTree::new(leaves.clone());
+            let segments = Segments::new(tree.segments().roots().to_vec(), n);
+            assert_eq!(segments, tree.segments());
+            assert_eq!(segments.root(), tree.root());
+            assert_eq!(segments.roots().len() as u64, (n + (1 << depth) - 1) >> depth);
+            for (i, leaf) in leaves.iter().enumerate() {
+                let proof = tree.proof(i);
+                assert!(proof.len() <= depth as usize);
+                assert!(segments.verify(*leaf, i as u64, &proof));
+                assert!(!segments.verify(chunk_hash(i as u64, b"bad"), i as u64, &proof));
+                if n > 1 {
+                    assert!(!segments.verify(*leaf, (i as u64 + 1) % n, &proof));
+                }
+                if !proof.is_empty() {
+                    assert!(!segments.verify(*leaf, i as u64, &proof[1..]));
+                }
+            }
+        }
+    }
+}
@@ -2,9 +2,13 @@ use {
     crate::{
         constants,
         hbfi::HBFI,
+        identity::{PrivateIdentity, PublicIdentity, Signature},
+        merkle::{self, Hash, Segments},
         link::{LinkId, ReplyTo},
     },
+    anyhow::Result,
     borsh::{BorshDeserialize, BorshSerialize},
+    rand::Rng,
     std::fmt,
 };
 
@@ -18,19 +22,158 @@ pub struct Data {
 pub enum NarrowWaistPacket {
     Request {
         hbfi: HBFI,
+        /// Drawn afresh for every `Request`, so that brokers can tell a
+        /// replayed `Request` from a new one for the same `hbfi`.
+        nonce: u64,
+        /// Who asked, and their signature over `hbfi` and `nonce`. Without
+        /// one a replay can pass as new by changing `nonce`.
+        requester: Option<(PublicIdentity, Signature)>,
+        /// Brokers this `Request` may still pass through. Each broker
+        /// takes one off and drops the `Request` at zero. Not signed, as
+        /// brokers change it.
+        hops: u8,
     },
     Response {
         hbfi: HBFI,
         data: Data,
         offset: u64,
         total: u64,
+        publisher: PublicIdentity,
+        signature: Signature,
+        proof: Vec<Hash>,
     },
 }
 
+impl NarrowWaistPacket {
+    pub fn request(hbfi: HBFI) -> Self {
+        NarrowWaistPacket::Request { hbfi, nonce: rand::thread_rng().gen(), requester: None, hops: constants::HOP_LIMIT }
+    }
+
+    /// Builds a `Request` signed by `identity`, such as a ccli identity
+    /// unlocked from a `Keystore`.
+    pub fn signed_request(identity: &PrivateIdentity, hbfi: HBFI) -> Result<Self> {
+        let nonce = rand::thread_rng().gen();
+        let signature = identity.sign(&request_bytes(&hbfi, nonce)?);
+        Ok(NarrowWaistPacket::Request {
+            hbfi,
+            nonce,
+            requester: Some((identity.public_id(), signature)),
+            hops: constants::HOP_LIMIT,
+        })
+    }
+
+    /// Sets how many brokers a `Request` may pass through.
+    pub fn hop_limit(self, hops: u8) -> Self {
+        match self {
+            NarrowWaistPacket::Request { hbfi, nonce, requester, .. } => {
+                NarrowWaistPacket::Request { hbfi, nonce, requester, hops }
+            },
+            response => response,
+        }
+    }
+
+    /// The packet as a broker passes it on: a `Request` one hop closer to
+    /// its limit, or `None` once it may go no further.
+    pub fn next_hop(&self) -> Option<Self> {
+        match self {
+            NarrowWaistPacket::Request { hbfi, nonce, requester, hops } => {
+                match hops.saturating_sub(1) {
+                    0 => None,
+                    hops => Some(NarrowWaistPacket::Request { hbfi: hbfi.clone(), nonce: *nonce, requester: *requester, hops }),
+                }
+            },
+            response => Some(response.clone()),
+        }
+    }
+
+    /// Builds a `Response` signed by the publisher `hbfi.id` is derived from.
+    /// `proof` places the chunk in the Merkle tree whose root is published
+    /// in the manifest at offset 0; it is not covered by the signature.
+    pub fn signed_response(
+        identity: &PrivateIdentity,
+        hbfi: HBFI,
+        data: Data,
+        offset: u64,
+        total: u64,
+        proof: Vec<Hash>,
+    ) -> Result<Self> {
+        let signature = identity.sign(&signed_bytes(&hbfi, &data, offset, total)?);
+        Ok(NarrowWaistPacket::Response {
+            hbfi,
+            data,
+            offset,
+            total,
+            publisher: identity.public_id(),
+            signature,
+            proof,
+        })
+    }
+
+    /// A `Response` verifies when its publisher owns `hbfi.id` and the
+    /// signature covers the rest of the packet. A `Request` verifies when
+    /// it is unsigned or its requester's signature holds.
+    pub fn verify(&self) -> bool {
+        match self {
+            NarrowWaistPacket::Request { requester: None, .. } => true,
+            NarrowWaistPacket::Request { hbfi, nonce, requester: Some((requester, signature)), .. } => {
+                match request_bytes(hbfi, *nonce) {
+                    Ok(message) => requester.verify(&message, signature),
+                    Err(_) => false,
+                }
+            },
+            NarrowWaistPacket::Response {
+                hbfi,
+                data,
+                offset,
+                total,
+                publisher,
+                signature,
+                ..
+            } => {
+                if !hbfi.is_published_by(publisher) {
+                    return false;
+                }
+                match signed_bytes(hbfi, data, *offset, *total) {
+                    Ok(message) => publisher.verify(&message, signature),
+                    Err(_) => false,
+                }
+            }
+        }
+    }
+    /// Checks a chunk's inclusion proof against the segment roots of the
+    /// publication's Merkle tree. The manifest at offset 0 is outside the
+    /// tree and is trusted on its signature alone.
+    pub fn verify_proof(&self, segments: &Segments) -> bool {
+        match self {
+            NarrowWaistPacket::Request { .. } => true,
+            NarrowWaistPacket::Response { hbfi, data, proof, .. } => {
+                if hbfi.os == 0 {
+                    return true;
+                }
+                segments.verify(merkle::leaf_hash(hbfi.os, data), hbfi.os - 1, proof)
+            }
+        }
+    }
+}
+
+fn request_bytes(hbfi: &HBFI, nonce: u64) -> Result<Vec<u8>> {
+    let mut message = hbfi.try_to_vec()?;
+    nonce.serialize(&mut message)?;
+    Ok(message)
+}
+
+fn signed_bytes(hbfi: &HBFI, data: &Data, offset: u64, total: u64) -> Result<Vec<u8>> {
+    let mut message = hbfi.try_to_vec()?;
+    data.serialize(&mut message)?;
+    offset.serialize(&mut message)?;
+    total.serialize(&mut message)?;
+    Ok(message)
+}
+
 impl fmt::Debug for NarrowWaistPacket {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match &*self {
-            NarrowWaistPacket::Request { hbfi } => write!(f, "REQ{:?}", hbfi),
+            NarrowWaistPacket::Request { hbfi, nonce, .. } => write!(f, "REQ{:?} #{}", hbfi, nonce),
             NarrowWaistPacket::Response {
                 hbfi,
                 offset,
@@ -78,6 +221,9 @@ impl InterLinkPacket {
     pub fn change_destination(&self, link_id: LinkId) -> Self {
         Self { link_id, lp: self.lp.clone() }
     }
+    pub fn change_narrow_waist(&self, nw: NarrowWaistPacket) -> Self {
+        Self { link_id: self.link_id.clone(), lp: LinkPacket::new(self.lp.reply_to(), nw) }
+    }
     pub fn reply_to(&self) -> ReplyTo {
         self.link_id.reply_to()
     }
@@ -88,3 +234,40 @@ impl InterLinkPacket {
         self.lp.clone()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn signed_requests_verify() {
+        let identity = PrivateIdentity::generate();
+        let hbfi = HBFI::new("app", &identity.public_id().to_string()).unwrap();
+        let unsigned = NarrowWaistPacket::request(hbfi.clone());
+        assert!(unsigned.verify());
+        let signed = NarrowWaistPacket::signed_request(&identity, hbfi.clone()).unwrap();
+        assert!(signed.verify());
+        let replayed = match signed {
+            NarrowWaistPacket::Request { hbfi, nonce, requester, hops } => {
+                NarrowWaistPacket::Request { hbfi, nonce: nonce.wrapping_add(1), requester, hops }
+            },
+            _ => unreachable!(),
+        };
+        assert!(!replayed.verify());
+    }
+
+    #[test]
+    fn hops_run_out() {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let identity = PrivateIdentity::generate();
+        let request = NarrowWaistPacket::signed_request(&identity, hbfi).unwrap().hop_limit(3);
+        let once = request.next_hop().unwrap();
+        match &once {
+            NarrowWaistPacket::Request { hops, .. } => assert_eq!(*hops, 2),
+            _ => unreachable!(),
+        }
+        assert!(once.verify());
+        assert!(once.next_hop().unwrap().next_hop().is_none());
+        assert!(request.hop_limit(0).next_hop().is_none());
+    }
+}
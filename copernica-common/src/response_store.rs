@@ -0,0 +1,241 @@
+use {
+    crate::{HBFI, BFI, NarrowWaistPacket},
+    borsh::{BorshDeserialize, BorshSerialize},
+    crossbeam_channel::{unbounded, Receiver, Sender},
+    async_std::{future, task},
+    anyhow::{anyhow, Result},
+    std::{
+        collections::HashMap,
+        mem,
+        sync::{Arc, RwLock},
+        time::Duration,
+    },
+    log::warn,
+};
+
+/// Wakes a waiting fetch when a Response arrives for a watched publication.
+pub trait Watch: Send {
+    /// Waits up to `wait` for the next Response inserted. A wake up may be
+    /// missed, so on `None` callers should look in the store themselves.
+    fn next_timeout(&mut self, wait: Duration) -> Option<NarrowWaistPacket>;
+}
+
+/// Where brokers and services keep Responses, keyed by their `HBFI`.
+pub trait ResponseStore: Clone + Send + Sync + 'static {
+    type Watcher: Watch;
+    fn get(&self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>>;
+    fn insert(&self, hbfi: &HBFI, nw: &NarrowWaistPacket) -> Result<()>;
+    fn insert_batch(&self, responses: Vec<(HBFI, NarrowWaistPacket)>) -> Result<()> {
+        for (hbfi, nw) in responses {
+            self.insert(&hbfi, &nw)?;
+        }
+        Ok(())
+    }
+    fn remove(&self, hbfi: &HBFI) -> Result<()>;
+    /// Watches every offset of the publication `hbfi` belongs to.
+    fn watch(&self, hbfi: &HBFI) -> Result<Self::Watcher>;
+    /// Every `HBFI` held, with the bytes its Response occupies.
+    fn index(&self) -> Result<Vec<(HBFI, u64)>>;
+    /// Named state kept apart from the Responses, such as a broker's
+    /// routing model.
+    fn load_state(&self, name: &str) -> Result<Option<Vec<u8>>>;
+    fn save_state(&self, name: &str, state: &[u8]) -> Result<()>;
+    fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+}
+
+const STATE_TREE: &str = "state";
+
+// Responses are stored as borsh under the borsh encoded HBFI, so all
+// offsets of a publication share the key prefix of h1 and id.
+fn publication_prefix(hbfi: &HBFI) -> Result<Vec<u8>> {
+    let mut prefix = hbfi.clone().offset(0).try_to_vec()?;
+    prefix.truncate(prefix.len() - mem::size_of::<u64>());
+    Ok(prefix)
+}
+
+pub struct SledWatcher {
+    subscriber: sled::Subscriber,
+}
+
+impl Watch for SledWatcher {
+    fn next_timeout(&mut self, wait: Duration) -> Option<NarrowWaistPacket> {
+        let subscriber = &mut self.subscriber;
+        match task::block_on(async { future::timeout(wait, subscriber).await }) {
+            Ok(Some(sled::Event::Insert { value, .. })) => NarrowWaistPacket::try_from_slice(&value).ok(),
+            _ => None,
+        }
+    }
+}
+
+impl ResponseStore for sled::Db {
+    type Watcher = SledWatcher;
+    fn get(&self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>> {
+        match sled::Tree::get(self, hbfi.try_to_vec()?)? {
+            Some(nw) => Ok(Some(NarrowWaistPacket::try_from_slice(&nw)?)),
+            None => Ok(None),
+        }
+    }
+    fn insert(&self, hbfi: &HBFI, nw: &NarrowWaistPacket) -> Result<()> {
+        sled::Tree::insert(self, hbfi.try_to_vec()?, nw.try_to_vec()?)?;
+        Ok(())
+    }
+    fn insert_batch(&self, responses: Vec<(HBFI, NarrowWaistPacket)>) -> Result<()> {
+        let mut batch = sled::Batch::default();
+        for (hbfi, nw) in responses {
+            batch.insert(hbfi.try_to_vec()?, nw.try_to_vec()?);
+        }
+        self.apply_batch(batch)?;
+        Ok(())
+    }
+    fn remove(&self, hbfi: &HBFI) -> Result<()> {
+        sled::Tree::remove(self, hbfi.try_to_vec()?)?;
+        Ok(())
+    }
+    fn watch(&self, hbfi: &HBFI) -> Result<SledWatcher> {
+        Ok(SledWatcher { subscriber: self.watch_prefix(publication_prefix(hbfi)?) })
+    }
+    fn index(&self) -> Result<Vec<(HBFI, u64)>> {
+        let mut index = vec![];
+        for kv in self.iter() {
+            let (key, value) = kv?;
+            match HBFI::try_from_slice(&key) {
+                Ok(hbfi) => index.push((hbfi, (key.len() + value.len()) as u64)),
+                Err(_) => warn!("response store holds a key that isn't an HBFI"),
+            }
+        }
+        Ok(index)
+    }
+    fn load_state(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        Ok(self.open_tree(STATE_TREE)?.get(name)?.map(|state| state.to_vec()))
+    }
+    fn save_state(&self, name: &str, state: &[u8]) -> Result<()> {
+        self.open_tree(STATE_TREE)?.insert(name, state)?;
+        Ok(())
+    }
+    fn flush(&self) -> Result<()> {
+        sled::Tree::flush(self)?;
+        Ok(())
+    }
+}
+
+pub struct MemoryWatcher {
+    rx: Receiver<NarrowWaistPacket>,
+}
+
+impl Watch for MemoryWatcher {
+    fn next_timeout(&mut self, wait: Duration) -> Option<NarrowWaistPacket> {
+        self.rx.recv_timeout(wait).ok()
+    }
+}
+
+#[derive(Default)]
+struct Memory {
+    responses: HashMap<HBFI, (NarrowWaistPacket, u64)>,
+    watchers: Vec<((BFI, BFI), Sender<NarrowWaistPacket>)>,
+    state: HashMap<String, Vec<u8>>,
+}
+
+/// A `ResponseStore` that lives and dies with the process, for tests and
+/// nodes without a disk. Clones share the same Responses.
+#[derive(Clone, Default)]
+pub struct MemoryStore {
+    inner: Arc<RwLock<Memory>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ResponseStore for MemoryStore {
+    type Watcher = MemoryWatcher;
+    fn get(&self, hbfi: &HBFI) -> Result<Option<NarrowWaistPacket>> {
+        let memory = self.inner.read().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        Ok(memory.responses.get(hbfi).map(|(nw, _)| nw.clone()))
+    }
+    fn insert(&self, hbfi: &HBFI, nw: &NarrowWaistPacket) -> Result<()> {
+        let size = (hbfi.try_to_vec()?.len() + nw.try_to_vec()?.len()) as u64;
+        let mut memory = self.inner.write().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        memory.responses.insert(hbfi.clone(), (nw.clone(), size));
+        memory.watchers.retain(|((h1, id), tx)| {
+            if *h1 != hbfi.h1 || *id != hbfi.id {
+                return true;
+            }
+            tx.send(nw.clone()).is_ok()
+        });
+        Ok(())
+    }
+    fn remove(&self, hbfi: &HBFI) -> Result<()> {
+        let mut memory = self.inner.write().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        memory.responses.remove(hbfi);
+        Ok(())
+    }
+    fn watch(&self, hbfi: &HBFI) -> Result<MemoryWatcher> {
+        let (tx, rx) = unbounded();
+        let mut memory = self.inner.write().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        memory.watchers.push(((hbfi.h1, hbfi.id), tx));
+        Ok(MemoryWatcher { rx })
+    }
+    fn index(&self) -> Result<Vec<(HBFI, u64)>> {
+        let memory = self.inner.read().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        Ok(memory.responses.iter().map(|(hbfi, (_, size))| (hbfi.clone(), *size)).collect())
+    }
+    fn load_state(&self, name: &str) -> Result<Option<Vec<u8>>> {
+        let memory = self.inner.read().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        Ok(memory.state.get(name).cloned())
+    }
+    fn save_state(&self, name: &str, state: &[u8]) -> Result<()> {
+        let mut memory = self.inner.write().map_err(|_| anyhow!("memory store lock poisoned"))?;
+        memory.state.insert(name.to_string(), state.to_vec());
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{PrivateIdentity, Data, constants};
+
+    fn response(identity: &PrivateIdentity, name: &str, offset: u64) -> (HBFI, NarrowWaistPacket) {
+        let hbfi = HBFI::new(name, &identity.public_id().to_string()).unwrap().offset(offset);
+        let data = Data { len: 0, data: [0; constants::FRAGMENT_SIZE as usize] };
+        let nw = NarrowWaistPacket::signed_response(identity, hbfi.clone(), data, offset, 10, vec![]).unwrap();
+        (hbfi, nw)
+    }
+
+    fn store_and_watch<S: ResponseStore>(rs: S) {
+        let id = PrivateIdentity::generate();
+        let (a, na) = response(&id, "app", 1);
+        let (b, nb) = response(&id, "other", 1);
+        let mut watcher = rs.watch(&a.clone().offset(0)).unwrap();
+        rs.insert(&b, &nb).unwrap();
+        rs.insert(&a, &na).unwrap();
+        match watcher.next_timeout(Duration::from_millis(500)) {
+            Some(NarrowWaistPacket::Response { hbfi, .. }) => assert_eq!(hbfi, a),
+            _ => panic!("expected the response to a"),
+        }
+        assert!(rs.get(&a).unwrap().is_some());
+        assert_eq!(rs.index().unwrap().len(), 2);
+        rs.remove(&a).unwrap();
+        assert!(rs.get(&a).unwrap().is_none());
+        rs.insert_batch(vec![response(&id, "app", 2), response(&id, "app", 3)]).unwrap();
+        assert_eq!(rs.index().unwrap().len(), 3);
+        assert_eq!(rs.load_state("model").unwrap(), None);
+        rs.save_state("model", b"counts").unwrap();
+        assert_eq!(rs.load_state("model").unwrap(), Some(b"counts".to_vec()));
+        assert_eq!(rs.index().unwrap().len(), 3);
+    }
+
+    #[test]
+    fn memory_store() {
+        store_and_watch(MemoryStore::new());
+    }
+
+    #[test]
+    fn sled_store() {
+        store_and_watch(sled::Config::new().temporary(true).open().unwrap());
+    }
+}
@@ -0,0 +1,91 @@
+use std::{
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+    },
+    thread::{self, JoinHandle},
+    time::Duration,
+};
+
+/// How long a thread waits on a channel or socket before checking whether
+/// it has been told to stop.
+pub const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Tells the threads of a broker, its links and its services to finish,
+/// and waits for them to. Clones share the signal and the threads; a
+/// handle that is never stopped leaves its threads running until the
+/// process exits.
+#[derive(Clone, Debug, Default)]
+pub struct StopHandle {
+    stopped: Arc<AtomicBool>,
+    threads: Arc<Mutex<Vec<JoinHandle<()>>>>,
+}
+
+impl StopHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Runs `f` on a thread that `stop` waits for. `f` is expected to
+    /// check `is_stopped` at least every `STOP_POLL`.
+    pub fn spawn<F, T>(&self, f: F)
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let handle = thread::spawn(move || {
+            let _ = f();
+        });
+        let mut threads = match self.threads.lock() {
+            Ok(threads) => threads,
+            Err(poisoned) => poisoned.into_inner(),
+        };
+        threads.retain(|thread| !thread.is_finished());
+        threads.push(handle);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.stopped.load(Ordering::SeqCst)
+    }
+
+    /// Signals every thread to finish and returns once all of them have,
+    /// including any they spawned while finishing.
+    pub fn stop(&self) {
+        self.stopped.store(true, Ordering::SeqCst);
+        loop {
+            let thread = match self.threads.lock() {
+                Ok(mut threads) => threads.pop(),
+                Err(poisoned) => poisoned.into_inner().pop(),
+            };
+            match thread {
+                Some(thread) => {
+                    let _ = thread.join();
+                },
+                None => break,
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stop_waits_for_every_thread() {
+        let stop = StopHandle::new();
+        let finished = Arc::new(AtomicBool::new(false));
+        let (polling, spawner, done) = (stop.clone(), stop.clone(), finished.clone());
+        stop.spawn(move || {
+            while !polling.is_stopped() {
+                thread::sleep(STOP_POLL);
+            }
+            spawner.spawn(move || {
+                thread::sleep(STOP_POLL);
+                done.store(true, Ordering::SeqCst);
+            });
+        });
+        stop.stop();
+        assert!(finished.load(Ordering::SeqCst));
+    }
+}
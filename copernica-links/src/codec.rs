@@ -0,0 +1,450 @@
+use {
+    copernica_common::{LinkPacket, constants},
+    borsh::{BorshDeserialize, BorshSerialize},
+    anyhow::{Result, anyhow},
+    reed_solomon::{Encoder, Decoder},
+    std::{
+        error,
+        fmt,
+        sync::{
+            Arc, Mutex,
+            atomic::{AtomicU64, Ordering},
+        },
+    },
+};
+
+/// Parity bytes per block unless a link is told otherwise.
+pub const ECC_LEN: u8 = 12;
+/// The least parity a link will use; below this corruption goes unnoticed.
+pub const MIN_ECC_LEN: u8 = 2;
+/// The most parity a link will use, a quarter of each block.
+pub const MAX_ECC_LEN: u8 = 64;
+// A Reed-Solomon block over GF(2^8), parity included.
+const BLOCK_LEN: usize = 255;
+// The header is one byte, sent three times so a single corrupt copy is
+// outvoted: the parity, with the top bit set for interleaved frames.
+const HEADER_LEN: usize = 3;
+const INTERLEAVED: u8 = 0x80;
+/// The longest frame `encode` makes: a packet of `constants::DATAGRAM_SIZE`
+/// bytes, which every Response fits, at the most parity.
+pub const MAX_FRAME_LEN: usize = HEADER_LEN + constants::DATAGRAM_SIZE
+    + MAX_ECC_LEN as usize * ((constants::DATAGRAM_SIZE + BLOCK_LEN - MAX_ECC_LEN as usize - 1) / (BLOCK_LEN - MAX_ECC_LEN as usize));
+// Packets decoded without needing more parity before the parity is lowered.
+const ADAPT_WINDOW: u32 = 32;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecodeError {
+    /// Block `block` has more errors than its parity can correct.
+    Uncorrectable { block: usize },
+    /// Block `block` is too short to hold its parity.
+    Truncated { block: usize },
+    /// No two copies of the header agree on a usable parity.
+    Header,
+    /// The corrected bytes are not a `LinkPacket`.
+    Malformed,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            DecodeError::Uncorrectable { block } => write!(f, "Block {} has too many errors to correct", block),
+            DecodeError::Truncated { block } => write!(f, "Block {} is shorter than its parity", block),
+            DecodeError::Header => write!(f, "Link header is corrupt"),
+            DecodeError::Malformed => write!(f, "Decoded bytes are not a link packet"),
+        }
+    }
+}
+
+impl error::Error for DecodeError {}
+
+/// How much Reed-Solomon parity a link adds to each block it sends.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Fec {
+    /// Always this many parity bytes, correcting half as many byte errors.
+    Fixed(u8),
+    /// Starts at `min` and follows the errors corrected in what the link
+    /// receives, on the premise that the path back is about as noisy: the
+    /// parity rises at once to twice what the worst block needed, and falls
+    /// step by step once packets have gone a while needing less.
+    Adaptive { min: u8, max: u8 },
+}
+
+impl Default for Fec {
+    fn default() -> Self {
+        Fec::Fixed(ECC_LEN)
+    }
+}
+
+/// What a link's Reed-Solomon decoding has seen since the link started.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct LinkStats {
+    /// Blocks received.
+    pub blocks: u64,
+    /// Bytes the parity corrected.
+    pub corrected: u64,
+    /// Blocks with too many errors to correct.
+    pub lost: u64,
+    /// Packets dropped because they couldn't be decoded.
+    pub dropped: u64,
+    /// Parity bytes per block the link currently sends.
+    pub ecc_len: u8,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    blocks: AtomicU64,
+    corrected: AtomicU64,
+    lost: AtomicU64,
+    dropped: AtomicU64,
+}
+
+#[derive(Debug)]
+struct Adapter {
+    fec: Fec,
+    ecc_len: u8,
+    // Packets since the parity last changed, and the most any of them needed.
+    quiet: u32,
+    needed: u8,
+}
+
+impl Adapter {
+    fn new(fec: Fec) -> Self {
+        let ecc_len = match fec {
+            Fec::Fixed(ecc_len) => clamp(ecc_len),
+            Fec::Adaptive { min, .. } => clamp(min),
+        };
+        Self { fec, ecc_len, quiet: 0, needed: 0 }
+    }
+
+    // `worst` is the most errors corrected in one block of a packet, or
+    // `None` if a block was lost.
+    fn observe(&mut self, worst: Option<usize>) {
+        let (min, max) = match self.fec {
+            Fec::Fixed(_) => return,
+            Fec::Adaptive { min, max } => (clamp(min), clamp(max).max(clamp(min))),
+        };
+        let needed = match worst {
+            Some(errors) => (errors.saturating_mul(4).min(max as usize) as u8).max(min),
+            None => self.ecc_len.saturating_mul(2).min(max),
+        };
+        if needed > self.ecc_len {
+            self.ecc_len = needed;
+            self.quiet = 0;
+            self.needed = min;
+            return;
+        }
+        self.needed = self.needed.max(needed);
+        self.quiet += 1;
+        if self.quiet >= ADAPT_WINDOW {
+            self.ecc_len = self.ecc_len.saturating_sub(2).max(self.needed).max(min);
+            self.quiet = 0;
+            self.needed = min;
+        }
+    }
+}
+
+fn clamp(ecc_len: u8) -> u8 {
+    ecc_len.clamp(MIN_ECC_LEN, MAX_ECC_LEN)
+}
+
+/// A link's Reed-Solomon framing. Each frame starts with a header naming
+/// its parity and layout, so either end may change its `Fec` or
+/// interleaving without telling the other. Clones share their counters
+/// and parity.
+///
+/// An interleaved frame sends the first byte of every block, then the
+/// second of every block, and so on, so a burst of errors is shared out
+/// across the blocks rather than landing on one: a frame of `n` blocks
+/// recovers bursts up to `n` times what one block corrects.
+#[derive(Clone, Debug)]
+pub struct Codec {
+    counters: Arc<Counters>,
+    adapter: Arc<Mutex<Adapter>>,
+    interleave: bool,
+}
+
+impl Default for Codec {
+    fn default() -> Self {
+        Codec::new(Fec::default())
+    }
+}
+
+impl Codec {
+    pub fn new(fec: Fec) -> Self {
+        Self {
+            counters: Arc::new(Counters::default()),
+            adapter: Arc::new(Mutex::new(Adapter::new(fec))),
+            interleave: false,
+        }
+    }
+
+    pub fn fec(mut self, fec: Fec) -> Self {
+        self.adapter = Arc::new(Mutex::new(Adapter::new(fec)));
+        self
+    }
+
+    pub fn interleave(mut self, interleave: bool) -> Self {
+        self.interleave = interleave;
+        self
+    }
+
+    fn ecc_len(&self) -> u8 {
+        match self.adapter.lock() {
+            Ok(adapter) => adapter.ecc_len,
+            Err(poisoned) => poisoned.into_inner().ecc_len,
+        }
+    }
+
+    pub fn encode(&self, wp: LinkPacket) -> Result<Vec<u8>> {
+        let ecc_len = self.ecc_len();
+        let header = if self.interleave { ecc_len | INTERLEAVED } else { ecc_len };
+        let mut merged = vec![header; HEADER_LEN];
+        let enc = Encoder::new(ecc_len as usize);
+        let nw = wp.try_to_vec()?;
+        if nw.len() > constants::DATAGRAM_SIZE {
+            return Err(anyhow!("a packet of {} bytes is larger than a datagram", nw.len()));
+        }
+        let mut blocks = vec![];
+        for c in nw.chunks(BLOCK_LEN - ecc_len as usize) {
+            let c = enc.encode(c);
+            blocks.extend(&**c);
+        }
+        if self.interleave {
+            merged.extend(interleave(&blocks));
+        } else {
+            merged.extend(blocks);
+        }
+        Ok(merged)
+    }
+
+    /// Corrects and deserializes a frame made by `encode`, counting what
+    /// it took.
+    pub fn decode(&self, msg: &[u8]) -> Result<LinkPacket, DecodeError> {
+        let decoded = self.correct(msg);
+        if decoded.is_err() {
+            self.counters.dropped.fetch_add(1, Ordering::Relaxed);
+        }
+        decoded
+    }
+
+    fn correct(&self, msg: &[u8]) -> Result<LinkPacket, DecodeError> {
+        let (ecc_len, interleaved) = header(msg).ok_or(DecodeError::Header)?;
+        let blocks = if interleaved {
+            deinterleave(&msg[HEADER_LEN..])
+        } else {
+            msg[HEADER_LEN..].to_vec()
+        };
+        let dec = Decoder::new(ecc_len);
+        let mut reconstituted = vec![];
+        let mut failed = None;
+        let mut worst = Some(0);
+        for (block, chunk) in blocks.chunks(BLOCK_LEN).enumerate() {
+            self.counters.blocks.fetch_add(1, Ordering::Relaxed);
+            if chunk.len() <= ecc_len {
+                failed = failed.or(Some(DecodeError::Truncated { block }));
+                continue;
+            }
+            match dec.correct_err_count(chunk, None) {
+                Ok((data, corrected)) => {
+                    self.counters.corrected.fetch_add(corrected as u64, Ordering::Relaxed);
+                    worst = worst.map(|worst: usize| worst.max(corrected));
+                    reconstituted.extend_from_slice(data.data());
+                },
+                Err(_) => {
+                    self.counters.lost.fetch_add(1, Ordering::Relaxed);
+                    worst = None;
+                    failed = failed.or(Some(DecodeError::Uncorrectable { block }));
+                },
+            }
+        }
+        if let Ok(mut adapter) = self.adapter.lock() {
+            adapter.observe(worst);
+        }
+        if let Some(error) = failed {
+            return Err(error);
+        }
+        LinkPacket::try_from_slice(&reconstituted).map_err(|_| DecodeError::Malformed)
+    }
+
+    pub fn stats(&self) -> LinkStats {
+        LinkStats {
+            blocks: self.counters.blocks.load(Ordering::Relaxed),
+            corrected: self.counters.corrected.load(Ordering::Relaxed),
+            lost: self.counters.lost.load(Ordering::Relaxed),
+            dropped: self.counters.dropped.load(Ordering::Relaxed),
+            ecc_len: self.ecc_len(),
+        }
+    }
+}
+
+// The parity and layout at least two copies of the header agree on.
+fn header(msg: &[u8]) -> Option<(usize, bool)> {
+    match msg {
+        [a, b, c, ..] => {
+            let header = if a == b || a == c { *a } else if b == c { *b } else { return None };
+            let ecc_len = header & !INTERLEAVED;
+            if (MIN_ECC_LEN..=MAX_ECC_LEN).contains(&ecc_len) {
+                Some((ecc_len as usize, header & INTERLEAVED != 0))
+            } else {
+                None
+            }
+        },
+        _ => None,
+    }
+}
+
+// Byte `i` of every block, for each `i` in turn. Every block is
+// `BLOCK_LEN` long but the last, so the lengths follow from the total.
+fn interleave(blocks: &[u8]) -> Vec<u8> {
+    let mut interleaved = Vec::with_capacity(blocks.len());
+    for i in 0..BLOCK_LEN {
+        interleaved.extend(blocks.chunks(BLOCK_LEN).filter_map(|block| block.get(i)));
+    }
+    interleaved
+}
+
+fn deinterleave(interleaved: &[u8]) -> Vec<u8> {
+    let mut blocks = vec![0; interleaved.len()];
+    let mut bytes = interleaved.iter();
+    for i in 0..BLOCK_LEN {
+        for block in blocks.chunks_mut(BLOCK_LEN) {
+            if let Some(slot) = block.get_mut(i) {
+                *slot = bytes.next().copied().unwrap_or_default();
+            }
+        }
+    }
+    blocks
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        copernica_common::{constants, Data, HBFI, NarrowWaistPacket, PrivateIdentity, ReplyTo},
+    };
+
+    fn packet() -> LinkPacket {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi))
+    }
+
+    fn response() -> LinkPacket {
+        let identity = PrivateIdentity::generate();
+        let hbfi = HBFI::new("app", &identity.public_id().to_string()).unwrap();
+        let data = Data { len: constants::FRAGMENT_SIZE, data: [7; constants::FRAGMENT_SIZE as usize] };
+        let nw = NarrowWaistPacket::signed_response(&identity, hbfi, data, 0, 1, vec![]).unwrap();
+        LinkPacket::new(ReplyTo::Mpsc, nw)
+    }
+
+    fn largest_response() -> LinkPacket {
+        let identity = PrivateIdentity::generate();
+        let hbfi = HBFI::new("app", &identity.public_id().to_string()).unwrap().offset(u64::MAX);
+        let data = Data { len: constants::FRAGMENT_SIZE, data: [7; constants::FRAGMENT_SIZE as usize] };
+        let proof = vec![[7; 32]; constants::MAX_PROOF_DEPTH as usize];
+        let nw = NarrowWaistPacket::signed_response(&identity, hbfi, data, u64::MAX, u64::MAX, proof).unwrap();
+        LinkPacket::new(ReplyTo::UdpIp("[::1]:50000".parse().unwrap()), nw)
+    }
+
+    fn flip(enc: &mut [u8], bytes: std::ops::Range<usize>) {
+        for byte in &mut enc[bytes] {
+            *byte = !*byte;
+        }
+    }
+
+    #[test]
+    fn counts_corrected_bytes() {
+        let codec = Codec::default();
+        let mut enc = codec.encode(packet()).unwrap();
+        flip(&mut enc, 4..10);
+        assert!(codec.decode(&enc).is_ok());
+        let stats = codec.stats();
+        assert_eq!(stats.corrected, 6);
+        assert_eq!(stats.lost, 0);
+        assert!(stats.blocks >= 1);
+    }
+
+    #[test]
+    fn uncorrectable_blocks_are_errors() {
+        let codec = Codec::default();
+        let mut enc = codec.encode(packet()).unwrap();
+        flip(&mut enc, HEADER_LEN..HEADER_LEN + ECC_LEN as usize);
+        assert_eq!(codec.decode(&enc).unwrap_err(), DecodeError::Uncorrectable { block: 0 });
+        assert_eq!(codec.decode(&enc[..HEADER_LEN + ECC_LEN as usize]).unwrap_err(), DecodeError::Truncated { block: 0 });
+        let stats = codec.stats();
+        assert_eq!(stats.lost, 1);
+        assert_eq!(stats.dropped, 2);
+    }
+
+    #[test]
+    fn the_header_names_the_parity() {
+        let strong = Codec::new(Fec::Fixed(32));
+        let weak = Codec::new(Fec::Fixed(4));
+        let mut enc = strong.encode(packet()).unwrap();
+        enc[1] = 0;
+        flip(&mut enc, 10..20);
+        assert!(weak.decode(&enc).is_ok());
+        assert!(weak.encode(packet()).unwrap().len() < enc.len());
+        enc[0] = 0;
+        assert_eq!(weak.decode(&enc).unwrap_err(), DecodeError::Header);
+    }
+
+    #[test]
+    fn adaptive_parity_follows_the_errors() {
+        let sender = Codec::new(Fec::Fixed(MAX_ECC_LEN));
+        let codec = Codec::new(Fec::Adaptive { min: 4, max: 32 });
+        assert_eq!(codec.stats().ecc_len, 4);
+        let mut enc = sender.encode(packet()).unwrap();
+        flip(&mut enc, 4..9);
+        codec.decode(&enc).unwrap();
+        assert_eq!(codec.stats().ecc_len, 20);
+        let clean = sender.encode(packet()).unwrap();
+        for _ in 0..ADAPT_WINDOW {
+            codec.decode(&clean).unwrap();
+        }
+        assert_eq!(codec.stats().ecc_len, 18);
+        let mut enc = sender.encode(packet()).unwrap();
+        flip(&mut enc, 3..40);
+        assert!(codec.decode(&enc).is_err());
+        assert_eq!(codec.stats().ecc_len, 32);
+        assert_eq!(Codec::new(Fec::Fixed(200)).stats().ecc_len, MAX_ECC_LEN);
+    }
+
+    #[test]
+    fn interleaving_spreads_bursts() {
+        let plain = Codec::default();
+        let interleaved = Codec::default().interleave(true);
+        let blocks = plain.encode(response()).unwrap().len() / BLOCK_LEN;
+        assert!(blocks >= 4);
+        let burst = HEADER_LEN..HEADER_LEN + blocks * ECC_LEN as usize / 2;
+        let mut enc = interleaved.encode(response()).unwrap();
+        flip(&mut enc, burst.clone());
+        assert!(interleaved.decode(&enc).is_ok());
+        assert_eq!(interleaved.stats().corrected as usize, burst.len());
+        let mut enc = plain.encode(response()).unwrap();
+        flip(&mut enc, burst);
+        assert!(plain.decode(&enc).is_err());
+        let bytes: Vec<u8> = (0..1000).map(|i| i as u8).collect();
+        assert_eq!(deinterleave(&interleave(&bytes)), bytes);
+    }
+
+    #[test]
+    fn the_largest_response_fits_a_frame() {
+        for ecc_len in MIN_ECC_LEN..=MAX_ECC_LEN {
+            for interleave in [false, true] {
+                let codec = Codec::new(Fec::Fixed(ecc_len)).interleave(interleave);
+                let enc = codec.encode(largest_response()).unwrap();
+                assert!(enc.len() <= MAX_FRAME_LEN);
+                assert!(codec.decode(&enc).unwrap().narrow_waist().verify());
+            }
+        }
+        let enc = Codec::default().encode(largest_response()).unwrap();
+        assert!(enc.len() <= constants::DATAGRAM_SIZE);
+        let mut too_large = largest_response();
+        if let NarrowWaistPacket::Response { proof, .. } = &mut too_large.nw {
+            proof.push([7; 32]);
+            proof.push([7; 32]);
+            proof.push([7; 32]);
+        }
+        assert!(Codec::default().encode(too_large).is_err());
+    }
+}
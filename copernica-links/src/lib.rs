@@ -1,43 +1,140 @@
+mod codec;
 mod udp;
+mod tcp;
 mod mpsc_channel;
-mod mpsc_corruptor;
+mod mpsc_simulator;
 pub use {
+    codec::{Codec, DecodeError, Fec, LinkStats, ECC_LEN, MIN_ECC_LEN, MAX_ECC_LEN, MAX_FRAME_LEN},
     udp::{UdpIp},
+    tcp::{Tcp, RECONNECT_MIN, RECONNECT_MAX},
     mpsc_channel::{MpscChannel},
-    mpsc_corruptor::{MpscCorruptor},
+    mpsc_simulator::{Faults, MpscSimulator},
 };
 
 use {
     copernica_common::{
-        InterLinkPacket, LinkId, LinkPacket
+        InterLinkPacket, LinkId,
     },
-    borsh::{BorshDeserialize, BorshSerialize},
     crossbeam_channel::{Sender, Receiver},
     anyhow::{Result},
-    reed_solomon::{Buffer, Encoder, Decoder},
 };
 
-pub fn decode(msg: Vec<u8>) -> Result<LinkPacket> {
-    let dec = Decoder::new(12);
-    let reconstituted: Vec<_> = msg.chunks(255).map(|c| Buffer::from_slice(c, c.len())).map(|d| dec.correct(&d,None).unwrap()).collect();
-    let reconstituted: Vec<_> = reconstituted.iter().map(|d| d.data()).collect::<Vec<_>>().concat();
-    let wp = LinkPacket::try_from_slice(&reconstituted[..])?;
-    Ok(wp)
+pub trait Link<'a> {
+    fn run(&self) -> Result<()>;
+    fn new(link: LinkId, router_in_and_out: ( Sender<InterLinkPacket> , Receiver<InterLinkPacket> ) ) -> Result<Self> where Self: Sized;
+    /// Reed-Solomon counts of the packets this link received.
+    fn stats(&self) -> LinkStats;
 }
 
-pub fn encode(wp: LinkPacket) -> Result<Vec<u8>> {
-    let mut merged = vec![];
-    let enc = Encoder::new(12);
-    let nw = wp.try_to_vec()?;
-    let cs = nw.chunks(256-13);
-    for c in cs {
-        let c = enc.encode(&c[..]);
-        merged.extend(&**c);
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        copernica_common::{constants, Data, HBFI, LinkPacket, NarrowWaistPacket, PrivateIdentity, ReplyTo},
+        crossbeam_channel::unbounded,
+        std::time::Duration,
+    };
+
+    #[test]
+    fn links_drop_undecodable_packets() {
+        let (t2c_tx, t2c_rx) = unbounded();
+        let (_c2t_tx, c2t_rx) = unbounded();
+        let mut link: MpscChannel = Link::new(LinkId::listen(ReplyTo::Mpsc), (t2c_tx, c2t_rx)).unwrap();
+        let (other_tx, _other_rx) = unbounded();
+        link.female(other_tx);
+        link.run().unwrap();
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let packet = LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi));
+        link.male().send(vec![0xff; 300]).unwrap();
+        link.male().send(Codec::default().encode(packet).unwrap()).unwrap();
+        assert!(t2c_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+        assert_eq!(link.stats().dropped, 1);
     }
-    Ok(merged)
-}
 
-pub trait Link<'a> {
-    fn run(&self) -> Result<()>;
-    fn new(link: LinkId, router_in_and_out: ( Sender<InterLinkPacket> , Receiver<InterLinkPacket> ) ) -> Result<Self> where Self: Sized;
+    #[test]
+    fn interleaving_recovers_long_bursts() {
+        let (t2c_tx, _t2c_rx) = unbounded();
+        let (c2t_tx, c2t_rx) = unbounded();
+        let corruptor: MpscSimulator = Link::new(LinkId::listen(ReplyTo::Mpsc), (t2c_tx, c2t_rx)).unwrap();
+        // Five times what one block's parity corrects, a Response with a
+        // full proof spans six blocks.
+        let faults = Faults::new().seed(1).bursts(1.0, 5 * ECC_LEN as usize / 2);
+        let mut corruptor = corruptor.interleave(true).faults(faults);
+        let (t2c_tx, t2c_rx) = unbounded();
+        let (_c2t_tx, c2t_rx) = unbounded();
+        let mut link: MpscChannel = Link::new(LinkId::listen(ReplyTo::Mpsc), (t2c_tx, c2t_rx)).unwrap();
+        corruptor.female(link.male());
+        link.female(corruptor.male());
+        corruptor.run().unwrap();
+        link.run().unwrap();
+        let identity = PrivateIdentity::generate();
+        let hbfi = HBFI::new("app", &identity.public_id().to_string()).unwrap();
+        let data = Data { len: constants::FRAGMENT_SIZE, data: [7; constants::FRAGMENT_SIZE as usize] };
+        let proof = vec![[7; 32]; constants::MAX_PROOF_DEPTH as usize];
+        let nw = NarrowWaistPacket::signed_response(&identity, hbfi, data, 1, 2, proof).unwrap();
+        let lp = LinkPacket::new(ReplyTo::Mpsc, nw);
+        c2t_tx.send(InterLinkPacket::new(LinkId::listen(ReplyTo::Mpsc), lp)).unwrap();
+        let ilp = t2c_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(ilp.narrow_waist().verify());
+        assert_eq!(link.stats().lost, 0);
+        assert!(link.stats().corrected > ECC_LEN as u64 / 2);
+    }
+
+    fn tcp(listen: &str) -> (Tcp, LinkId, Sender<InterLinkPacket>, Receiver<InterLinkPacket>) {
+        let (t2c_tx, t2c_rx) = unbounded();
+        let (c2t_tx, c2t_rx) = unbounded();
+        let lid = LinkId::listen(ReplyTo::Tcp(listen.parse().unwrap()));
+        let link: Tcp = Link::new(lid.clone(), (t2c_tx, c2t_rx)).unwrap();
+        let link = link.reconnect(Duration::from_millis(10), Duration::from_millis(50));
+        (link, lid, c2t_tx, t2c_rx)
+    }
+
+    fn request() -> LinkPacket {
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        LinkPacket::new(ReplyTo::Mpsc, NarrowWaistPacket::request(hbfi))
+    }
+
+    #[test]
+    fn tcp_links_reconnect() {
+        let (a, lid_a, a_tx, _a_rx) = tcp("127.0.0.1:50130");
+        let (b, _lid_b, _b_tx, b_rx) = tcp("127.0.0.1:50131");
+        a.run().unwrap();
+        let to_b = lid_a.remote(ReplyTo::Tcp("127.0.0.1:50131".parse().unwrap()));
+        a_tx.send(InterLinkPacket::new(to_b.clone(), request())).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        b.run().unwrap();
+        let started = std::time::Instant::now();
+        let ilp = loop {
+            a_tx.send(InterLinkPacket::new(to_b.clone(), request())).unwrap();
+            if let Ok(ilp) = b_rx.recv_timeout(Duration::from_millis(50)) {
+                break ilp;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "never reconnected");
+        };
+        // the packet claims a's listening address, but b knows a by the
+        // address a dialled from
+        assert_eq!(ilp.wire_packet().reply_to(), ReplyTo::Tcp("127.0.0.1:50130".parse().unwrap()));
+        assert_ne!(ilp.reply_to(), ilp.wire_packet().reply_to());
+    }
+
+    #[test]
+    fn tcp_replies_use_the_dialled_connection() {
+        // a cannot listen, as if only allowed to dial out
+        let _taken = std::net::TcpListener::bind("127.0.0.1:50132").unwrap();
+        let (a, lid_a, a_tx, a_rx) = tcp("127.0.0.1:50132");
+        let (b, _lid_b, b_tx, b_rx) = tcp("127.0.0.1:50133");
+        a.run().unwrap();
+        b.run().unwrap();
+        let to_b = lid_a.remote(ReplyTo::Tcp("127.0.0.1:50133".parse().unwrap()));
+        let started = std::time::Instant::now();
+        let ilp = loop {
+            a_tx.send(InterLinkPacket::new(to_b.clone(), request())).unwrap();
+            if let Ok(ilp) = b_rx.recv_timeout(Duration::from_millis(50)) {
+                break ilp;
+            }
+            assert!(started.elapsed() < Duration::from_secs(5), "never connected");
+        };
+        b_tx.send(ilp).unwrap();
+        assert!(a_rx.recv_timeout(Duration::from_secs(5)).is_ok());
+    }
 }
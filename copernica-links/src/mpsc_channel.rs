@@ -1,11 +1,11 @@
 use {
-    crate::{Link, decode, encode},
+    crate::{Link, Codec, Fec, LinkStats},
     copernica_common::{
-        InterLinkPacket, LinkId, ReplyTo
+        InterLinkPacket, LinkId, ReplyTo, StopHandle, STOP_POLL
     },
     anyhow::{anyhow, Result},
-    crossbeam_channel::{Sender, Receiver, unbounded},
-    log::{debug, error, trace},
+    crossbeam_channel::{Sender, Receiver, RecvTimeoutError, unbounded},
+    log::{debug, error, trace, warn},
 };
 
 pub struct MpscChannel {
@@ -13,12 +13,29 @@ pub struct MpscChannel {
     // t = tansport; c = copernic; 0 = this instance of t; 1 = the pair of same type
     t2c_tx: Sender<InterLinkPacket>,
     c2t_rx: Receiver<InterLinkPacket>,
+    codec: Codec,
     t2t0_tx: Sender<Vec<u8>>,        // give
     t2t0_rx: Receiver<Vec<u8>>,      // keep
     t2t1_tx: Option<Vec<Sender<Vec<u8>>>>,
+    stop: StopHandle,
 }
 
 impl MpscChannel {
+    /// The parity this link adds to what it sends.
+    pub fn fec(mut self, fec: Fec) -> Self {
+        self.codec = self.codec.fec(fec);
+        self
+    }
+    /// Whether this link interleaves the blocks of what it sends.
+    pub fn interleave(mut self, interleave: bool) -> Self {
+        self.codec = self.codec.interleave(interleave);
+        self
+    }
+    /// Stops this link's threads when `stop` is.
+    pub fn stop_handle(mut self, stop: StopHandle) -> Self {
+        self.stop = stop;
+        self
+    }
     pub fn male(&self) -> Sender<Vec<u8>> {
         self.t2t0_tx.clone()
     }
@@ -44,33 +61,48 @@ impl<'a> Link<'a> for MpscChannel {
                         link_id,
                         t2c_tx,
                         c2t_rx,
+                        codec: Codec::default(),
                         t2t0_tx,
                         t2t0_rx,
                         t2t1_tx: None,
+                        stop: StopHandle::new(),
                     })
             }
             _ => return Err(anyhow!("MpscChannel Link expects a LinkId of type LinkId::Mpsc")),
         }
     }
 
+    fn stats(&self) -> LinkStats {
+        self.codec.stats()
+    }
+
     #[allow(unreachable_code)]
     fn run(&self) -> Result<()> {
         let this_link = self.link_id.clone();
         trace!("Started {:?}:", this_link);
         let t2t0_rx = self.t2t0_rx.clone();
         let t2c_tx = self.t2c_tx.clone();
-        std::thread::spawn(move || {
+        let codec = self.codec.clone();
+        let stop = self.stop.clone();
+        self.stop.spawn(move || {
             match this_link.reply_to() {
                 ReplyTo::Mpsc => {
-                    loop {
-                        match t2t0_rx.recv(){
+                    while !stop.is_stopped() {
+                        match t2t0_rx.recv_timeout(STOP_POLL) {
                             Ok(msg) => {
-                                let wp = decode(msg)?;
+                                let wp = match codec.decode(&msg) {
+                                    Ok(wp) => wp,
+                                    Err(error) => {
+                                        warn!("{:?}: dropping packet: {}", this_link, error);
+                                        continue;
+                                    },
+                                };
                                 let link_id = LinkId::new(this_link.nonce(), wp.reply_to());
                                 let ilp = InterLinkPacket::new(link_id, wp.clone());
                                 debug!("MpscChannel Recv on {:?} => {:?}", this_link, wp);
                                 let _r = t2c_tx.send(ilp)?;
                             },
+                            Err(RecvTimeoutError::Timeout) => {},
                             Err(error) => error!("{:?}: {}", this_link, error),
                         };
                     }
@@ -82,18 +114,21 @@ impl<'a> Link<'a> for MpscChannel {
 
         let this_link = self.link_id.clone();
         let c2t_rx = self.c2t_rx.clone();
+        let codec = self.codec.clone();
+        let stop = self.stop.clone();
         if let Some(t2t1_tx) = self.t2t1_tx.clone() {
-            std::thread::spawn(move || {
-                loop {
-                    match c2t_rx.recv(){
+            self.stop.spawn(move || {
+                while !stop.is_stopped() {
+                    match c2t_rx.recv_timeout(STOP_POLL) {
                         Ok(ilp) => {
                             let wp = ilp.wire_packet().change_origination(this_link.reply_to());
-                            let enc = encode(wp.clone())?;
+                            let enc = codec.encode(wp.clone())?;
                             for s in t2t1_tx.clone() {
                                 debug!("MpscChannel Send on {:?} => {:?}", this_link, wp);
                                 s.send(enc.clone())?;
                             }
                         },
+                        Err(RecvTimeoutError::Timeout) => {},
                         Err(error) => error!("{:?}: {}", this_link, error),
                     }
                 }
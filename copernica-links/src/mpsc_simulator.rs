@@ -0,0 +1,356 @@
+use {
+    crate::{Link, Codec, Fec, LinkStats},
+    copernica_common::{
+        InterLinkPacket, LinkId, ReplyTo
+    },
+    anyhow::{anyhow, Result},
+    crossbeam_channel::{Sender, Receiver, RecvTimeoutError, unbounded},
+    log::{debug, error, trace, warn},
+    rand::{Rng, SeedableRng, rngs::StdRng},
+    std::{
+        cmp::Reverse,
+        collections::BinaryHeap,
+        time::{Duration, Instant},
+    },
+};
+
+/// The network conditions an `MpscSimulator` imposes on what it sends.
+/// The same seed and traffic give the same faults. No faults unless set.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Faults {
+    seed: u64,
+    loss: f64,
+    duplication: f64,
+    reordering: f64,
+    reorder_delay: Duration,
+    latency: Duration,
+    jitter: Duration,
+    bandwidth: Option<u64>,
+    bit_errors: f64,
+    bursts: f64,
+    burst_len: usize,
+}
+
+impl Default for Faults {
+    fn default() -> Self {
+        Self {
+            seed: 0,
+            loss: 0.0,
+            duplication: 0.0,
+            reordering: 0.0,
+            reorder_delay: Duration::from_millis(0),
+            latency: Duration::from_millis(0),
+            jitter: Duration::from_millis(0),
+            bandwidth: None,
+            bit_errors: 0.0,
+            bursts: 0.0,
+            burst_len: 0,
+        }
+    }
+}
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = seed;
+        self
+    }
+
+    /// The chance a packet is never delivered.
+    pub fn loss(mut self, probability: f64) -> Self {
+        self.loss = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The chance a packet is delivered twice.
+    pub fn duplication(mut self, probability: f64) -> Self {
+        self.duplication = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The chance a packet is held back by `delay`, letting those sent
+    /// meanwhile overtake it.
+    pub fn reordering(mut self, probability: f64, delay: Duration) -> Self {
+        self.reordering = probability.clamp(0.0, 1.0);
+        self.reorder_delay = delay;
+        self
+    }
+
+    /// Every packet is delayed by `latency` plus up to `jitter` more.
+    pub fn latency(mut self, latency: Duration, jitter: Duration) -> Self {
+        self.latency = latency;
+        self.jitter = jitter;
+        self
+    }
+
+    /// Bytes per second; packets queue behind one another to go out.
+    pub fn bandwidth(mut self, bytes_per_second: u64) -> Self {
+        self.bandwidth = Some(bytes_per_second.max(1));
+        self
+    }
+
+    /// The chance each bit is flipped.
+    pub fn bit_errors(mut self, probability: f64) -> Self {
+        self.bit_errors = probability.clamp(0.0, 1.0);
+        self
+    }
+
+    /// The chance a packet has a run of `len` corrupt bytes somewhere in it.
+    pub fn bursts(mut self, probability: f64, len: usize) -> Self {
+        self.bursts = probability.clamp(0.0, 1.0);
+        self.burst_len = len;
+        self
+    }
+}
+
+// Applies `faults` to packets in the order they are sent, deciding when,
+// if ever, and how damaged each is delivered.
+struct Impairment {
+    faults: Faults,
+    rng: StdRng,
+    // When the last packet finishes going out at `bandwidth`.
+    busy_until: Instant,
+}
+
+impl Impairment {
+    fn new(faults: Faults) -> Self {
+        Self { faults, rng: StdRng::seed_from_u64(faults.seed), busy_until: Instant::now() }
+    }
+
+    fn deliveries(&mut self, mut packet: Vec<u8>, now: Instant) -> Vec<(Instant, Vec<u8>)> {
+        if self.rng.gen_bool(self.faults.loss) {
+            return vec![];
+        }
+        self.corrupt(&mut packet);
+        let copies = if self.rng.gen_bool(self.faults.duplication) { 2 } else { 1 };
+        let mut deliveries = vec![];
+        for _ in 0..copies {
+            let mut due = now;
+            if let Some(bandwidth) = self.faults.bandwidth {
+                let sending = Duration::from_secs_f64(packet.len() as f64 / bandwidth as f64);
+                self.busy_until = self.busy_until.max(now) + sending;
+                due = self.busy_until;
+            }
+            due += self.faults.latency + self.faults.jitter.mul_f64(self.rng.gen::<f64>());
+            if self.rng.gen_bool(self.faults.reordering) {
+                due += self.faults.reorder_delay;
+            }
+            deliveries.push((due, packet.clone()));
+        }
+        deliveries
+    }
+
+    fn corrupt(&mut self, packet: &mut [u8]) {
+        if self.faults.bit_errors > 0.0 {
+            for byte in packet.iter_mut() {
+                for bit in 0..8 {
+                    if self.rng.gen_bool(self.faults.bit_errors) {
+                        *byte ^= 1 << bit;
+                    }
+                }
+            }
+        }
+        if !packet.is_empty() && self.rng.gen_bool(self.faults.bursts) {
+            let start = self.rng.gen_range(0, packet.len());
+            for byte in packet.iter_mut().skip(start).take(self.faults.burst_len) {
+                *byte ^= self.rng.gen_range(1, 256) as u8;
+            }
+        }
+    }
+}
+
+/// An `MpscChannel` that sends through a simulated network, for testing
+/// how brokers and services cope with `Faults`.
+pub struct MpscSimulator {
+    link_id: LinkId,
+    // t = tansport; c = copernic; 0 = this instance of t; 1 = the pair of same type
+    t2c_tx: Sender<InterLinkPacket>,
+    c2t_rx: Receiver<InterLinkPacket>,
+    codec: Codec,
+    faults: Faults,
+    t2t0_tx: Sender<Vec<u8>>,        // give
+    t2t0_rx: Receiver<Vec<u8>>,      // keep
+    t2t1_tx: Option<Vec<Sender<Vec<u8>>>>,
+}
+
+impl MpscSimulator {
+    /// The parity this link adds to what it sends.
+    pub fn fec(mut self, fec: Fec) -> Self {
+        self.codec = self.codec.fec(fec);
+        self
+    }
+    /// Whether this link interleaves the blocks of what it sends.
+    pub fn interleave(mut self, interleave: bool) -> Self {
+        self.codec = self.codec.interleave(interleave);
+        self
+    }
+    /// The network conditions this link sends through.
+    pub fn faults(mut self, faults: Faults) -> Self {
+        self.faults = faults;
+        self
+    }
+    pub fn male(&self) -> Sender<Vec<u8>> {
+        self.t2t0_tx.clone()
+    }
+    pub fn female(&mut self, new_t2t1_tx: Sender<Vec<u8>>) {
+        if self.t2t1_tx.is_none() {
+            self.t2t1_tx = Some(vec![]);
+        }
+        if let Some(t2t1_tx) = &mut self.t2t1_tx {
+            t2t1_tx.push(new_t2t1_tx);
+        }
+    }
+}
+
+impl<'a> Link<'a> for MpscSimulator {
+    fn new(link_id: LinkId
+        , (t2c_tx, c2t_rx): ( Sender<InterLinkPacket> , Receiver<InterLinkPacket> )
+        ) -> Result<MpscSimulator> {
+        match link_id.reply_to() {
+            ReplyTo::Mpsc => {
+                let (t2t0_tx, t2t0_rx) = unbounded::<Vec<u8>>();
+                Ok(
+                    MpscSimulator {
+                        link_id,
+                        t2c_tx,
+                        c2t_rx,
+                        codec: Codec::default(),
+                        faults: Faults::default(),
+                        t2t0_tx,
+                        t2t0_rx,
+                        t2t1_tx: None,
+                    })
+            }
+            _ => Err(anyhow!("MpscSimulator Link expects a LinkId of type LinkId::Mpsc")),
+        }
+    }
+
+    fn stats(&self) -> LinkStats {
+        self.codec.stats()
+    }
+
+    #[allow(unreachable_code)]
+    fn run(&self) -> Result<()> {
+        let this_link = self.link_id.clone();
+        trace!("Started {:?}:", this_link);
+        let t2t0_rx = self.t2t0_rx.clone();
+        let t2c_tx = self.t2c_tx.clone();
+        let codec = self.codec.clone();
+        std::thread::spawn(move || {
+            loop {
+                match t2t0_rx.recv(){
+                    Ok(msg) => {
+                        let wp = match codec.decode(&msg) {
+                            Ok(wp) => wp,
+                            Err(error) => {
+                                warn!("{:?}: dropping packet: {}", this_link, error);
+                                continue;
+                            },
+                        };
+                        let link_id = LinkId::new(this_link.nonce(), wp.reply_to());
+                        let ilp = InterLinkPacket::new(link_id, wp.clone());
+                        debug!("MpscSimulator Recv on {:?} => {:?}", this_link, wp);
+                        t2c_tx.send(ilp)?;
+                    },
+                    Err(error) => error!("{:?}: {}", this_link, error),
+                };
+            }
+            Ok::<(), anyhow::Error>(())
+        });
+
+        let this_link = self.link_id.clone();
+        let c2t_rx = self.c2t_rx.clone();
+        let codec = self.codec.clone();
+        let mut impairment = Impairment::new(self.faults);
+        if let Some(t2t1_tx) = self.t2t1_tx.clone() {
+            std::thread::spawn(move || {
+                // packets in flight, by when they arrive and then in the
+                // order they were sent
+                let mut in_flight: BinaryHeap<Reverse<(Instant, u64, Vec<u8>)>> = BinaryHeap::new();
+                let mut sent: u64 = 0;
+                loop {
+                    let now = Instant::now();
+                    while let Some(Reverse((due, _, _))) = in_flight.peek() {
+                        if *due > now {
+                            break;
+                        }
+                        if let Some(Reverse((_, _, packet))) = in_flight.pop() {
+                            for s in t2t1_tx.clone() {
+                                s.send(packet.clone())?;
+                            }
+                        }
+                    }
+                    let wait = match in_flight.peek() {
+                        Some(Reverse((due, _, _))) => due.saturating_duration_since(now),
+                        None => Duration::from_secs(1),
+                    };
+                    match c2t_rx.recv_timeout(wait) {
+                        Ok(ilp) => {
+                            let wp = ilp.wire_packet().change_origination(this_link.reply_to());
+                            debug!("MpscSimulator Send on {:?} => {:?}", this_link, wp);
+                            let enc = codec.encode(wp)?;
+                            for (due, packet) in impairment.deliveries(enc, Instant::now()) {
+                                in_flight.push(Reverse((due, sent, packet)));
+                                sent += 1;
+                            }
+                        },
+                        Err(RecvTimeoutError::Timeout) => {},
+                        Err(error) => error!("{:?}: {}", this_link, error),
+                    }
+                }
+                Ok::<(), anyhow::Error>(())
+            });
+        } else {
+            return Err(anyhow!("You need to bind the transports before using them, i.e. t0.female(t1.male()); followed by: t1.female(t0.male());"))
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deliver(faults: Faults, packets: usize) -> Vec<(Instant, Vec<u8>)> {
+        let mut impairment = Impairment::new(faults);
+        let now = Instant::now();
+        (0..packets).flat_map(|i| impairment.deliveries(vec![i as u8; 100], now)).collect()
+    }
+
+    fn delivered(faults: Faults, packets: usize) -> Vec<Vec<u8>> {
+        deliver(faults, packets).into_iter().map(|(_, packet)| packet).collect()
+    }
+
+    #[test]
+    fn faults_are_reproducible() {
+        let faults = Faults::new().seed(7).loss(0.2).duplication(0.1).bit_errors(0.01).bursts(0.5, 8)
+            .latency(Duration::from_millis(5), Duration::from_millis(5));
+        let once = delivered(faults, 100);
+        assert_eq!(once, delivered(faults, 100));
+        assert_ne!(once, delivered(faults.seed(8), 100));
+        assert!(once.len() > 70 && once.len() < 100);
+        assert!(once.iter().any(|packet| packet.iter().any(|byte| *byte != packet[0])));
+        assert_eq!(delivered(Faults::new(), 100), (0..100).map(|i| vec![i as u8; 100]).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn bandwidth_queues_packets() {
+        let packets = deliver(Faults::new().bandwidth(10_000).latency(Duration::from_millis(10), Duration::from_millis(0)), 10);
+        let first = packets.first().unwrap().0;
+        let last = packets.last().unwrap().0;
+        // 100 bytes at 10kB/s take 10ms each
+        assert_eq!(last - first, Duration::from_millis(90));
+    }
+
+    #[test]
+    fn reordered_packets_are_overtaken() {
+        let packets = deliver(Faults::new().seed(3).reordering(0.5, Duration::from_millis(50)), 20);
+        let sent = packets.iter().map(|(due, _)| *due).min().unwrap();
+        let held = packets.iter().filter(|(due, _)| *due == sent + Duration::from_millis(50)).count();
+        assert!(held > 0 && held < 20);
+    }
+}
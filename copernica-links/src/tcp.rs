@@ -0,0 +1,405 @@
+use {
+    crate::{Link, Codec, Fec, LinkStats, MAX_FRAME_LEN},
+    copernica_common::{
+        InterLinkPacket, LinkId, ReplyTo, StopHandle, STOP_POLL
+    },
+    anyhow::{anyhow, Result},
+    crossbeam_channel::{Sender, Receiver, RecvTimeoutError, TrySendError, bounded},
+    log::{debug, error, trace, warn},
+    std::{
+        collections::HashMap,
+        io::{self, Read, Write},
+        net::{SocketAddr, TcpListener, TcpStream},
+        sync::{Arc, Mutex, MutexGuard},
+        time::{Duration, Instant},
+    },
+};
+
+/// The first wait before reconnecting to an unreachable peer.
+pub const RECONNECT_MIN: Duration = Duration::from_millis(100);
+/// The longest wait between attempts; each failure doubles the wait up to this.
+pub const RECONNECT_MAX: Duration = Duration::from_secs(10);
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Frames waiting for a peer beyond this many are dropped, as they are
+// while it is unreachable.
+const QUEUE_LEN: usize = 256;
+
+// The queue of frames for each peer's writer, by the address we dialled
+// it on or the one it dialled us from.
+type Connections = Arc<Mutex<HashMap<SocketAddr, Sender<Vec<u8>>>>>;
+// When each unreachable peer may be tried again, and the wait after that.
+type Backoff = Arc<Mutex<HashMap<SocketAddr, (Instant, Duration)>>>;
+
+/// A link over TCP. It listens on the address in its `LinkId` and sends to
+/// the address its packets are for, connecting on first use and
+/// reconnecting with backoff when a connection fails; packets sent while
+/// a peer is unreachable are dropped. Each peer has its own writer, so a
+/// slow or unreachable one holds up no other. Packets arriving over a
+/// connection a peer opened are known by the address it connected from,
+/// not the one they claim, so replies travel back over that connection,
+/// a node that can only dial out still hears from the nodes it dials, and
+/// no peer can divert the traffic meant for another.
+pub struct Tcp {
+    link_id: LinkId,
+    t2c_tx: Sender<InterLinkPacket>,
+    c2t_rx: Receiver<InterLinkPacket>,
+    codec: Codec,
+    reconnect: (Duration, Duration),
+    listener: Option<TcpListener>,
+    stop: StopHandle,
+}
+
+impl Tcp {
+    /// The parity this link adds to what it sends.
+    pub fn fec(mut self, fec: Fec) -> Self {
+        self.codec = self.codec.fec(fec);
+        self
+    }
+    /// Whether this link interleaves the blocks of what it sends.
+    pub fn interleave(mut self, interleave: bool) -> Self {
+        self.codec = self.codec.interleave(interleave);
+        self
+    }
+    /// The first and longest waits before reconnecting to a peer.
+    pub fn reconnect(mut self, min: Duration, max: Duration) -> Self {
+        self.reconnect = (min, max.max(min));
+        self
+    }
+    /// Accepts on `listener`, already bound to the listen address, rather
+    /// than binding it when run.
+    pub fn listener(mut self, listener: TcpListener) -> Self {
+        self.listener = Some(listener);
+        self
+    }
+    /// Stops this link's threads, and closes its connections, when `stop` is.
+    pub fn stop_handle(mut self, stop: StopHandle) -> Self {
+        self.stop = stop;
+        self
+    }
+}
+
+impl Link<'_> for Tcp {
+    fn new(link_id: LinkId
+        , (t2c_tx, c2t_rx): ( Sender<InterLinkPacket> , Receiver<InterLinkPacket> )
+        ) -> Result<Tcp>
+    {
+        trace!("LISTEN ON {:?}:", link_id);
+        match link_id.reply_to() {
+            ReplyTo::Tcp(_) => Ok(Tcp {
+                link_id,
+                t2c_tx,
+                c2t_rx,
+                codec: Codec::default(),
+                reconnect: (RECONNECT_MIN, RECONNECT_MAX),
+                listener: None,
+                stop: StopHandle::new(),
+            }),
+            _ => Err(anyhow!("Tcp Link expects a LinkId of type Link.ReplyTo::Tcp(...)")),
+        }
+    }
+
+    fn stats(&self) -> LinkStats {
+        self.codec.stats()
+    }
+
+    fn run(&self) -> Result<()> {
+        let listen_addr = match self.link_id.reply_to() {
+            ReplyTo::Tcp(listen_addr) => listen_addr,
+            _ => return Err(anyhow!("Tcp Link expects a LinkId of type Link.ReplyTo::Tcp(...)")),
+        };
+        let shared = Shared {
+            this_link: self.link_id.clone(),
+            t2c_tx: self.t2c_tx.clone(),
+            codec: self.codec.clone(),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            backoff: Arc::new(Mutex::new(HashMap::new())),
+            reconnect: self.reconnect,
+            stop: self.stop.clone(),
+        };
+        let bound = match &self.listener {
+            Some(listener) => Some(listener.try_clone()?),
+            None => None,
+        };
+        let accepting = shared.clone();
+        self.stop.spawn(move || {
+            let this_link = &accepting.this_link;
+            // accepting without blocking lets the thread notice it's stopped
+            let listener = bound
+                .map_or_else(|| TcpListener::bind(listen_addr), Ok)
+                .and_then(|listener| listener.set_nonblocking(true).map(|_| listener));
+            match listener {
+                Ok(listener) => {
+                    while !accepting.stop.is_stopped() {
+                        let accepted = listener.accept().and_then(|(stream, remote_addr)| {
+                            stream.set_nonblocking(false)?;
+                            configure(&stream)?;
+                            Ok((remote_addr, stream))
+                        });
+                        match accepted {
+                            Ok((remote_addr, stream)) => {
+                                let (tx, rx) = bounded(QUEUE_LEN);
+                                lock(&accepting.connections).insert(remote_addr, tx.clone());
+                                let serving = accepting.clone();
+                                accepting.stop.spawn(move || serving.serve(remote_addr, stream, tx, rx));
+                            },
+                            Err(error) if error.kind() == io::ErrorKind::WouldBlock => std::thread::sleep(STOP_POLL),
+                            Err(error) => error!("{:?}: {}", this_link, error),
+                        }
+                    }
+                },
+                Err(error) => error!("{:?}: {}", this_link, error),
+            }
+        });
+
+        let c2t_rx = self.c2t_rx.clone();
+        self.stop.spawn(move || {
+            let this_link = &shared.this_link;
+            while !shared.stop.is_stopped() {
+                let ilp = match c2t_rx.recv_timeout(STOP_POLL) {
+                    Ok(ilp) => ilp,
+                    Err(RecvTimeoutError::Timeout) => continue,
+                    Err(error) => {
+                        error!("{:?}: {}", this_link, error);
+                        break;
+                    },
+                };
+                let remote_addr = match ilp.reply_to() {
+                    ReplyTo::Tcp(remote_addr) => remote_addr,
+                    _ => continue,
+                };
+                let wp = ilp.wire_packet().change_origination(this_link.reply_to());
+                debug!("Tcp Send on {:?} => {:?}", this_link, wp);
+                let enc = match shared.codec.encode(wp) {
+                    Ok(enc) => enc,
+                    Err(error) => {
+                        warn!("{:?}: dropping packet for {}: {}", this_link, remote_addr, error);
+                        continue;
+                    },
+                };
+                let tx = lock(&shared.connections).get(&remote_addr).cloned();
+                let tx = match tx {
+                    Some(tx) => tx,
+                    None => {
+                        if let Some((retry_at, _)) = lock(&shared.backoff).get(&remote_addr) {
+                            if Instant::now() < *retry_at {
+                                debug!("{:?}: dropping packet for unreachable {}", this_link, remote_addr);
+                                continue;
+                            }
+                        }
+                        shared.dial(remote_addr)
+                    },
+                };
+                match tx.try_send(enc) {
+                    Ok(()) => {},
+                    Err(TrySendError::Full(_)) => debug!("{:?}: dropping packet for {}, its queue is full", this_link, remote_addr),
+                    Err(TrySendError::Disconnected(_)) => {
+                        debug!("{:?}: dropping packet for {}, its connection closed", this_link, remote_addr);
+                        forget(&shared.connections, remote_addr, &tx);
+                    },
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+// What the listening, sending, reading and writing threads of a link share.
+#[derive(Clone)]
+struct Shared {
+    this_link: LinkId,
+    t2c_tx: Sender<InterLinkPacket>,
+    codec: Codec,
+    connections: Connections,
+    backoff: Backoff,
+    reconnect: (Duration, Duration),
+    stop: StopHandle,
+}
+
+impl Shared {
+    // Queues frames for `remote_addr` while connecting to it in the
+    // background, so that an unreachable peer holds up no other.
+    fn dial(&self, remote_addr: SocketAddr) -> Sender<Vec<u8>> {
+        let (tx, rx) = bounded(QUEUE_LEN);
+        lock(&self.connections).insert(remote_addr, tx.clone());
+        let dialling = self.clone();
+        let queued = tx.clone();
+        self.stop.spawn(move || {
+            match connect(remote_addr) {
+                Ok(_) if dialling.stop.is_stopped() => {},
+                Ok(stream) => {
+                    lock(&dialling.backoff).remove(&remote_addr);
+                    dialling.serve(remote_addr, stream, queued, rx);
+                },
+                Err(error) => {
+                    let (reconnect_min, reconnect_max) = dialling.reconnect;
+                    let mut backoff = lock(&dialling.backoff);
+                    let wait = match backoff.get(&remote_addr) {
+                        Some((_, wait)) => (*wait * 2).min(reconnect_max),
+                        None => reconnect_min,
+                    };
+                    warn!("{:?}: cannot reach {}, retrying in {:?}: {}", dialling.this_link, remote_addr, wait, error);
+                    backoff.insert(remote_addr, (Instant::now() + wait, wait));
+                    drop(backoff);
+                    forget(&dialling.connections, remote_addr, &queued);
+                },
+            }
+        });
+        tx
+    }
+
+    // Writes the frames queued on `rx` to `stream` while another thread
+    // reads from it, until either side fails, the queue is forgotten or
+    // the link is stopped.
+    fn serve(self, remote_addr: SocketAddr, stream: TcpStream, tx: Sender<Vec<u8>>, rx: Receiver<Vec<u8>>) {
+        match stream.try_clone() {
+            Ok(reader) => {
+                let reading = self.clone();
+                self.stop.spawn(move || reading.receive(remote_addr, reader, tx));
+            },
+            Err(error) => {
+                warn!("{:?}: lost connection to {}: {}", self.this_link, remote_addr, error);
+                forget(&self.connections, remote_addr, &tx);
+                return;
+            },
+        }
+        while !self.stop.is_stopped() {
+            let frame = match rx.recv_timeout(STOP_POLL) {
+                Ok(frame) => frame,
+                Err(RecvTimeoutError::Timeout) => continue,
+                Err(RecvTimeoutError::Disconnected) => break,
+            };
+            if let Err(error) = write_frame(&stream, &frame) {
+                warn!("{:?}: lost connection to {}: {}", self.this_link, remote_addr, error);
+                break;
+            }
+        }
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+
+    // Reads frames off `stream` until it closes, then forgets the queue
+    // `tx` of its writer. Packets are known to come from `remote_addr`,
+    // whatever address they claim to reply to.
+    fn receive(self, remote_addr: SocketAddr, stream: TcpStream, tx: Sender<Vec<u8>>) {
+        let this_link = &self.this_link;
+        let mut reader = &stream;
+        loop {
+            let msg = match read_frame(&mut reader) {
+                Ok(msg) => msg,
+                Err(error) => {
+                    debug!("{:?}: connection closed: {}", this_link, error);
+                    break;
+                },
+            };
+            let wp = match self.codec.decode(&msg) {
+                Ok(wp) => wp,
+                Err(error) => {
+                    warn!("{:?}: dropping packet: {}", this_link, error);
+                    continue;
+                },
+            };
+            debug!("Tcp Recv on {:?} => {:?}", this_link, wp);
+            let link_id = LinkId::new(this_link.nonce(), ReplyTo::Tcp(remote_addr));
+            let ilp = InterLinkPacket::new(link_id, wp);
+            if self.t2c_tx.send(ilp).is_err() {
+                break;
+            }
+        }
+        forget(&self.connections, remote_addr, &tx);
+        let _ = stream.shutdown(std::net::Shutdown::Both);
+    }
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    match mutex.lock() {
+        Ok(guard) => guard,
+        Err(poisoned) => poisoned.into_inner(),
+    }
+}
+
+fn connect(remote_addr: SocketAddr) -> io::Result<TcpStream> {
+    let stream = TcpStream::connect_timeout(&remote_addr, CONNECT_TIMEOUT)?;
+    configure(&stream)?;
+    Ok(stream)
+}
+
+// A peer that stops reading mustn't hold its writer for long.
+fn configure(stream: &TcpStream) -> io::Result<()> {
+    stream.set_nodelay(true)?;
+    stream.set_write_timeout(Some(CONNECT_TIMEOUT))
+}
+
+// Drops the queue for `remote_addr` if it is still `tx`, leaving any newer
+// one alone. Its writer stops once the last sender is gone.
+fn forget(connections: &Connections, remote_addr: SocketAddr, tx: &Sender<Vec<u8>>) {
+    let mut connections = lock(connections);
+    if connections.get(&remote_addr).is_some_and(|known| known.same_channel(tx)) {
+        connections.remove(&remote_addr);
+    }
+}
+
+fn write_frame(mut stream: &TcpStream, frame: &[u8]) -> io::Result<()> {
+    let mut buf = Vec::with_capacity(4 + frame.len());
+    buf.extend_from_slice(&(frame.len() as u32).to_be_bytes());
+    buf.extend_from_slice(frame);
+    stream.write_all(&buf)
+}
+
+// Frames are length prefixed; anything claiming to be longer than the
+// codec ever makes is garbage, and is refused before allocating for it.
+fn read_frame(stream: &mut impl Read) -> io::Result<Vec<u8>> {
+    let mut len = [0u8; 4];
+    stream.read_exact(&mut len)?;
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, format!("frame of {} bytes is too large", len)));
+    }
+    let mut frame = vec![0u8; len];
+    stream.read_exact(&mut frame)?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use {
+        super::*,
+        copernica_common::{HBFI, LinkPacket, NarrowWaistPacket},
+        crossbeam_channel::unbounded,
+    };
+
+    #[test]
+    fn frames_are_length_prefixed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut accepted, _) = listener.accept().unwrap();
+        write_frame(&stream, &[1, 2, 3]).unwrap();
+        write_frame(&stream, &[]).unwrap();
+        assert_eq!(read_frame(&mut accepted).unwrap(), vec![1, 2, 3]);
+        assert_eq!(read_frame(&mut accepted).unwrap(), Vec::<u8>::new());
+        (&stream).write_all(&((MAX_FRAME_LEN + 1) as u32).to_be_bytes()).unwrap();
+        assert_eq!(read_frame(&mut accepted).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn peers_are_known_by_where_they_connect_from() {
+        let listen_addr: SocketAddr = "127.0.0.1:50150".parse().unwrap();
+        let (t2c_tx, t2c_rx) = unbounded();
+        let (_c2t_tx, c2t_rx) = unbounded();
+        let tcp: Tcp = Link::new(LinkId::listen(ReplyTo::Tcp(listen_addr)), (t2c_tx, c2t_rx)).unwrap();
+        tcp.run().unwrap();
+        let mut stream = None;
+        for _ in 0..50 {
+            match TcpStream::connect(listen_addr) {
+                Ok(connected) => { stream = Some(connected); break },
+                Err(_) => std::thread::sleep(Duration::from_millis(10)),
+            }
+        }
+        let stream = stream.expect("the link listens");
+        let victim = ReplyTo::Tcp("127.0.0.1:50151".parse().unwrap());
+        let hbfi = HBFI::new("app", "publisher").unwrap();
+        let lp = LinkPacket::new(victim.clone(), NarrowWaistPacket::request(hbfi));
+        write_frame(&stream, &Codec::default().encode(lp).unwrap()).unwrap();
+        let ilp = t2c_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!(ilp.reply_to(), ReplyTo::Tcp(stream.local_addr().unwrap()));
+        assert_eq!(ilp.wire_packet().reply_to(), victim);
+    }
+}
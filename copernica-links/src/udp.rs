@@ -1,24 +1,49 @@
 use {
-    crate::{Link, encode, decode},
+    crate::{Link, Codec, Fec, LinkStats, MAX_FRAME_LEN},
     copernica_common::{
-        InterLinkPacket, LinkId, ReplyTo, LinkPacket
+        InterLinkPacket, LinkId, ReplyTo, LinkPacket, StopHandle, STOP_POLL
     },
     anyhow::{anyhow, Result},
-    crossbeam_channel::{Sender, Receiver},
+    crossbeam_channel::{Sender, Receiver, RecvTimeoutError},
     async_std::{
+        io,
         net::UdpSocket,
         task,
     },
-    log::{debug, error, trace},
+    log::{debug, error, trace, warn},
 };
 
 pub struct UdpIp {
     link_id: LinkId,
     t2c_tx: Sender<InterLinkPacket>,
     c2t_rx: Receiver<InterLinkPacket>,
+    codec: Codec,
+    socket: Option<std::net::UdpSocket>,
+    stop: StopHandle,
 }
 
 impl UdpIp {
+    /// The parity this link adds to what it sends.
+    pub fn fec(mut self, fec: Fec) -> Self {
+        self.codec = self.codec.fec(fec);
+        self
+    }
+    /// Whether this link interleaves the blocks of what it sends.
+    pub fn interleave(mut self, interleave: bool) -> Self {
+        self.codec = self.codec.interleave(interleave);
+        self
+    }
+    /// Receives on `socket`, already bound to the listen address, rather
+    /// than binding it when run.
+    pub fn socket(mut self, socket: std::net::UdpSocket) -> Self {
+        self.socket = Some(socket);
+        self
+    }
+    /// Stops this link's threads when `stop` is.
+    pub fn stop_handle(mut self, stop: StopHandle) -> Self {
+        self.stop = stop;
+        self
+    }
 }
 
 impl Link<'_> for UdpIp {
@@ -28,31 +53,53 @@ impl Link<'_> for UdpIp {
     {
         trace!("LISTEN ON {:?}:", link_id);
         match link_id.reply_to() {
-            ReplyTo::UdpIp(_) => return Ok(UdpIp { link_id, t2c_tx, c2t_rx }),
-            _ => return Err(anyhow!("UdpIp Link expects a LinkId of type Link.ReplyTo::UdpIp(...)")),
+            ReplyTo::UdpIp(_) => Ok(UdpIp { link_id, t2c_tx, c2t_rx, codec: Codec::default(), socket: None, stop: StopHandle::new() }),
+            _ => Err(anyhow!("UdpIp Link expects a LinkId of type Link.ReplyTo::UdpIp(...)")),
         }
     }
 
+    fn stats(&self) -> LinkStats {
+        self.codec.stats()
+    }
+
     #[allow(unreachable_code)]
     fn run(&self) -> Result<()> {
         let this_link = self.link_id.clone();
         let t2c_tx = self.t2c_tx.clone();
-        std::thread::spawn(move || {
+        let codec = self.codec.clone();
+        let bound = match &self.socket {
+            Some(socket) => Some(socket.try_clone()?),
+            None => None,
+        };
+        let stop = self.stop.clone();
+        self.stop.spawn(move || {
             task::block_on(async move {
                 match this_link.reply_to() {
                     ReplyTo::UdpIp(listen_addr) => {
-                        match UdpSocket::bind(listen_addr).await {
+                        let socket = match bound {
+                            Some(socket) => Ok(UdpSocket::from(socket)),
+                            None => UdpSocket::bind(listen_addr).await,
+                        };
+                        match socket {
                             Ok(socket) => {
-                                loop {
-                                    let mut buf = vec![0u8; 1500];
-                                    match socket.recv_from(&mut buf).await {
+                                // a frame at the most parity is larger than a packet
+                                let mut buf = vec![0u8; MAX_FRAME_LEN];
+                                while !stop.is_stopped() {
+                                    match io::timeout(STOP_POLL, socket.recv_from(&mut buf)).await {
                                         Ok((n, _peer)) => {
-                                            let wp: LinkPacket = decode(buf[..n].to_vec())?;
+                                            let wp: LinkPacket = match codec.decode(&buf[..n]) {
+                                                Ok(wp) => wp,
+                                                Err(error) => {
+                                                    warn!("{:?}: dropping packet: {}", this_link, error);
+                                                    continue;
+                                                },
+                                            };
                                             debug!("Udp Recv on {:?} => {:?}", this_link, wp);
                                             let link_id = LinkId::new(this_link.nonce(), wp.reply_to());
                                             let ilp = InterLinkPacket::new(link_id, wp);
                                             let _r = t2c_tx.send(ilp)?;
                                         },
+                                        Err(error) if error.kind() == std::io::ErrorKind::TimedOut => {},
                                         Err(error) => error!("{:?}: {}", this_link, error),
                                     };
                                 }
@@ -67,23 +114,26 @@ impl Link<'_> for UdpIp {
         });
         let this_link = self.link_id.clone();
         let c2t_rx = self.c2t_rx.clone();
-        std::thread::spawn(move || {
+        let codec = self.codec.clone();
+        let stop = self.stop.clone();
+        self.stop.spawn(move || {
             task::block_on(async move {
                 match UdpSocket::bind("127.0.0.1:0").await {
                     Ok(socket) => {
-                        loop {
-                            match c2t_rx.recv(){
+                        while !stop.is_stopped() {
+                            match c2t_rx.recv_timeout(STOP_POLL) {
                                 Ok(ilp) => {
                                     match ilp.reply_to() {
                                         ReplyTo::UdpIp(remote_addr) => {
                                             let wp = ilp.wire_packet().change_origination(this_link.reply_to());
                                             debug!("Udp Send on {:?} => {:?}", this_link, wp);
-                                            let enc = encode(wp)?;
+                                            let enc = codec.encode(wp)?;
                                             socket.send_to(&enc, remote_addr).await?;
                                         },
                                         _ => {},
                                     }
                                 },
+                                Err(RecvTimeoutError::Timeout) => {},
                                 Err(error) => error!("{:?}: {}", this_link, error),
                             }
                         }
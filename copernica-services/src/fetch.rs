@@ -0,0 +1,558 @@
+use {
+    copernica_common::{HBFI, LinkId, NarrowWaistPacket, LinkPacket, InterLinkPacket, Data, Segments, PrivateIdentity, ResponseStore, Watch, constants},
+    std::{
+        fmt,
+        error,
+        io,
+        ops::RangeInclusive,
+        time::{Duration, Instant},
+        collections::{HashMap, BTreeMap},
+        sync::{
+            Arc,
+            atomic::{AtomicBool, Ordering},
+        },
+    },
+    crossbeam_channel::{Sender},
+    anyhow::{Result},
+    log::{trace, warn},
+};
+
+/// Controls how long `Service::get` waits for each `Response`, how
+/// often it retransmits the `Request` before giving up with
+/// `FetchError::Timeout`, and how many `Request`s it keeps in flight.
+#[derive(Clone, Debug)]
+pub struct FetchConfig {
+    timeout: Duration,
+    min_timeout: Duration,
+    max_timeout: Duration,
+    retries: u32,
+    backoff: u32,
+    window: usize,
+    max_window: usize,
+    identity: Option<PrivateIdentity>,
+    hop_limit: u8,
+}
+
+impl Default for FetchConfig {
+    fn default() -> Self {
+        Self {
+            timeout: Duration::from_millis(500),
+            min_timeout: Duration::from_millis(100),
+            max_timeout: Duration::from_secs(8),
+            retries: 5,
+            backoff: 2,
+            window: 4,
+            max_window: 64,
+            identity: None,
+            hop_limit: constants::HOP_LIMIT,
+        }
+    }
+}
+
+impl FetchConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Time to wait for the first `Response` before retransmitting.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Lower bound on the retransmission timeout estimated from round trip
+    /// times, so that a run of quick round trips doesn't leave a momentarily
+    /// stalled peer too little time to answer.
+    pub fn min_timeout(mut self, min_timeout: Duration) -> Self {
+        self.min_timeout = min_timeout;
+        self
+    }
+
+    /// Upper bound on the wait between retransmissions once backoff kicks in.
+    pub fn max_timeout(mut self, max_timeout: Duration) -> Self {
+        self.max_timeout = max_timeout;
+        self
+    }
+
+    /// Number of retransmissions after the initial `Request`.
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Multiplier applied to the wait after every unanswered `Request`.
+    pub fn backoff(mut self, backoff: u32) -> Self {
+        self.backoff = backoff;
+        self
+    }
+
+    /// Number of `Request`s in flight when a fetch starts.
+    pub fn window(mut self, window: usize) -> Self {
+        self.window = window;
+        self
+    }
+
+    /// Upper bound on the `Request`s in flight, which also bounds the
+    /// number of out of order `Response`s held for reassembly.
+    pub fn max_window(mut self, max_window: usize) -> Self {
+        self.max_window = max_window;
+        self
+    }
+
+    /// Signs every `Request` with `identity`. Brokers fending off a flood
+    /// of `Request`s favour signed ones, whose nonces can't be forged to
+    /// pass a replay off as new.
+    pub fn identity(mut self, identity: PrivateIdentity) -> Self {
+        self.identity = Some(identity);
+        self
+    }
+
+    /// Brokers each `Request` may pass through, so that a `Request` for
+    /// content nobody has dies out rather than crossing the whole network.
+    pub fn hop_limit(mut self, hops: u8) -> Self {
+        self.hop_limit = hops;
+        self
+    }
+
+    pub fn get_retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// The time to wait after the `attempt`th transmission, 0 being the
+    /// first, given a base retransmission timeout of `rto`.
+    pub fn deadline(&self, rto: Duration, attempt: u32) -> Duration {
+        let factor = self.backoff.max(1).saturating_pow(attempt);
+        let deadline = rto.checked_mul(factor).unwrap_or(self.max_timeout);
+        if deadline > self.max_timeout { self.max_timeout } else { deadline }
+    }
+}
+
+/// An AIMD congestion window. The window grows by one `Request` per
+/// `Response` while below the slow start threshold, by one `Request` per
+/// window of `Response`s above it, and halves on loss. Round trip times
+/// of `Request`s that were never retransmitted feed a smoothed estimate
+/// (RFC 6298) used as the base retransmission timeout, floored at
+/// `FetchConfig::min_timeout`.
+#[derive(Debug)]
+struct Window {
+    cwnd: f64,
+    ssthresh: f64,
+    max: f64,
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    last_decrease: Option<Instant>,
+}
+
+impl Window {
+    fn new(config: &FetchConfig) -> Self {
+        let max = config.max_window.max(1) as f64;
+        Self {
+            cwnd: (config.window.max(1) as f64).min(max),
+            ssthresh: max,
+            max,
+            srtt: None,
+            rttvar: Duration::from_millis(0),
+            last_decrease: None,
+        }
+    }
+
+    fn size(&self) -> usize {
+        self.cwnd as usize
+    }
+
+    fn on_response(&mut self, rtt: Option<Duration>) {
+        if self.cwnd < self.ssthresh {
+            self.cwnd += 1.0;
+        } else {
+            self.cwnd += 1.0 / self.cwnd;
+        }
+        self.cwnd = self.cwnd.min(self.max);
+        if let Some(rtt) = rtt {
+            match self.srtt {
+                None => {
+                    self.srtt = Some(rtt);
+                    self.rttvar = rtt / 2;
+                },
+                Some(srtt) => {
+                    let delta = srtt.abs_diff(rtt);
+                    self.rttvar = (self.rttvar * 3 + delta) / 4;
+                    self.srtt = Some((srtt * 7 + rtt) / 8);
+                },
+            }
+        }
+    }
+
+    fn on_loss(&mut self, now: Instant) {
+        // only react once per round trip, a burst of losses is one congestion event
+        let rtt = self.srtt.unwrap_or_else(|| Duration::from_millis(0));
+        if let Some(last) = self.last_decrease {
+            if now.duration_since(last) < rtt {
+                return
+            }
+        }
+        self.ssthresh = (self.cwnd / 2.0).max(1.0);
+        self.cwnd = self.ssthresh;
+        self.last_decrease = Some(now);
+    }
+
+    fn rto(&self, config: &FetchConfig) -> Duration {
+        match self.srtt {
+            Some(srtt) => {
+                let rto = (srtt + self.rttvar * 4).max(config.min_timeout);
+                if rto > config.max_timeout { config.max_timeout } else { rto }
+            },
+            None => config.timeout,
+        }
+    }
+}
+
+struct Outstanding {
+    sent: Instant,
+    deadline: Instant,
+    attempt: u32,
+}
+
+/// Fetches the offsets `start..=end` of `hbfi`, keeping up to a window of
+/// `Request`s in flight and yielding the `Response` payloads in order.
+pub struct Fetcher<S: ResponseStore = sled::Db> {
+    rs: S,
+    s2l_tx: Sender<InterLinkPacket>,
+    link_id: LinkId,
+    hbfi: HBFI,
+    segments: Option<Segments>,
+    config: FetchConfig,
+    cancel: CancelHandle,
+    watcher: S::Watcher,
+    next_request: u64,
+    next_yield: u64,
+    end: u64,
+    outstanding: HashMap<u64, Outstanding>,
+    received: BTreeMap<u64, Data>,
+    window: Window,
+    done: bool,
+}
+
+impl<S: ResponseStore> Fetcher<S> {
+    pub(crate) fn new(rs: S,
+        s2l_tx: Sender<InterLinkPacket>,
+        link_id: LinkId,
+        hbfi: HBFI,
+        offsets: RangeInclusive<u64>,
+        config: FetchConfig,
+        cancel: CancelHandle,
+    ) -> Result<Self> {
+        let (start, end) = offsets.into_inner();
+        let watcher = rs.watch(&hbfi)?;
+        let window = Window::new(&config);
+        Ok(Self {
+            rs,
+            s2l_tx,
+            link_id,
+            hbfi,
+            segments: None,
+            config,
+            cancel,
+            watcher,
+            next_request: start,
+            next_yield: start,
+            end,
+            outstanding: HashMap::new(),
+            received: BTreeMap::new(),
+            window,
+            done: start > end,
+        })
+    }
+
+    /// Also require every chunk past the manifest to prove its inclusion
+    /// below one of these segment roots.
+    pub(crate) fn segments(mut self, segments: Segments) -> Self {
+        self.segments = Some(segments);
+        self
+    }
+
+    fn request(&self, offset: u64) -> Result<()> {
+        let hbfi = self.hbfi.clone().offset(offset);
+        let nw = match &self.config.identity {
+            Some(identity) => NarrowWaistPacket::signed_request(identity, hbfi)?,
+            None => NarrowWaistPacket::request(hbfi),
+        };
+        let lp = LinkPacket::new(self.link_id.reply_to(), nw.hop_limit(self.config.hop_limit));
+        self.s2l_tx.send(InterLinkPacket::new(self.link_id.clone(), lp))?;
+        Ok(())
+    }
+
+    /// Takes a `Response` into the reassembly buffer. One that is not
+    /// signed by the publisher of the requested `HBFI`, or that fails its
+    /// Merkle proof, is evicted from the store and requested again.
+    fn accept(&mut self, nw: NarrowWaistPacket) -> Result<bool> {
+        if let NarrowWaistPacket::Response { hbfi, .. } = &nw {
+            let proven = match &self.segments {
+                Some(segments) => nw.verify_proof(segments),
+                None => true,
+            };
+            if hbfi.h1 != self.hbfi.h1 || hbfi.id != self.hbfi.id || !nw.verify() || !proven {
+                warn!("rejecting unverified response {:?}", nw);
+                self.rs.remove(hbfi)?;
+                if self.outstanding.contains_key(&hbfi.os) {
+                    self.request(hbfi.os)?;
+                }
+                return Ok(false);
+            }
+        }
+        if let NarrowWaistPacket::Response { hbfi, data, .. } = nw {
+            let offset = hbfi.os;
+            if offset < self.next_yield || offset > self.end || self.received.contains_key(&offset) {
+                return Ok(true)
+            }
+            if let Some(Outstanding { sent, attempt, .. }) = self.outstanding.remove(&offset) {
+                // Karn's algorithm: a retransmitted Request gives an ambiguous sample
+                let rtt = if attempt == 1 { Some(sent.elapsed()) } else { None };
+                self.window.on_response(rtt);
+            }
+            self.received.insert(offset, data);
+        }
+        Ok(true)
+    }
+
+    fn fill(&mut self) -> Result<()> {
+        let max_window = self.config.max_window.max(1) as u64;
+        while self.next_request <= self.end
+            && self.outstanding.len() < self.window.size()
+            && self.next_request - self.next_yield < max_window {
+            let offset = self.next_request;
+            self.next_request += 1;
+            if let Some(nw) = self.rs.get(&self.hbfi.clone().offset(offset))? {
+                if let NarrowWaistPacket::Response {..} = nw {
+                    if self.accept(nw)? {
+                        continue;
+                    }
+                }
+            }
+            self.request(offset)?;
+            let now = Instant::now();
+            let deadline = now + self.config.deadline(self.window.rto(&self.config), 0);
+            self.outstanding.insert(offset, Outstanding { sent: now, deadline, attempt: 1 });
+        }
+        Ok(())
+    }
+
+    fn poll_store(&mut self) -> Result<()> {
+        let offsets: Vec<u64> = self.outstanding.keys().cloned().collect();
+        for offset in offsets {
+            if let Some(nw) = self.rs.get(&self.hbfi.clone().offset(offset))? {
+                self.accept(nw)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn retransmit(&mut self) -> Result<()> {
+        let now = Instant::now();
+        let expired: Vec<u64> = self.outstanding.iter()
+            .filter(|(_, o)| o.deadline <= now)
+            .map(|(offset, _)| *offset)
+            .collect();
+        for offset in expired {
+            self.window.on_loss(now);
+            let rto = self.window.rto(&self.config);
+            if let Some(o) = self.outstanding.get_mut(&offset) {
+                if o.attempt > self.config.retries {
+                    let hbfi = self.hbfi.clone().offset(offset);
+                    return Err(FetchError::Timeout { hbfi, offset }.into());
+                }
+                o.deadline = now + self.config.deadline(rto, o.attempt);
+                o.sent = now;
+                o.attempt += 1;
+            }
+            trace!("retransmitting {:?} at offset {}, window {}", self.hbfi, offset, self.window.size());
+            self.request(offset)?;
+        }
+        Ok(())
+    }
+
+    fn next_chunk(&mut self) -> Result<Option<Vec<u8>>> {
+        loop {
+            if self.cancel.is_cancelled() {
+                let hbfi = self.hbfi.clone().offset(self.next_yield);
+                return Err(FetchError::Cancelled { hbfi, offset: self.next_yield }.into());
+            }
+            if let Some(data) = self.received.remove(&self.next_yield) {
+                self.next_yield += 1;
+                let (chunk, _) = data.data.split_at(data.len.into());
+                return Ok(Some(chunk.to_vec()));
+            }
+            if self.next_yield > self.end {
+                return Ok(None);
+            }
+            self.fill()?;
+            if self.received.contains_key(&self.next_yield) {
+                continue;
+            }
+            self.retransmit()?;
+            let now = Instant::now();
+            let wait = self.outstanding.values()
+                .map(|o| o.deadline.saturating_duration_since(now))
+                .min()
+                .unwrap_or_else(|| Duration::from_millis(0));
+            match next_event(&mut self.watcher, wait) {
+                Some(nw) => {
+                    self.accept(nw)?;
+                },
+                None => self.poll_store()?,
+            }
+        }
+    }
+}
+
+impl<S: ResponseStore> Iterator for Fetcher<S> {
+    type Item = Result<Vec<u8>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None
+        }
+        match self.next_chunk() {
+            Ok(Some(chunk)) => Some(Ok(chunk)),
+            Ok(None) => {
+                self.done = true;
+                None
+            },
+            Err(e) => {
+                self.done = true;
+                Some(Err(e))
+            },
+        }
+    }
+}
+
+/// Adapts a `Fetcher` to `std::io::Read`, holding at most one chunk
+/// beyond what the `Fetcher` itself buffers for reassembly.
+pub struct FetchReader<S: ResponseStore = sled::Db> {
+    fetcher: Fetcher<S>,
+    chunk: Vec<u8>,
+    position: usize,
+}
+
+impl<S: ResponseStore> FetchReader<S> {
+    pub fn new(fetcher: Fetcher<S>) -> Self {
+        Self { fetcher, chunk: vec![], position: 0 }
+    }
+}
+
+impl<S: ResponseStore> io::Read for FetchReader<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.chunk.len() {
+            match self.fetcher.next() {
+                Some(Ok(chunk)) => {
+                    self.chunk = chunk;
+                    self.position = 0;
+                },
+                Some(Err(e)) => return Err(io::Error::other(e)),
+                None => return Ok(0),
+            }
+        }
+        let n = buf.len().min(self.chunk.len() - self.position);
+        buf[..n].copy_from_slice(&self.chunk[self.position..self.position + n]);
+        self.position += n;
+        Ok(n)
+    }
+}
+
+/// A handle which aborts any fetch in progress on the `Service` it was
+/// taken from. Cancellation is sticky: every subsequent fetch fails with
+/// `FetchError::Cancelled` until `reset` is called.
+#[derive(Clone, Debug, Default)]
+pub struct CancelHandle {
+    cancelled: Arc<AtomicBool>,
+}
+
+impl CancelHandle {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    pub fn cancel(&self) {
+        self.cancelled.store(true, Ordering::SeqCst);
+    }
+    pub fn reset(&self) {
+        self.cancelled.store(false, Ordering::SeqCst);
+    }
+    pub fn is_cancelled(&self) -> bool {
+        self.cancelled.load(Ordering::SeqCst)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum FetchError {
+    Timeout { hbfi: HBFI, offset: u64 },
+    Cancelled { hbfi: HBFI, offset: u64 },
+    NotPeered,
+}
+
+impl fmt::Display for FetchError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FetchError::Timeout { hbfi, offset } => write!(f, "Timed out fetching {:?} at offset {}", hbfi, offset),
+            FetchError::Cancelled { hbfi, offset } => write!(f, "Cancelled fetching {:?} at offset {}", hbfi, offset),
+            FetchError::NotPeered => write!(f, "Service must be peered with a link before fetching"),
+        }
+    }
+}
+
+impl error::Error for FetchError {}
+
+// How often a blocked fetch wakes up to check its CancelHandle and the store.
+const POLL: Duration = Duration::from_millis(50);
+
+/// Waits up to `wait`, but no longer than `POLL`, for the next Response
+/// seen by `watcher`. Watching a sled `Subscriber` can lose an event
+/// whose insert is still in flight, so callers must treat events only as
+/// a wake up and check the store themselves when this returns `None`.
+pub(crate) fn next_event<W: Watch>(watcher: &mut W, wait: Duration) -> Option<NarrowWaistPacket> {
+    let wait = if wait < POLL { wait } else { POLL };
+    if wait == Duration::from_millis(0) {
+        return None
+    }
+    watcher.next_timeout(wait)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn window_aimd() {
+        let config = FetchConfig::new().window(2).max_window(16);
+        let mut window = Window::new(&config);
+        assert_eq!(window.size(), 2);
+        for _ in 0..6 {
+            window.on_response(None);
+        }
+        assert_eq!(window.size(), 8);
+        window.on_loss(Instant::now());
+        assert_eq!(window.size(), 4);
+        for _ in 0..4 {
+            window.on_response(None);
+        }
+        assert_eq!(window.size(), 4);
+        window.on_response(None);
+        assert_eq!(window.size(), 5);
+        for _ in 0..200 {
+            window.on_response(None);
+        }
+        assert_eq!(window.size(), 16);
+    }
+
+    #[test]
+    fn rto_follows_rtt() {
+        let config = FetchConfig::new().timeout(Duration::from_millis(500));
+        let mut window = Window::new(&config);
+        assert_eq!(window.rto(&config), Duration::from_millis(500));
+        window.on_response(Some(Duration::from_millis(40)));
+        assert_eq!(window.rto(&config), Duration::from_millis(120));
+        assert_eq!(config.deadline(window.rto(&config), 2), Duration::from_millis(480));
+        assert_eq!(config.deadline(window.rto(&config), 20), Duration::from_secs(8));
+        let mut window = Window::new(&config);
+        window.on_response(Some(Duration::from_millis(1)));
+        assert_eq!(window.rto(&config), Duration::from_millis(100));
+    }
+}
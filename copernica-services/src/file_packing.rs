@@ -1,10 +1,12 @@
 use {
-    copernica_common::{NarrowWaistPacket, Data, HBFI, constants},
+    crate::{Progress, ProgressFn},
+    copernica_common::{NarrowWaistPacket, Data, HBFI, PrivateIdentity, Hash, MerkleTree, ResponseStore, chunk_hash, constants},
     std::{
         path::{Path, PathBuf},
         collections::HashMap,
-        io::prelude::*,
+        io::{self, prelude::*},
         fs,
+        mem,
     },
     borsh::{BorshSerialize, BorshDeserialize},
     walkdir::{WalkDir},
@@ -13,12 +15,19 @@ use {
 };
 
 pub type PathsWithSizes = Vec<(PathBuf, u64)>;
-pub type PathsWithOffsets = HashMap<String, (u64, u64)>;
+/// The first and last offsets of each file, and its length in bytes.
+pub type PathsWithOffsets = HashMap<String, (u64, u64, u64)>;
 
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
 pub struct Manifest {
     pub start: u64,
     pub end: u64,
+    /// Merkle root over every chunk from offset 1 to the end of the file manifest.
+    pub root: Hash,
+    /// Offsets of the segment roots of that tree, concatenated, against
+    /// which each chunk's proof is checked.
+    pub segments_start: u64,
+    pub segments_end: u64,
 }
 
 #[derive(BorshSerialize, BorshDeserialize, PartialEq, Debug)]
@@ -31,12 +40,14 @@ pub struct FilePacker {
     src_dir: PathBuf,
     dest_dir: PathBuf,
     name: String,
-    id: String,
+    identity: PrivateIdentity,
     absolute_files_size: PathsWithSizes,
+    batch_size: usize,
+    progress: Option<ProgressFn>,
 }
 
 impl FilePacker {
-    pub fn new(source_dir: &Path, dest_dir: &Path, name: String, id: String) -> Result<Self> {
+    pub fn new(source_dir: &Path, dest_dir: &Path, name: String, identity: PrivateIdentity) -> Result<Self> {
         let src_dir = source_dir.clone().to_path_buf();
         let dest_dir = dest_dir.to_path_buf();
         Ok(Self {
@@ -44,8 +55,10 @@ impl FilePacker {
             src_dir,
             dest_dir,
             name,
-            id,
+            identity,
             absolute_files_size: directory_structure(&source_dir)?,
+            batch_size: 256,
+            progress: None,
         })
     }
 
@@ -69,69 +82,182 @@ impl FilePacker {
         self
     }
 
-    pub fn id(mut self, id: String) -> Self {
-        self.id = id;
+    /// The publisher signing every chunk; `HBFI.id` is derived from its public key.
+    pub fn identity(mut self, identity: PrivateIdentity) -> Self {
+        self.identity = identity;
         self
     }
 
+    /// Number of chunks written to the store per `insert_batch`.
+    pub fn batch_size(mut self, batch_size: usize) -> Self {
+        self.batch_size = batch_size;
+        self
+    }
+
+    /// Called after every batch of chunks written and once per file.
+    pub fn progress(mut self, progress: ProgressFn) -> Self {
+        self.progress = Some(progress);
+        self
+    }
+
+    /// Publishes into a sled store at `dest_dir`.
     pub fn publish(&self) -> Result<()> {
-        let rs = sled::open(&self.dest_dir).expect("open");
+        let rs = sled::open(&self.dest_dir)?;
+        self.publish_to(&rs)
+    }
+
+    pub fn publish_to<S: ResponseStore>(&self, rs: &S) -> Result<()> {
         let chunk_size = self.chunk_size;
+        if chunk_size == 0 || chunk_size > constants::FRAGMENT_SIZE {
+            return Err(anyhow!("chunk size {} must be between 1 and {}", chunk_size, constants::FRAGMENT_SIZE));
+        }
         let mut relative_files_offsets = PathsWithOffsets::new();
-        let mut absolute_files_offsets = PathsWithOffsets::new();
+        let mut files_offsets: Vec<(PathBuf, String, u64, u64, u64)> = vec![];
         let mut total_offset: u64 = 1; // 0 is used for manifest, always
         for (entry, size) in &self.absolute_files_size {
             let (start, end, offset) = offset(total_offset, *size, chunk_size as u64)?;
             total_offset += offset;
-            let stripped_entry = entry.strip_prefix(self.src_dir.clone())?;
-            relative_files_offsets.insert(stripped_entry.to_str().unwrap().to_string(), (start, end));
-            absolute_files_offsets.insert(entry.to_str().unwrap().to_string(), (start, end));
+            let stripped_entry = entry.strip_prefix(self.src_dir.clone())?.to_str().unwrap().to_string();
+            relative_files_offsets.insert(stripped_entry.clone(), (start, end, *size));
+            files_offsets.push((entry.clone(), stripped_entry, start, end, *size));
         }
         let file_manifest = FileManifest { files: relative_files_offsets };
         let file_manifest = file_manifest.try_to_vec()?;
         let file_manifest_size = file_manifest.len() as u64;
         let (file_manifest_start, file_manifest_end, file_manifest_offset)= offset(total_offset, file_manifest_size, chunk_size as u64)?;
         total_offset += file_manifest_offset;
+        let chunks = total_offset - 1;
+        let segments = (chunks + (1 << constants::MAX_PROOF_DEPTH) - 1) >> constants::MAX_PROOF_DEPTH;
+        let (segments_start, segments_end, segments_offset) = offset(total_offset, segments * 32, chunk_size as u64)?;
+        total_offset += segments_offset;
 
         // this concludes the calculation of the total size and file chunk sizes.
 
-        let hbfi = HBFI::new(&self.name, &self.id)?;
-
-        let mut current_offset: u64 = 1;
-        for (file_path, (start, end)) in absolute_files_offsets {
-            if end > 0 {
-                let mut file = fs::File::open(&file_path)?;
-                let mut buffer = Vec::new();
-                file.read_to_end(&mut buffer)?;
-                let file_chunks = buffer.chunks(chunk_size as usize);
-                let mut counter = start;
-                for file_chunk in file_chunks {
-                    let hbfi = hbfi.clone().offset(counter);
-                    let resp = create_response(hbfi.clone(), file_chunk, counter, total_offset)?.try_to_vec()?;
-                    rs.insert(&hbfi.try_to_vec()?, resp)?;
-                    current_offset += 1;
-                    counter += 1;
+        let hbfi = HBFI::new(&self.name, &self.identity.public_id().to_string())?;
+        let batch_size = self.batch_size.max(1);
+        let files = files_offsets.len();
+
+        // the first pass hashes every chunk so that the second can attach an
+        // inclusion proof to each Response.
+        let mut leaves: Vec<Hash> = Vec::with_capacity(chunks as usize);
+        for (file_path, _, start, end, _) in &files_offsets {
+            for_each_chunk(file_path, *start, *end, chunk_size, |offset, chunk| {
+                leaves.push(chunk_hash(offset, chunk));
+                Ok(())
+            })?;
+        }
+        for (index, file_manifest_chunk) in file_manifest.chunks(chunk_size as usize).enumerate() {
+            leaves.push(chunk_hash(file_manifest_start + index as u64, file_manifest_chunk));
+        }
+        let tree = MerkleTree::new(leaves);
+
+        for (index, (file_path, name, start, end, size)) in files_offsets.into_iter().enumerate() {
+            let mut progress = Progress { name, bytes: 0, size, file: index + 1, files };
+            let mut batch = Vec::with_capacity(batch_size);
+            for_each_chunk(&file_path, start, end, chunk_size, |offset, chunk| {
+                let hbfi = hbfi.clone().offset(offset);
+                let proof = tree.proof((offset - 1) as usize);
+                let resp = create_response(&self.identity, hbfi.clone(), chunk, offset, total_offset, proof)?;
+                batch.push((hbfi, resp));
+                progress.bytes += chunk.len() as u64;
+                if batch.len() == batch_size {
+                    rs.insert_batch(mem::replace(&mut batch, Vec::with_capacity(batch_size)))?;
+                    self.report(&progress);
                 }
-            }
+                Ok(())
+            })?;
+            rs.insert_batch(batch)?;
+            self.report(&progress);
         }
 
+        let mut batch = vec![];
+        let mut current_offset = file_manifest_start;
         let file_manifest_chunks = file_manifest.chunks(chunk_size as usize);
         for file_manifest_chunk in file_manifest_chunks {
             let hbfi = hbfi.clone().offset(current_offset);
-            let resp = create_response(hbfi.clone(), file_manifest_chunk, current_offset, total_offset)?.try_to_vec()?;
-            rs.insert(&hbfi.try_to_vec()?, resp)?;
+            let proof = tree.proof((current_offset - 1) as usize);
+            let resp = create_response(&self.identity, hbfi.clone(), file_manifest_chunk, current_offset, total_offset, proof)?;
+            batch.push((hbfi, resp));
             current_offset += 1;
         }
 
-        let manifest = Manifest { start: file_manifest_start, end: file_manifest_end }.try_to_vec()?;
-        let resp = create_response(hbfi.clone(), &manifest, 0, total_offset)?.try_to_vec()?;
-        rs.insert(hbfi.try_to_vec()?, resp)?;
+        // the segment roots are outside the tree; a fetcher checks them
+        // against the root in the manifest once they are all in.
+        let segments = tree.segments().roots().concat();
+        for (index, segments_chunk) in segments.chunks(chunk_size as usize).enumerate() {
+            let current_offset = segments_start + index as u64;
+            let hbfi = hbfi.clone().offset(current_offset);
+            let resp = create_response(&self.identity, hbfi.clone(), segments_chunk, current_offset, total_offset, vec![])?;
+            batch.push((hbfi, resp));
+        }
+
+        let manifest = Manifest {
+            start: file_manifest_start,
+            end: file_manifest_end,
+            root: tree.root(),
+            segments_start,
+            segments_end,
+        }.try_to_vec()?;
+        let resp = create_response(&self.identity, hbfi.clone(), &manifest, 0, total_offset, vec![])?;
+        batch.push((hbfi, resp));
+        rs.insert_batch(batch)?;
+        rs.flush()?;
 
         Ok(())
     }
+
+    fn report(&self, progress: &Progress) {
+        if let Some(report) = &self.progress {
+            report(progress);
+        }
+    }
+}
+
+/// Reads the file published at offsets `start..=end` one chunk at a time,
+/// failing if its length changed since the offsets were computed.
+fn for_each_chunk<F>(file_path: &Path, start: u64, end: u64, chunk_size: u16, mut f: F) -> Result<()>
+where
+    F: FnMut(u64, &[u8]) -> Result<()>,
+{
+    if end == 0 {
+        return Ok(());
+    }
+    let mut file = fs::File::open(file_path)?;
+    let mut buffer = vec![0; chunk_size as usize];
+    let mut counter = start;
+    loop {
+        let len = read_chunk(&mut file, &mut buffer)?;
+        if len == 0 {
+            break;
+        }
+        if counter > end {
+            return Err(anyhow!("{:?} grew while it was being published", file_path));
+        }
+        f(counter, &buffer[..len])?;
+        counter += 1;
+    }
+    if counter != end + 1 {
+        return Err(anyhow!("{:?} shrank while it was being published", file_path));
+    }
+    Ok(())
+}
+
+/// Fills `buffer` from `file`, only returning less than a full buffer at
+/// the end of the file.
+fn read_chunk(file: &mut fs::File, buffer: &mut [u8]) -> Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(n) => filled += n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        }
+    }
+    Ok(filled)
 }
 
-fn create_response(hbfi: HBFI, chunk: &[u8], offset: u64, total_offset: u64) -> Result<NarrowWaistPacket> {
+fn create_response(identity: &PrivateIdentity, hbfi: HBFI, chunk: &[u8], offset: u64, total_offset: u64, proof: Vec<Hash>) -> Result<NarrowWaistPacket> {
     let mut data = [0; constants::FRAGMENT_SIZE as usize];
     let len = chunk.len() as u16;
     if len < constants::FRAGMENT_SIZE {
@@ -141,7 +267,7 @@ fn create_response(hbfi: HBFI, chunk: &[u8], offset: u64, total_offset: u64) ->
         data.copy_from_slice(&*chunk);
     }
     let data = Data { len: len, data: data };
-    Ok(NarrowWaistPacket::Response { hbfi: hbfi.clone(), data, offset: offset, total: total_offset })
+    NarrowWaistPacket::signed_response(identity, hbfi, data, offset, total_offset, proof)
 }
 
 fn offset(current_offset: u64, size: u64, chunk_size: u64) -> Result<(u64, u64, u64)> {
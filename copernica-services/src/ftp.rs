@@ -1,22 +1,32 @@
 use {
-    copernica_common::{HBFI, LinkId, InterLinkPacket},
-    crate::{Manifest, FileManifest, Service, DropHookFn},
+    copernica_common::{HBFI, LinkId, InterLinkPacket, ResponseStore, StopHandle},
+    crate::{Manifest, FileManifest, Service, DropHookFn, FetchConfig, CancelHandle, Fetcher, FetchReader, Progress, ProgressFn},
     crossbeam_channel::{ Sender, Receiver },
-    sled::{Db},
     borsh::{BorshDeserialize},
     anyhow::{Result, anyhow},
-    log::{debug},
+    log::{debug, warn},
+    std::{
+        fs,
+        io::{BufWriter, Write},
+        path::{Component, Path},
+    },
 };
 
-pub struct FTP {
+/// Chunks `download_all` writes between progress reports.
+const PROGRESS_CHUNKS: u64 = 256;
+
+pub struct FTP<S: ResponseStore = sled::Db> {
     link_id: Option<LinkId>,
-    rs: Db,
+    rs: S,
     l2s_rx: Option<Receiver<InterLinkPacket>>,
     s2l_tx: Option<Sender<InterLinkPacket>>,
+    fetch_config: FetchConfig,
+    cancel: CancelHandle,
+    stop: StopHandle,
     drop_hook: DropHookFn,
 }
 
-impl<'a> FTP {
+impl<S: ResponseStore> FTP<S> {
     pub fn manifest(&mut self, hbfi: HBFI) -> Result<Manifest> {
         let hbfi = hbfi.clone().offset(0);
         debug!("File Sharer to Service:\t{:?}", hbfi);
@@ -38,31 +48,113 @@ impl<'a> FTP {
     }
     pub fn file(&mut self, hbfi: HBFI, name: String) -> Result<Vec<u8>> {
         let file_manifest: FileManifest = self.file_manifest(hbfi.clone())?;
-        if let Some((start, end)) = file_manifest.files.get(&name) {
-            let file = self.get(hbfi.clone(), *start, *end)?;
-            return Ok(file);
+        match file_manifest.files.get(&name) {
+            Some((start, end, _)) => self.get(hbfi, *start, *end),
+            None => Err(anyhow!("File not present")),
+        }
+    }
+    /// Streams a file without holding it in memory, only the chunks in
+    /// the fetch window are buffered.
+    pub fn file_reader(&mut self, hbfi: HBFI, name: String) -> Result<FetchReader<S>> {
+        Ok(FetchReader::new(self.file_fetcher(hbfi, name)?))
+    }
+    /// Streams a file to `path`, returning the number of bytes written.
+    /// A partially written file is removed if the fetch fails.
+    pub fn download_to(&mut self, hbfi: HBFI, name: String, path: &Path) -> Result<u64> {
+        let fetcher = self.file_fetcher(hbfi, name)?;
+        write_chunks(fetcher, path, |_| {})
+    }
+    /// Recreates every file of the publication below `dest_dir`, creating
+    /// subdirectories as needed. `progress` is called as each file starts,
+    /// every 256 chunks and once it is written. A partially written file
+    /// is removed if the fetch fails.
+    pub fn download_all(&mut self, hbfi: HBFI, dest_dir: &Path, progress: ProgressFn) -> Result<()> {
+        let file_manifest: FileManifest = self.file_manifest(hbfi.clone())?;
+        let mut files: Vec<(String, (u64, u64, u64))> = file_manifest.files.into_iter().collect();
+        files.sort();
+        let count = files.len();
+        for (index, (name, (start, end, size))) in files.into_iter().enumerate() {
+            let relative = Path::new(&name);
+            if !relative.components().all(|c| matches!(c, Component::Normal(_))) {
+                return Err(anyhow!("Refusing to write {:?} outside of {:?}", name, dest_dir));
+            }
+            let path = dest_dir.join(relative);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            let mut report = Progress { name, bytes: 0, size, file: index + 1, files: count };
+            progress(&report);
+            let mut fetched: u64 = 0;
+            // end == 0 marks a zero length file, there is nothing to fetch
+            let fetcher = if end > 0 { Some(self.fetcher(hbfi.clone(), start, end)?) } else { None };
+            report.bytes = write_chunks(fetcher.into_iter().flatten(), &path, |written| {
+                fetched += 1;
+                if fetched.is_multiple_of(PROGRESS_CHUNKS) {
+                    report.bytes = written;
+                    progress(&report);
+                }
+            })?;
+            progress(&report);
+        }
+        Ok(())
+    }
+    fn file_fetcher(&mut self, hbfi: HBFI, name: String) -> Result<Fetcher<S>> {
+        let file_manifest: FileManifest = self.file_manifest(hbfi.clone())?;
+        match file_manifest.files.get(&name) {
+            Some((start, end, _)) => self.fetcher(hbfi, *start, *end),
+            None => Err(anyhow!("File not present")),
         }
-        return Err(anyhow!("File not present"))
     }
 }
 
-impl Drop for FTP {
+/// Writes `chunks` to `path`, calling `written` with the bytes written so
+/// far after each chunk. A partially written file is removed on failure.
+fn write_chunks<I, F>(mut chunks: I, path: &Path, mut written: F) -> Result<u64>
+where
+    I: Iterator<Item = Result<Vec<u8>>>,
+    F: FnMut(u64),
+{
+    let mut writer = BufWriter::new(fs::File::create(path)?);
+    let mut bytes: u64 = 0;
+    let result = chunks
+        .try_for_each(|chunk| {
+            let chunk = chunk?;
+            writer.write_all(&chunk)?;
+            bytes += chunk.len() as u64;
+            written(bytes);
+            Ok::<(), anyhow::Error>(())
+        })
+        .and_then(|_| Ok(writer.flush()?));
+    if let Err(e) = result {
+        if let Err(error) = fs::remove_file(path) {
+            warn!("could not remove partial download {:?}: {}", path, error);
+        }
+        return Err(e);
+    }
+    Ok(bytes)
+}
+
+impl<S: ResponseStore> Drop for FTP<S> {
     fn drop(&mut self) {
         &(self.drop_hook)();
     }
 }
 
-impl<'a> Service<'a> for FTP {
-    fn new(rs: Db, drop_hook: DropHookFn) -> FTP {
+impl<'a, S: ResponseStore> Service<'a> for FTP<S> {
+    type Store = S;
+    fn new(rs: S, drop_hook: DropHookFn) -> Self {
         FTP {
             link_id: None,
             l2s_rx: None,
             s2l_tx: None,
+            fetch_config: FetchConfig::default(),
+            cancel: CancelHandle::new(),
+            stop: StopHandle::new(),
             rs,
             drop_hook,
         }
     }
-    fn response_store(&self) -> Db {
+    fn response_store(&self) -> S {
         self.rs.clone()
     }
     fn set_l2s_rx(&mut self, r: Receiver<InterLinkPacket>) {
@@ -83,5 +175,20 @@ impl<'a> Service<'a> for FTP {
     fn get_link_id(&mut self) -> Option<LinkId> {
         self.link_id.clone()
     }
+    fn get_fetch_config(&mut self) -> FetchConfig {
+        self.fetch_config.clone()
+    }
+    fn set_fetch_config(&mut self, config: FetchConfig) {
+        self.fetch_config = config;
+    }
+    fn get_cancel_handle(&mut self) -> CancelHandle {
+        self.cancel.clone()
+    }
+    fn get_stop_handle(&mut self) -> StopHandle {
+        self.stop.clone()
+    }
+    fn set_stop_handle(&mut self, stop: StopHandle) {
+        self.stop = stop;
+    }
 }
 
@@ -2,6 +2,8 @@ mod service;
 mod file_packing;
 mod relay_node;
 mod ftp;
+mod fetch;
+mod progress;
 
 pub use {
     self::{
@@ -9,6 +11,8 @@ pub use {
         relay_node::{RelayNode},
         file_packing::{Manifest, FileManifest, FilePacker},
         ftp::{FTP},
+        progress::{Progress, ProgressFn},
+        fetch::{FetchConfig, FetchError, CancelHandle, Fetcher, FetchReader},
     },
 };
 
@@ -0,0 +1,11 @@
+/// Reported as each file of a publication is published or downloaded.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Progress {
+    pub name: String, // path relative to the publication root
+    pub bytes: u64,   // bytes of this file processed so far
+    pub size: u64,    // total bytes of this file
+    pub file: usize,  // 1 based index of this file
+    pub files: usize, // number of files in the publication
+}
+
+pub type ProgressFn = Box<dyn Fn(&Progress) + Send + 'static>;
@@ -1,30 +1,36 @@
 use {
-    crate::{Service, DropHookFn},
-    copernica_common::{LinkId, InterLinkPacket,},
+    crate::{Service, DropHookFn, FetchConfig, CancelHandle},
+    copernica_common::{LinkId, InterLinkPacket, ResponseStore, StopHandle},
     crossbeam_channel::{ Sender, Receiver },
-    sled::{Db},
 };
 
 
-pub struct RelayNode {
+pub struct RelayNode<S: ResponseStore = sled::Db> {
     link_id: Option<LinkId>,
-    rs: Db,
+    rs: S,
     l2s_rx: Option<Receiver<InterLinkPacket>>,
     s2l_tx: Option<Sender<InterLinkPacket>>,
+    fetch_config: FetchConfig,
+    cancel: CancelHandle,
+    stop: StopHandle,
     drop_hook: DropHookFn
 }
 
-impl<'a> Service<'a> for RelayNode {
-    fn new(rs: Db, drop_hook: DropHookFn) -> RelayNode {
+impl<'a, S: ResponseStore> Service<'a> for RelayNode<S> {
+    type Store = S;
+    fn new(rs: S, drop_hook: DropHookFn) -> Self {
         RelayNode {
             link_id: None,
             l2s_rx: None,
             s2l_tx: None,
+            fetch_config: FetchConfig::default(),
+            cancel: CancelHandle::new(),
+            stop: StopHandle::new(),
             rs,
             drop_hook,
         }
     }
-    fn response_store(&self) -> Db {
+    fn response_store(&self) -> S {
         self.rs.clone()
     }
     fn set_l2s_rx(&mut self, r: Receiver<InterLinkPacket>) {
@@ -45,9 +51,24 @@ impl<'a> Service<'a> for RelayNode {
     fn get_link_id(&mut self) -> Option<LinkId> {
         self.link_id.clone()
     }
+    fn get_fetch_config(&mut self) -> FetchConfig {
+        self.fetch_config.clone()
+    }
+    fn set_fetch_config(&mut self, config: FetchConfig) {
+        self.fetch_config = config;
+    }
+    fn get_cancel_handle(&mut self) -> CancelHandle {
+        self.cancel.clone()
+    }
+    fn get_stop_handle(&mut self) -> StopHandle {
+        self.stop.clone()
+    }
+    fn set_stop_handle(&mut self, stop: StopHandle) {
+        self.stop = stop;
+    }
 }
 
-impl Drop for RelayNode {
+impl<S: ResponseStore> Drop for RelayNode<S> {
     fn drop(&mut self) {
         &(self.drop_hook)();
     }
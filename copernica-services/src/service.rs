@@ -1,10 +1,14 @@
 use {
-    copernica_common::{LinkId, NarrowWaistPacket, LinkPacket, InterLinkPacket, HBFI},
-    borsh::{BorshSerialize, BorshDeserialize},
-    std::{thread},
+    crate::{
+        Manifest,
+        fetch::{FetchConfig, FetchError, CancelHandle, Fetcher},
+    },
+    copernica_common::{LinkId, NarrowWaistPacket, LinkPacket, InterLinkPacket, HBFI, Hash, Segments, ResponseStore, StopHandle, STOP_POLL},
+    borsh::{BorshDeserialize},
+    std::{convert::TryInto},
     crossbeam_channel::{Sender, Receiver, unbounded},
-    sled::{Db, Event},
-    anyhow::{Result},
+    anyhow::{Result, anyhow},
+    log::{warn},
 };
 
 pub type DropHookFn = Box<dyn Fn() + Send + 'static>;
@@ -34,14 +38,21 @@ pub type DropHookFn = Box<dyn Fn() + Send + 'static>;
 */
 
 pub trait Service<'a> {
-    fn new(db: sled::Db, drop_hook: DropHookFn) -> Self;
-    fn response_store(&self) -> Db;
+    type Store: ResponseStore;
+    fn new(rs: Self::Store, drop_hook: DropHookFn) -> Self;
+    fn response_store(&self) -> Self::Store;
     fn get_l2s_rx(&mut self) -> Option<Receiver<InterLinkPacket>>;
     fn set_l2s_rx(&mut self, s: Receiver<InterLinkPacket>);
     fn get_s2l_tx(&mut self) -> Option<Sender<InterLinkPacket>>;
     fn set_s2l_tx(&mut self, s: Sender<InterLinkPacket>);
     fn get_link_id(&mut self) -> Option<LinkId>;
     fn set_link_id(&mut self, link_id: LinkId);
+    fn get_fetch_config(&mut self) -> FetchConfig;
+    fn set_fetch_config(&mut self, config: FetchConfig);
+    fn get_cancel_handle(&mut self) -> CancelHandle;
+    fn get_stop_handle(&mut self) -> StopHandle;
+    /// Stops the running service when `stop` is.
+    fn set_stop_handle(&mut self, stop: StopHandle);
     fn handle_narrow_waist(&self, _nw: NarrowWaistPacket) -> Option<NarrowWaistPacket> {
         None
     }
@@ -62,21 +73,25 @@ pub trait Service<'a> {
         let l2s_rx = self.get_l2s_rx();
         let s2l_tx = self.get_s2l_tx();
         let link_id = self.get_link_id();
-        thread::spawn(move || {
+        let stop = self.get_stop_handle();
+        self.get_stop_handle().spawn(move || {
             if let (Some(l2s_rx), Some(s2l_tx), Some(link_id)) = (l2s_rx, s2l_tx, link_id) {
-                loop {
-                    if let Ok(ilp) = l2s_rx.recv() {
+                while !stop.is_stopped() {
+                    if let Ok(ilp) = l2s_rx.recv_timeout(STOP_POLL) {
                         let packet: NarrowWaistPacket = ilp.narrow_waist();
                         match packet.clone() {
-                            NarrowWaistPacket::Request { hbfi } => {
-                                if let Some(nw) = rs.get(hbfi.try_to_vec()?)? {
-                                    let nw = NarrowWaistPacket::try_from_slice(&nw)?;
+                            NarrowWaistPacket::Request { hbfi, .. } => {
+                                if let Some(nw) = rs.get(&hbfi)? {
                                     let lp = LinkPacket::new(link_id.reply_to(), nw);
                                     s2l_tx.send(InterLinkPacket::new(ilp.link_id(), lp))?;
                                 } else { continue }
                             },
                             NarrowWaistPacket::Response { hbfi, .. } => {
-                                rs.insert(hbfi.try_to_vec()?, packet.clone().try_to_vec()?)?;
+                                if !packet.verify() {
+                                    warn!("dropping unverified response {:?}", packet);
+                                    continue;
+                                }
+                                rs.insert(&hbfi, &packet)?;
                             },
                         }
                     }
@@ -87,85 +102,35 @@ pub trait Service<'a> {
         Ok(())
     }
     fn get(&mut self, hbfi: HBFI, start: u64, end: u64) -> Result<Vec<u8>> {
-        let mut counter = start;
         let mut reconstruct: Vec<u8> = vec![];
-        let rs = self.response_store();
-        let s2l_tx = self.get_s2l_tx();
-        let link_id = self.get_link_id();
-        if let Some(s2l_tx) = s2l_tx {
-            if let Some(link_id) = link_id {
-                while counter <= end {
-                    let hbfi = hbfi.clone().offset(counter);
-                    match rs.get(hbfi.try_to_vec()?)? {
-                        Some(resp) => {
-                            let nw = NarrowWaistPacket::try_from_slice(&resp)?;
-                            match nw {
-                                NarrowWaistPacket::Request {..} => {
-                                    match self.handle_narrow_waist(nw) {
-                                        Some(nw) => {
-                                            let lp = LinkPacket::new(link_id.reply_to(), nw);
-                                            let ilp = InterLinkPacket::new(link_id.clone(), lp);
-                                            s2l_tx.send(ilp)?;
-                                        },
-                                        None => {},
-                                    }
-                                },
-                                NarrowWaistPacket::Response {data, ..} => {
-                                    let (chunk, _) = data.data.split_at(data.len.into());
-                                    reconstruct.append(&mut chunk.to_vec());
-                                }
-                            }
-                        }
-                        None => {
-                            let lp = LinkPacket::new(link_id.reply_to(), NarrowWaistPacket::Request{ hbfi: hbfi.clone() });
-                            let ilp = InterLinkPacket::new(link_id.clone(), lp);
-                            let subscriber = rs.watch_prefix(hbfi.try_to_vec()?);
-                            s2l_tx.send(ilp)?;
-                            /*while let Some(event) = (&mut subscriber).await {
-                                match event {
-                                    Event::Insert{ key: _, value } => {
-                                        let nw = NarrowWaistPacket::try_from_slice(&value)?;
-                                        match nw {
-                                            NarrowWaistPacket::Request {..} => return Err(anyhow!("Didn't find FileManifest but found a Request")),
-                                            NarrowWaistPacket::Response {data, ..} => {
-                                                let (chunk, _) = data.data.split_at(data.len.into());
-                                                reconstruct.append(&mut chunk.to_vec());
-                                            }
-                                        }
-                                    }
-                                    Event::Remove {key:_ } => {}
-                                }
-                            }*/
-                            for event in subscriber.take(1) {
-                                match event {
-                                    Event::Insert{ key: _, value } => {
-                                        let nw = NarrowWaistPacket::try_from_slice(&value)?;
-                                        match nw {
-                                            NarrowWaistPacket::Request {..} => {
-                                                match self.handle_narrow_waist(nw) {
-                                                    Some(nw) => {
-                                                        let lp = LinkPacket::new(link_id.reply_to(), nw);
-                                                        let ilp = InterLinkPacket::new(link_id.clone(), lp);
-                                                        s2l_tx.send(ilp)?;
-                                                    },
-                                                    None => {},
-                                                }
-                                            }
-                                            NarrowWaistPacket::Response {data, ..} => {
-                                                let (chunk, _) = data.data.split_at(data.len.into());
-                                                reconstruct.append(&mut chunk.to_vec());
-                                            }
-                                        }
-                                    }
-                                    Event::Remove {key:_ } => {}
-                                }
-                            }
-                        }
-                    }
-                    counter += 1;
-                }
-            }
+        for chunk in self.fetcher(hbfi, start, end)? {
+            reconstruct.append(&mut chunk?);
         }
         Ok(reconstruct)
     }
+    fn fetcher(&mut self, hbfi: HBFI, start: u64, end: u64) -> Result<Fetcher<Self::Store>> {
+        let rs = self.response_store();
+        let config = self.get_fetch_config();
+        let cancel = self.get_cancel_handle();
+        let fetcher = match (self.get_s2l_tx(), self.get_link_id()) {
+            (Some(s2l_tx), Some(link_id)) => Fetcher::new(rs, s2l_tx, link_id, hbfi.clone(), start..=end, config, cancel)?,
+            _ => return Err(FetchError::NotPeered.into()),
+        };
+        if end == 0 {
+            return Ok(fetcher);
+        }
+        // chunks past the manifest prove their place below one of the
+        // segment roots, which in turn must rebuild the manifest's root.
+        let manifest = Manifest::try_from_slice(&self.get(hbfi.clone(), 0, 0)?)?;
+        if start >= manifest.segments_start {
+            return Ok(fetcher);
+        }
+        let roots = self.get(hbfi, manifest.segments_start, manifest.segments_end)?;
+        let roots = roots.chunks_exact(32).map(|root| root.try_into()).collect::<Result<Vec<Hash>, _>>()?;
+        let segments = Segments::new(roots, manifest.end);
+        if segments.root() != manifest.root {
+            return Err(anyhow!("the segment roots do not match the manifest's Merkle root"));
+        }
+        Ok(fetcher.segments(segments))
+    }
 }
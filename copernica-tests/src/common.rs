@@ -7,8 +7,12 @@ use {
         io::Write,
     },
     copernica_services::{
-        FilePacker,
+        FilePacker, FTP, FetchConfig, Service,
     },
+    copernica_broker::{Broker},
+    copernica_common::{HBFI, InterLinkPacket, LinkId, PrivateIdentity, ReplyTo, ResponseStore},
+    copernica_links::{Link, MpscChannel},
+    crossbeam_channel::{Sender, Receiver},
     anyhow::{Result},
 };
 
@@ -32,19 +36,104 @@ pub async fn generate_random_dir_name() -> PathBuf {
 
 pub type TestData = Vec<(PathBuf, u8, usize)>;
 
-pub async fn populate_tmp_dir(name: String, id: String, test_data: TestData) -> Result<(PathBuf, PathBuf)> {
+// Writes `test_data` below a fresh directory, returned with a fresh store
+// it is published into as `name`. The store is handed back open: sled lets
+// go of a store some time after its last handle is dropped, so reopening
+// one just published into can fail.
+pub async fn populate_tmp_dir(name: String, id: PrivateIdentity, test_data: TestData) -> Result<(PathBuf, sled::Db)> {
     let router_data_dir = generate_random_dir_name().await;
+    let source_data_dir = write_tmp_dir(test_data).await?;
+    let rs = sled::open(&router_data_dir)?;
+    let packer: FilePacker = FilePacker::new(&source_data_dir, &router_data_dir, name, id)?;
+    packer.publish_to(&rs)?;
+    Ok((source_data_dir, rs))
+}
+
+pub async fn write_tmp_dir(test_data: TestData) -> Result<PathBuf> {
     let source_data_dir = generate_random_dir_name().await;
     for (path, data, size) in test_data {
         let dir = source_data_dir.join(path);
+        if let Some(parent) = dir.parent() {
+            fs::create_dir_all(parent)?;
+        }
         let data = vec![data; size];
         let mut f = fs::File::create(dir.clone()).unwrap();
         f.write_all(&data).unwrap();
         f.sync_all().unwrap();
     }
-    let packer: FilePacker = FilePacker::new(&source_data_dir, &router_data_dir, name, id)?;
-    packer.publish()?;
-    Ok((source_data_dir, router_data_dir))
+    Ok(source_data_dir)
+}
+
+pub async fn tmp_store() -> Result<sled::Db> {
+    Ok(sled::open(generate_random_dir_name().await)?)
+}
+
+// `test_data` as published under a fresh identity, kept in `rs`.
+pub struct Publication<S> {
+    pub hbfi: HBFI,
+    pub raw_data_dir: PathBuf,
+    pub rs: S,
+}
+
+impl<S> Publication<S> {
+    // Fetches every file of the publication with `ftp` and compares it to
+    // the one written to disk.
+    pub fn assert_fetched<T: ResponseStore>(&self, ftp: &mut FTP<T>) -> Result<()> {
+        for file_name in ftp.file_names(self.hbfi.clone())? {
+            let actual_file = ftp.file(self.hbfi.clone(), file_name.clone())?;
+            let expected_file = fs::read(self.raw_data_dir.join(&file_name))?;
+            assert_eq!(actual_file, expected_file, "{}", file_name);
+        }
+        Ok(())
+    }
+}
+
+pub async fn publish<S: ResponseStore>(name: &str, test_data: TestData, rs: S) -> Result<Publication<S>> {
+    let id = PrivateIdentity::generate();
+    let raw_data_dir = write_tmp_dir(test_data).await?;
+    FilePacker::new(&raw_data_dir, &generate_random_dir_name().await, name.into(), id.clone())?.publish_to(&rs)?;
+    let hbfi = HBFI::new(name, &id.public_id().to_string())?;
+    Ok(Publication { hbfi, raw_data_dir, rs })
+}
+
+pub type Peer = (Sender<InterLinkPacket>, Receiver<InterLinkPacket>);
+
+// Joins two ends with a pair of running MpscChannels, `a` and `b` peer
+// each end with the LinkId its channel listens on.
+pub fn mpsc_link<A, B>(a: A, b: B) -> Result<()>
+where
+    A: FnOnce(LinkId) -> Result<Peer>,
+    B: FnOnce(LinkId) -> Result<Peer>,
+{
+    let lid_a = LinkId::listen(ReplyTo::Mpsc);
+    let lid_b = LinkId::listen(ReplyTo::Mpsc);
+    let mut channel_a: MpscChannel = Link::new(lid_a.clone(), a(lid_a)?)?;
+    let mut channel_b: MpscChannel = Link::new(lid_b.clone(), b(lid_b)?)?;
+    channel_a.female(channel_b.male());
+    channel_b.female(channel_a.male());
+    channel_a.run()?;
+    channel_b.run()?;
+    Ok(())
+}
+
+// Runs a publisher serving `publisher` and a requester fetching into
+// `requester` with `config`, peered through `broker` if there is one.
+pub fn ftp_pair<S: ResponseStore>(publisher: S, broker: Option<&mut Broker<S>>, requester: S, config: FetchConfig) -> Result<(FTP<S>, FTP<S>)> {
+    let drop_hook = Box::new(move || {});
+    let mut f0: FTP<S> = Service::new(publisher, drop_hook.clone());
+    let mut f1: FTP<S> = Service::new(requester, drop_hook);
+    f1.set_fetch_config(config);
+    match broker {
+        Some(b0) => {
+            mpsc_link(|lid| f0.peer(lid), |lid| b0.peer(lid))?;
+            mpsc_link(|lid| b0.peer(lid), |lid| f1.peer(lid))?;
+            b0.run()?;
+        },
+        None => mpsc_link(|lid| f0.peer(lid), |lid| f1.peer(lid))?,
+    }
+    f0.run()?;
+    f1.run()?;
+    Ok((f0, f1))
 }
 
 async fn populate_tmp_dir_dispersed_gt_mtu(node_count: usize, data_size: u64) -> Result<Vec<(String, String)>> {
@@ -60,7 +149,7 @@ async fn populate_tmp_dir_dispersed_gt_mtu(node_count: usize, data_size: u64) ->
         let mut source_file = fs::File::create(source_file_name).unwrap();
         source_file.write_all(&value).unwrap();
         source_file.sync_all().unwrap();
-        let id = "namable".into();
+        let id = PrivateIdentity::generate();
         let _packer = FilePacker::new(&source_data_dir, &router_data_dir, name ,id)?;
         _packer.publish()?;
     }
@@ -1,22 +1,28 @@
 #![allow(dead_code)]
 use {
     anyhow::{Result},
-    crate::common::{populate_tmp_dir, TestData, generate_random_dir_name },
+    crate::common::{populate_tmp_dir, publish, tmp_store, ftp_pair, mpsc_link, TestData, generate_random_dir_name },
     sled,
     std::{
         io::prelude::*,
         fs,
+        thread,
+        time::{Duration, Instant},
+        sync::{Arc, Mutex},
     },
     copernica_services::{
-        Manifest, FileManifest, FTP
+        Manifest, FileManifest, FTP, FetchConfig, FetchError, Progress, FilePacker
     },
-    copernica_broker::{Broker},
+    copernica_broker::{Broker, BayesSnapshot, DefconConfig, BAYES_STATE},
     copernica_common::{
-        HBFI, LinkId, ReplyTo
+        HBFI, LinkId, ReplyTo, PrivateIdentity, NarrowWaistPacket, Data, MemoryStore, constants,
+        InterLinkPacket, LinkPacket
     },
+    crossbeam_channel::unbounded,
+    borsh::{BorshDeserialize, BorshSerialize},
     copernica_services::{Service},
-    copernica_links::{Link, MpscChannel, MpscCorruptor,
-    UdpIp },
+    copernica_links::{Link, MpscChannel, MpscSimulator, Faults, Fec,
+    Tcp, UdpIp },
     log::{debug},
 };
 
@@ -26,17 +32,15 @@ pub async fn smoke_test() -> Result<()> {
     let mut test_data0 = TestData::new();
     test_data0.push(("0.txt".into(), 0, 1024));
     let name0: String = "namable0".into();
-    let id0: String = "namable_id0".into();
-    let (raw_data_dir0, packaged_data_dir0) = populate_tmp_dir(name0.clone(), id0.clone(), test_data0).await?;
+    let id0 = PrivateIdentity::generate();
+    let (raw_data_dir0, rs0) = populate_tmp_dir(name0.clone(), id0.clone(), test_data0).await?;
 
     let mut test_data1 = TestData::new();
     test_data1.push(("1.txt".into(), 1, 1024));
     let name1: String = "namable1".into();
-    let id1: String = "namable_id1".into();
-    let (raw_data_dir1, packaged_data_dir1) = populate_tmp_dir(name1.clone(), id1.clone(), test_data1).await?;
+    let id1 = PrivateIdentity::generate();
+    let (raw_data_dir1, rs1) = populate_tmp_dir(name1.clone(), id1.clone(), test_data1).await?;
 
-    let rs0 = sled::open(packaged_data_dir0)?;
-    let rs1 = sled::open(packaged_data_dir1)?;
     let rs2 = sled::open(generate_random_dir_name().await)?;
 
     let mut cb = Broker::new(rs2);
@@ -65,8 +69,8 @@ pub async fn smoke_test() -> Result<()> {
     fs0.run()?;
     fs1.run()?;
 
-    let hbfi0: HBFI = HBFI::new(&name0, &id0)?;
-    let hbfi1: HBFI = HBFI::new(&name1, &id1)?;
+    let hbfi0: HBFI = HBFI::new(&name0, &id0.public_id().to_string())?;
+    let hbfi1: HBFI = HBFI::new(&name1, &id1.public_id().to_string())?;
     debug!("requesting manifest 0");
     let manifest0: Manifest = fs1.manifest(hbfi0.clone())?;
     debug!("manifest 0: {:?}", manifest0);
@@ -119,22 +123,20 @@ pub async fn transports() -> Result<()> {
     let mut test_data0 = TestData::new();
     test_data0.push(("0.txt".into(), 2, 2024));
     let name0: String = "namable0".into();
-    let id0: String = "namable_id0".into();
-    let (raw_data_dir0, packaged_data_dir0) = populate_tmp_dir(name0.clone(), id0.clone(), test_data0).await?;
+    let id0 = PrivateIdentity::generate();
+    let (raw_data_dir0, frs0) = populate_tmp_dir(name0.clone(), id0.clone(), test_data0).await?;
 
     let mut test_data1 = TestData::new();
     test_data1.push(("1.txt".into(), 1, 1024));
     let name1: String = "namable1".into();
-    let id1: String = "namable_id1".into();
-    let (raw_data_dir1, packaged_data_dir1) = populate_tmp_dir(name1.clone(), id1.clone(), test_data1).await?;
+    let id1 = PrivateIdentity::generate();
+    let (raw_data_dir1, frs1) = populate_tmp_dir(name1.clone(), id1.clone(), test_data1).await?;
 
     let brs0 = generate_random_dir_name().await;
     let brs1 = generate_random_dir_name().await;
 
-    let frs0 = sled::open(packaged_data_dir0)?;
     let brs0 = sled::open(brs0)?;
     let brs1 = sled::open(brs1)?;
-    let frs1 = sled::open(packaged_data_dir1)?;
 
     let mut f0: FTP = Service::new(frs0, drop_hook.clone());
     let mut b0 = Broker::new(brs0);
@@ -152,8 +154,12 @@ pub async fn transports() -> Result<()> {
     let lid2to3 = LinkId::listen(lid2to3_address.clone());
     let lid3to2 = LinkId::listen(lid3to2_address.clone());
 
-    let mut mpscchannel0: MpscCorruptor = Link::new(lid0to1.clone(), f0.peer(lid0to1)?)?;
-    let mut mpscchannel1: MpscCorruptor = Link::new(lid1to0.clone(), b0.peer(lid1to0)?)?;
+    // a six byte burst in every packet, all one block's parity corrects
+    let corrupt = Faults::new().bursts(1.0, 6);
+    let mpscchannel0: MpscSimulator = Link::new(lid0to1.clone(), f0.peer(lid0to1)?)?;
+    let mpscchannel1: MpscSimulator = Link::new(lid1to0.clone(), b0.peer(lid1to0)?)?;
+    let mut mpscchannel0 = mpscchannel0.faults(corrupt.seed(0));
+    let mut mpscchannel1 = mpscchannel1.faults(corrupt.seed(1));
     let mut mpscchannel2: MpscChannel   = Link::new(lid1to2.clone(), b0.peer(lid1to2)?)?;
     let mut mpscchannel3: MpscChannel   = Link::new(lid2to1.clone(), b1.peer(lid2to1)?)?;
     let udpip4:           UdpIp         = Link::new(lid2to3.clone(), b1.peer(lid2to3.remote(lid3to2_address))?)?;
@@ -181,8 +187,8 @@ pub async fn transports() -> Result<()> {
     b1.run()?;
     f1.run()?;
 
-    let hbfi0: HBFI = HBFI::new(&name0, &id0)?;
-    let hbfi1: HBFI = HBFI::new(&name1, &id1)?;
+    let hbfi0: HBFI = HBFI::new(&name0, &id0.public_id().to_string())?;
+    let hbfi1: HBFI = HBFI::new(&name1, &id1.public_id().to_string())?;
 
     let manifest1: Manifest = f0.manifest(hbfi1.clone())?;
     let manifest0: Manifest = f1.manifest(hbfi0.clone())?;
@@ -220,6 +226,347 @@ pub async fn transports() -> Result<()> {
     Ok(())
 }
 
+pub async fn fetch_timeout() -> Result<()> {
+    let mut b0 = Broker::new(tmp_store().await?);
+    let config = FetchConfig::new().timeout(Duration::from_millis(20)).retries(2);
+    let (_f0, mut f1) = ftp_pair(tmp_store().await?, Some(&mut b0), tmp_store().await?, config)?;
+
+    let hbfi: HBFI = HBFI::new("missing", "nobody")?;
+    let err = f1.manifest(hbfi.clone()).expect_err("nobody publishes missing");
+    assert_eq!(err.downcast_ref::<FetchError>(), Some(&FetchError::Timeout { hbfi: hbfi.clone(), offset: 0 }));
+
+    f1.set_fetch_config(FetchConfig::new().timeout(Duration::from_secs(60)));
+    let cancel = f1.get_cancel_handle();
+    thread::spawn(move || {
+        thread::sleep(Duration::from_millis(100));
+        cancel.cancel();
+    });
+    let err = f1.file_manifest(hbfi.clone()).expect_err("fetch was cancelled");
+    assert_eq!(err.downcast_ref::<FetchError>(), Some(&FetchError::Cancelled { hbfi, offset: 0 }));
+    Ok(())
+}
+
+pub async fn windowed_fetch() -> Result<()> {
+    let publication = publish("windowed0", vec![("0.txt".into(), 3, 1024 * 256 + 7)], tmp_store().await?).await?;
+    let mut b0 = Broker::new(tmp_store().await?);
+    let config = FetchConfig::new().window(8).max_window(32);
+    let (_f0, mut f1) = ftp_pair(publication.rs.clone(), Some(&mut b0), tmp_store().await?, config)?;
+
+    publication.assert_fetched(&mut f1)
+}
+
+pub async fn streaming_download() -> Result<()> {
+    let publication = publish("streaming0", vec![("0.bin".into(), 4, 1024 * 1024 + 1)], tmp_store().await?).await?;
+    let config = FetchConfig::new().window(16);
+    let (_f0, mut f1) = ftp_pair(publication.rs.clone(), None, tmp_store().await?, config)?;
+
+    let hbfi0 = publication.hbfi.clone();
+    let download_dir = generate_random_dir_name().await;
+    let expected_buffer = fs::read(publication.raw_data_dir.join("0.bin"))?;
+
+    let written = f1.download_to(hbfi0.clone(), "0.bin".into(), &download_dir.join("0.bin"))?;
+    assert_eq!(written, expected_buffer.len() as u64);
+    assert_eq!(fs::read(download_dir.join("0.bin"))?, expected_buffer);
+
+    let mut reader = f1.file_reader(hbfi0, "0.bin".into())?;
+    let mut actual_buffer = Vec::new();
+    reader.read_to_end(&mut actual_buffer)?;
+    assert_eq!(actual_buffer, expected_buffer);
+    Ok(())
+}
+
+pub async fn download_all() -> Result<()> {
+    let test_data0: TestData = vec![
+        ("top.txt".into(), 5, 3000),
+        ("empty.txt".into(), 0, 0),
+        ("a/b/nested.txt".into(), 6, 1024),
+        ("a/empty_too.txt".into(), 0, 0),
+    ];
+    let publication = publish("tree0", test_data0, tmp_store().await?).await?;
+    let (_f0, mut f1) = ftp_pair(publication.rs.clone(), None, tmp_store().await?, FetchConfig::new())?;
+
+    let hbfi0 = publication.hbfi.clone();
+    let download_dir = generate_random_dir_name().await;
+    let reported: Arc<Mutex<Vec<Progress>>> = Arc::new(Mutex::new(vec![]));
+    let reporter = reported.clone();
+    f1.download_all(hbfi0.clone(), &download_dir, Box::new(move |p: &Progress| {
+        reporter.lock().unwrap().push(p.clone());
+    }))?;
+
+    let reported = reported.lock().unwrap();
+    // each file is reported as it starts and once it is written
+    assert_eq!(reported.len(), 8);
+    assert!(reported.iter().all(|p| p.files == 4 && p.bytes <= p.size));
+    for file_name in f1.file_names(hbfi0)? {
+        let expected_buffer = fs::read(publication.raw_data_dir.join(&file_name))?;
+        assert_eq!(fs::read(download_dir.join(&file_name))?, expected_buffer, "{}", file_name);
+        let first = reported.iter().find(|p| p.name == file_name).expect("progress reported");
+        assert_eq!(first.bytes, 0);
+        let last = reported.iter().rev().find(|p| p.name == file_name).expect("progress reported");
+        assert_eq!(last.bytes, expected_buffer.len() as u64);
+        assert_eq!(first.size, expected_buffer.len() as u64);
+        assert_eq!(last.size, expected_buffer.len() as u64);
+    }
+    Ok(())
+}
+
+pub async fn streaming_publish() -> Result<()> {
+    let source_data_dir = generate_random_dir_name().await;
+    let packaged_data_dir0 = generate_random_dir_name().await;
+    let frs0 = sled::open(&packaged_data_dir0)?;
+    let expected_buffer: Vec<u8> = (0..10 * 1024 + 100).map(|i| (i % 251) as u8).collect();
+    fs::write(source_data_dir.join("big.bin"), &expected_buffer)?;
+    fs::write(source_data_dir.join("small.bin"), b"small")?;
+
+    let name0: String = "packed0".into();
+    let id0 = PrivateIdentity::generate();
+    let reported: Arc<Mutex<Vec<Progress>>> = Arc::new(Mutex::new(vec![]));
+    let reporter = reported.clone();
+    FilePacker::new(&source_data_dir, &packaged_data_dir0, name0.clone(), id0.clone())?
+        .batch_size(3)
+        .progress(Box::new(move |p: &Progress| reporter.lock().unwrap().push(p.clone())))
+        .publish_to(&frs0)?;
+    {
+        let reported = reported.lock().unwrap();
+        let big: Vec<&Progress> = reported.iter().filter(|p| p.name == "big.bin").collect();
+        // 11 chunks in batches of 3 report after chunks 3, 6 and 9, then once the file is done
+        assert_eq!(big.iter().map(|p| p.bytes).collect::<Vec<u64>>(), vec![3072, 6144, 9216, 10340]);
+        assert!(reported.iter().all(|p| p.files == 2 && p.bytes <= p.size));
+    }
+
+    let (_f0, mut f1) = ftp_pair(frs0, None, tmp_store().await?, FetchConfig::new())?;
+
+    let hbfi0: HBFI = HBFI::new(&name0, &id0.public_id().to_string())?;
+    assert_eq!(f1.file(hbfi0.clone(), "big.bin".into())?, expected_buffer);
+    assert_eq!(f1.file(hbfi0, "small.bin".into())?, b"small".to_vec());
+    Ok(())
+}
+
+pub async fn forged_response() -> Result<()> {
+    let publication = publish("forged0", vec![("0.txt".into(), 0, 1024)], tmp_store().await?).await?;
+    let frs0 = &publication.rs;
+    let frs1 = tmp_store().await?;
+
+    // a relay flips a byte of a genuine Response but keeps its signature
+    let hbfi0 = publication.hbfi.clone();
+    let genuine = NarrowWaistPacket::try_from_slice(&frs0.get(hbfi0.try_to_vec()?)?.expect("published manifest"))?;
+    let tampered = match genuine {
+        NarrowWaistPacket::Response { hbfi, mut data, offset, total, publisher, signature, proof } => {
+            data.data[0] ^= 1;
+            NarrowWaistPacket::Response { hbfi, data, offset, total, publisher, signature, proof }
+        },
+        _ => unreachable!(),
+    };
+    frs0.insert(hbfi0.try_to_vec()?, tampered.try_to_vec()?)?;
+
+    // an impostor signs a Response for a publication it does not own
+    let impostor = PrivateIdentity::generate();
+    let id1 = PrivateIdentity::generate();
+    let hbfi1: HBFI = HBFI::new("forged1", &id1.public_id().to_string())?;
+    let data = Data { len: 0, data: [0; constants::FRAGMENT_SIZE as usize] };
+    let forged = NarrowWaistPacket::signed_response(&impostor, hbfi1.clone(), data, 0, 1, vec![])?;
+    frs0.insert(hbfi1.try_to_vec()?, forged.try_to_vec()?)?;
+
+    let config = FetchConfig::new().timeout(Duration::from_millis(20)).retries(2);
+    let (_f0, mut f1) = ftp_pair(frs0.clone(), None, frs1.clone(), config)?;
+
+    for hbfi in [hbfi0, hbfi1] {
+        let err = f1.manifest(hbfi.clone()).expect_err("forged responses are rejected");
+        assert_eq!(err.downcast_ref::<FetchError>(), Some(&FetchError::Timeout { hbfi: hbfi.clone(), offset: 0 }));
+        assert!(frs1.get(hbfi.try_to_vec()?)?.is_none());
+    }
+    Ok(())
+}
+
+pub async fn merkle_proof() -> Result<()> {
+    // spans several segments, each chunk proving itself against one root
+    let publication = publish("merkle0", vec![("0.bin".into(), 7, 200 * 1024 + 1)], tmp_store().await?).await?;
+    let frs1 = tmp_store().await?;
+
+    // the requester already holds a correctly signed chunk whose proof is bad
+    let key = publication.hbfi.clone().offset(2).try_to_vec()?;
+    let genuine = publication.rs.get(&key)?.expect("published chunk");
+    let bad_proof = match NarrowWaistPacket::try_from_slice(&genuine)? {
+        NarrowWaistPacket::Response { hbfi, data, offset, total, publisher, signature, mut proof } => {
+            assert_eq!(proof.len(), constants::MAX_PROOF_DEPTH as usize);
+            proof[0][0] ^= 1;
+            NarrowWaistPacket::Response { hbfi, data, offset, total, publisher, signature, proof }
+        },
+        _ => unreachable!(),
+    };
+    assert!(bad_proof.verify());
+    frs1.insert(&key, bad_proof.try_to_vec()?)?;
+
+    let (_f0, mut f1) = ftp_pair(publication.rs.clone(), None, frs1.clone(), FetchConfig::new())?;
+
+    publication.assert_fetched(&mut f1)?;
+    assert_eq!(frs1.get(&key)?.expect("re-requested chunk").to_vec(), genuine.to_vec());
+    Ok(())
+}
+
+pub async fn in_memory() -> Result<()> {
+    let publication = publish("memory0", vec![("0.txt".into(), 5, 1024 * 64 + 3)], MemoryStore::new()).await?;
+    let mut b0 = Broker::new(MemoryStore::new());
+    let (_f0, mut f1) = ftp_pair(publication.rs.clone(), Some(&mut b0), MemoryStore::new(), FetchConfig::new())?;
+
+    publication.assert_fetched(&mut f1)?;
+    assert!(b0.content_store_stats().entries > 0);
+    Ok(())
+}
+
+pub async fn response_flood() -> Result<()> {
+    // in scope here only, the other scenarios use sled::Db's own get and insert
+    use copernica_common::ResponseStore;
+    let drop_hook = Box::new(move || {});
+
+    let publication = publish("flood0", vec![("0.txt".into(), 9, 1024 * 16)], MemoryStore::new()).await?;
+    // genuine, correctly signed Responses nobody asked the flooder for
+    let mut genuine = vec![];
+    for (hbfi, _) in publication.rs.index()? {
+        genuine.extend(publication.rs.get(&hbfi)?);
+    }
+
+    let brs = MemoryStore::new();
+    let defcon = DefconConfig::new().unsolicited(100).quarantine(Duration::from_secs(60));
+    let mut f0: FTP<MemoryStore> = Service::new(publication.rs.clone(), drop_hook.clone());
+    let mut b0 = Broker::new(brs.clone()).defcon(defcon).checkpoint_interval(Duration::from_millis(20));
+    let mut f1: FTP<MemoryStore> = Service::new(MemoryStore::new(), drop_hook);
+
+    let (flood_tx, flood_rx) = unbounded::<InterLinkPacket>();
+    let (requests_tx, _requests_rx) = unbounded::<InterLinkPacket>();
+    let mut flooder = None;
+    mpsc_link(|lid| f0.peer(lid), |lid| b0.named_peer(lid, "publisher"))?;
+    mpsc_link(|lid| b0.peer(lid), |lid| f1.peer(lid))?;
+    mpsc_link(|lid| b0.named_peer(lid, "flooder"), |lid| {
+        flooder = Some(lid);
+        Ok((requests_tx, flood_rx))
+    })?;
+    let flooder = flooder.expect("flooder link");
+    f0.run()?;
+    b0.run()?;
+    f1.run()?;
+
+    // the flooder replays every Response over and over, before and during
+    // the fetch
+    let flood = move |rounds: usize| -> Result<()> {
+        for _ in 0..rounds {
+            for nw in &genuine {
+                let lp = LinkPacket::new(ReplyTo::Mpsc, nw.clone());
+                flood_tx.send(InterLinkPacket::new(flooder.clone(), lp))?;
+            }
+        }
+        Ok(())
+    };
+    flood(20)?;
+    // fetch only once the flooder is quarantined, or its Responses could
+    // answer the fetch's own Requests
+    let started = Instant::now();
+    while b0.defcon_stats().quarantines == 0 {
+        assert!(started.elapsed() < Duration::from_secs(30), "flooder never quarantined");
+        thread::sleep(Duration::from_millis(20));
+    }
+    let during = thread::spawn(move || flood(20));
+
+    publication.assert_fetched(&mut f1)?;
+    during.join().expect("flooder")?;
+    thread::sleep(Duration::from_millis(200));
+
+    let stats = b0.defcon_stats();
+    assert!(stats.unsolicited >= 100);
+    assert!(stats.quarantines >= 1);
+    assert!(stats.quarantined > 0);
+    let snapshot = BayesSnapshot::try_from_slice(&brs.load_state(BAYES_STATE)?.expect("a checkpoint"))?;
+    let count = |name: &str| snapshot.links.iter().find(|link| link.name == name).map(|link| link.count);
+    let flooder = count("flooder").expect("flooder link");
+    let publisher = count("publisher").expect("publisher link");
+    // the flooder is known only from being peered
+    assert!(flooder < 1.5);
+    assert!(publisher > flooder);
+    Ok(())
+}
+
+pub async fn lossy_network() -> Result<()> {
+    let drop_hook = Box::new(move || {});
+
+    let publication = publish("lossy0", vec![("0.txt".into(), 11, 1024 * 16 + 5)], MemoryStore::new()).await?;
+    let mut f0: FTP<MemoryStore> = Service::new(publication.rs.clone(), drop_hook.clone());
+    let mut b0 = Broker::new(MemoryStore::new());
+    let mut f1: FTP<MemoryStore> = Service::new(MemoryStore::new(), drop_hook);
+
+    let lid0to1 = LinkId::listen(ReplyTo::Mpsc);
+    let lid1to0 = LinkId::listen(ReplyTo::Mpsc);
+    let lid1to2 = LinkId::listen(ReplyTo::Mpsc);
+    let lid2to1 = LinkId::listen(ReplyTo::Mpsc);
+
+    // a noisy, lossy radio hop either side of the broker
+    let faults = Faults::new()
+        .loss(0.02)
+        .duplication(0.02)
+        .reordering(0.05, Duration::from_millis(5))
+        .latency(Duration::from_millis(1), Duration::from_millis(2))
+        .bandwidth(4 * 1024 * 1024)
+        .bit_errors(1e-4)
+        .bursts(0.1, 16);
+    let fec = Fec::Adaptive { min: 8, max: 32 };
+    let link = |lid: LinkId, channels| -> Result<MpscSimulator> {
+        let link: MpscSimulator = Link::new(lid, channels)?;
+        Ok(link.fec(fec).interleave(true))
+    };
+    let mut mpscchannel0 = link(lid0to1.clone(), f0.peer(lid0to1)?)?.faults(faults.seed(0));
+    let mut mpscchannel1 = link(lid1to0.clone(), b0.peer(lid1to0)?)?.faults(faults.seed(1));
+    let mut mpscchannel2 = link(lid1to2.clone(), b0.peer(lid1to2)?)?.faults(faults.seed(2));
+    let mut mpscchannel3 = link(lid2to1.clone(), f1.peer(lid2to1)?)?.faults(faults.seed(3));
+    mpscchannel0.female(mpscchannel1.male());
+    mpscchannel1.female(mpscchannel0.male());
+    mpscchannel2.female(mpscchannel3.male());
+    mpscchannel3.female(mpscchannel2.male());
+
+    let links: Vec<Box<dyn Link>> = vec![
+        Box::new(mpscchannel0),
+        Box::new(mpscchannel1),
+        Box::new(mpscchannel2),
+        Box::new(mpscchannel3),
+    ];
+    for link in &links {
+        link.run()?;
+    }
+    f0.run()?;
+    b0.run()?;
+    f1.run()?;
+
+    publication.assert_fetched(&mut f1)?;
+    assert!(links.iter().map(|link| link.stats().corrected).sum::<u64>() > 0);
+    Ok(())
+}
+
+pub async fn tcp_transport() -> Result<()> {
+    let drop_hook = Box::new(move || {});
+
+    let publication = publish("tcp0", vec![("0.txt".into(), 13, 1024 * 16 + 7)], MemoryStore::new()).await?;
+    let mut f0: FTP<MemoryStore> = Service::new(publication.rs.clone(), drop_hook.clone());
+    let mut b0 = Broker::new(MemoryStore::new());
+    let mut b1 = Broker::new(MemoryStore::new());
+    let mut f1: FTP<MemoryStore> = Service::new(MemoryStore::new(), drop_hook);
+
+    let lid1to2_address = ReplyTo::Tcp("127.0.0.1:50140".parse()?);
+    let lid2to1_address = ReplyTo::Tcp("127.0.0.1:50141".parse()?);
+    let lid1to2 = LinkId::listen(lid1to2_address.clone());
+    let lid2to1 = LinkId::listen(lid2to1_address.clone());
+
+    mpsc_link(|lid| f0.peer(lid), |lid| b0.peer(lid))?;
+    let tcp2: Tcp = Link::new(lid1to2.clone(), b0.peer(lid1to2.remote(lid2to1_address))?)?;
+    let tcp3: Tcp = Link::new(lid2to1.clone(), b1.peer(lid2to1.remote(lid1to2_address))?)?;
+    tcp2.run()?;
+    tcp3.run()?;
+    mpsc_link(|lid| b1.peer(lid), |lid| f1.peer(lid))?;
+    f0.run()?;
+    b0.run()?;
+    b1.run()?;
+    f1.run()?;
+
+    publication.assert_fetched(&mut f1)
+}
+
 #[cfg(test)]
 mod copernicafs {
     use super::*;
@@ -231,4 +578,81 @@ mod copernicafs {
             let _r = smoke_test().await;
         })
     }
+
+    #[test]
+    fn test_fetch_timeout() {
+        task::block_on(async {
+            fetch_timeout().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_windowed_fetch() {
+        task::block_on(async {
+            windowed_fetch().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_streaming_download() {
+        task::block_on(async {
+            streaming_download().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_download_all() {
+        task::block_on(async {
+            download_all().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_streaming_publish() {
+        task::block_on(async {
+            streaming_publish().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_forged_response() {
+        task::block_on(async {
+            forged_response().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_merkle_proof() {
+        task::block_on(async {
+            merkle_proof().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_in_memory() {
+        task::block_on(async {
+            in_memory().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_response_flood() {
+        task::block_on(async {
+            response_flood().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_lossy_network() {
+        task::block_on(async {
+            lossy_network().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_tcp_transport() {
+        task::block_on(async {
+            tcp_transport().await.unwrap();
+        })
+    }
 }
@@ -2,6 +2,7 @@
 mod router;
 mod common;
 mod ftp;
+mod node;
 use {
     async_std::{ task, },
     anyhow::{Result},
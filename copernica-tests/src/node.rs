@@ -0,0 +1,89 @@
+#![allow(dead_code)]
+use {
+    anyhow::{Result},
+    crate::common::{write_tmp_dir, TestData, generate_random_dir_name},
+    std::{
+        fs,
+        net::UdpSocket,
+    },
+    copernica_clients::{Config, ContentStoreConfig, EvictionConfig, FecConfig, LinkConfig, ServiceConfig, Node},
+    copernica_common::{HBFI, PrivateIdentity},
+    copernica_services::{FilePacker, Service},
+};
+
+pub async fn configured_nodes() -> Result<()> {
+    let test_data0: TestData = vec![("0.txt".into(), 0, 3000)];
+    let name0: String = "node0".into();
+    let id0 = PrivateIdentity::generate();
+    let raw_data_dir0 = write_tmp_dir(test_data0).await?;
+
+    let config0 = Config {
+        data_dir: generate_random_dir_name().await,
+        content_store: ContentStoreConfig::default(),
+        links: vec![LinkConfig::UdpIp { listen: "127.0.0.1:50110".parse()?, remote: "127.0.0.1:50111".parse()?, fec: FecConfig::default(), interleave: false }],
+        services: vec![ServiceConfig::Ftp { data_dir: generate_random_dir_name().await }],
+    };
+    let config1 = Config {
+        data_dir: generate_random_dir_name().await,
+        content_store: ContentStoreConfig { max_entries: Some(64), eviction: EvictionConfig::Lfu, ..Default::default() },
+        links: vec![LinkConfig::UdpIp { listen: "127.0.0.1:50111".parse()?, remote: "127.0.0.1:50110".parse()?, fec: FecConfig::Adaptive { min: 4, max: 32 }, interleave: true }],
+        services: vec![ServiceConfig::Ftp { data_dir: generate_random_dir_name().await }],
+    };
+    let config_path = generate_random_dir_name().await.join("copernica.json");
+    fs::write(&config_path, serde_json::to_string(&config1)?)?;
+    let config1 = Config::from_file(&config_path)?;
+
+    let mut node0 = Node::new(config0)?;
+    let mut node1 = Node::new(config1)?;
+    // published into the node's own store, which is already open
+    let rs0 = node0.ftps()[0].response_store();
+    FilePacker::new(&raw_data_dir0, &generate_random_dir_name().await, name0.clone(), id0.clone())?.publish_to(&rs0)?;
+    node0.run()?;
+    node1.run()?;
+
+    let hbfi0: HBFI = HBFI::new(&name0, &id0.public_id().to_string())?;
+    let actual = node1.ftps()[0].file(hbfi0, "0.txt".into())?;
+    assert_eq!(actual, fs::read(raw_data_dir0.join("0.txt"))?);
+    node0.shutdown()?;
+    node1.shutdown()?;
+    Ok(())
+}
+
+pub async fn conflicting_nodes() -> Result<()> {
+    let config = Config {
+        data_dir: generate_random_dir_name().await,
+        content_store: ContentStoreConfig::default(),
+        links: vec![LinkConfig::UdpIp { listen: "127.0.0.1:50112".parse()?, remote: "127.0.0.1:50113".parse()?, fec: FecConfig::default(), interleave: false }],
+        services: vec![],
+    };
+    let mut node = Node::new(config.clone())?;
+    node.run()?;
+    let store_taken = Node::new(Config { links: vec![], ..config.clone() }).err().expect("the store is in use");
+    assert!(store_taken.to_string().contains("is another node using it?"), "{}", store_taken);
+    let port_taken = Node::new(Config { data_dir: generate_random_dir_name().await, ..config }).err().expect("the port is in use");
+    assert!(port_taken.to_string().contains("Cannot listen on 127.0.0.1:50112"), "{}", port_taken);
+    node.shutdown()?;
+    // the link's threads have ended and closed the socket
+    let _socket = UdpSocket::bind("127.0.0.1:50112")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod nodes {
+    use super::*;
+    use async_std::{ task, };
+
+    #[test]
+    fn test_configured_nodes() {
+        task::block_on(async {
+            configured_nodes().await.unwrap();
+        })
+    }
+
+    #[test]
+    fn test_conflicting_nodes() {
+        task::block_on(async {
+            conflicting_nodes().await.unwrap();
+        })
+    }
+}